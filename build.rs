@@ -0,0 +1,74 @@
+//! Records where the kernel was built from, for `buildinfo` to report.
+//!
+//! `BUILD_COMMIT` is the short hash of HEAD, with `-dirty` if the work
+//! tree has changes, or `unknown` outside a git checkout. `BUILD_TIME` is
+//! when the build ran, or `SOURCE_DATE_EPOCH` if that is set so builds
+//! can be reproduced.
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    println!("cargo:rustc-env=BUILD_COMMIT={}", commit());
+    println!("cargo:rustc-env=BUILD_TIME={}", format_time(build_time()));
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+fn commit() -> String {
+    let Some(hash) = git(&["rev-parse", "--short=12", "HEAD"]) else {
+        return "unknown".to_owned();
+    };
+    match git(&["status", "--porcelain", "--untracked-files=no"]) {
+        Some(status) if status.is_empty() => hash,
+        _ => hash + "-dirty",
+    }
+}
+
+fn build_time() -> u64 {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        })
+}
+
+/// Seconds since the epoch as an ISO 8601 UTC timestamp
+fn format_time(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let secs = secs % 86_400;
+
+    // Days to a civil date, after Howard Hinnant's days_from_civil inverse
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3_600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,42 @@
+//! # Alignment
+//!
+//! Rounding addresses to a power of two boundary. Rounding up fails
+//! instead of wrapping when the result wouldn't fit in a `usize`, which
+//! matters for MMIO windows near the top of the address space.
+
+/// Round `addr` up to the next multiple of `align`, or `None` if that
+/// overflows
+pub const fn align_up(addr: usize, align: usize) -> Option<usize> {
+    debug_assert!(align.is_power_of_two(), "alignment must be a power of two");
+    match addr.checked_add(align - 1) {
+        Some(addr) => Some(addr & !(align - 1)),
+        None => None,
+    }
+}
+
+/// Round `addr` down to a multiple of `align`
+pub const fn align_down(addr: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two(), "alignment must be a power of two");
+    addr & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rounds_to_the_boundary() {
+        assert_eq!(align_up(0x1001, 0x1000), Some(0x2000));
+        assert_eq!(align_up(0x1000, 0x1000), Some(0x1000));
+        assert_eq!(align_down(0x1fff, 0x1000), 0x1000);
+    }
+
+    #[test]
+    fn rounding_up_past_the_top_fails() {
+        assert_eq!(align_up(usize::MAX - 1, 0x1000), None);
+        assert_eq!(
+            align_up(usize::MAX & !0xfff, 0x1000),
+            Some(usize::MAX & !0xfff)
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! # Address space identifiers
+//!
+//! The TLB tags translations with the ASID in `satp`, so switching between
+//! address spaces with different ASIDs needs no flush. Harts implement
+//! anywhere from none to 16 ASID bits, which runs out long before address
+//! spaces do, so ASIDs are handed out in generations: each address space
+//! remembers the generation its ASID came from, and once every ASID of a
+//! generation is taken the next one starts with a single full flush, after
+//! which address spaces pick up a new ASID as they are next switched to.
+//!
+//! ASID 0 is never handed out, so translations made before the allocator
+//! existed can't be mistaken for those of an address space.
+//!
+//! An address space that is destroyed hands its ASID back once every hart
+//! it ran on has flushed it, and the ASID is handed out again before the
+//! generation's unused ones, putting off the next rollover.
+
+/// Which ASID an address space was last given, and in which generation
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct AddressSpaceId {
+    /// Zero until the first ASID is assigned, as generations start at one
+    generation: u64,
+    asid: u16,
+}
+
+impl AddressSpaceId {
+    pub const fn new() -> Self {
+        Self {
+            generation: 0,
+            asid: 0,
+        }
+    }
+
+    /// The ASID last assigned, zero if none has been
+    pub const fn asid(&self) -> u16 {
+        self.asid
+    }
+}
+
+/// How often switching address spaces could skip the TLB flush
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct AsidStats {
+    /// Switches to an address space whose ASID was still current
+    pub flushes_avoided: u64,
+    /// Switches that had to flush the whole TLB
+    pub flushes_forced: u64,
+    /// Times every ASID was taken and a new generation began
+    pub rollovers: u64,
+}
+
+/// Released ASIDs kept for reuse. Any more wait for the next rollover.
+const RELEASED_ASIDS: usize = 16;
+
+pub struct AsidAllocator {
+    /// ASID bits the hart implements
+    bits: u32,
+    generation: u64,
+    /// The next ASID of this generation to hand out
+    next: u32,
+    /// ASIDs of this generation that were released, already flushed
+    released: [u16; RELEASED_ASIDS],
+    released_len: usize,
+    stats: AsidStats,
+}
+
+impl AsidAllocator {
+    pub const fn new(bits: u32) -> Self {
+        Self {
+            bits,
+            generation: 1,
+            next: 1,
+            released: [0; RELEASED_ASIDS],
+            released_len: 0,
+            stats: AsidStats {
+                flushes_avoided: 0,
+                flushes_forced: 0,
+                rollovers: 0,
+            },
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn stats(&self) -> AsidStats {
+        self.stats
+    }
+
+    /// Make sure `id` holds an ASID of the current generation, returning
+    /// it and whether the TLB must be flushed before using it
+    pub fn assign(&mut self, id: &mut AddressSpaceId) -> (u16, bool) {
+        // Without ASIDs every address space shares the TLB
+        if self.bits == 0 {
+            self.stats.flushes_forced += 1;
+            return (0, true);
+        }
+
+        if id.generation == self.generation {
+            self.stats.flushes_avoided += 1;
+            return (id.asid, false);
+        }
+
+        if self.released_len > 0 {
+            // Flushed everywhere when it was released
+            self.released_len -= 1;
+            *id = AddressSpaceId {
+                generation: self.generation,
+                asid: self.released[self.released_len],
+            };
+            self.stats.flushes_avoided += 1;
+            return (id.asid, false);
+        }
+
+        let mut flush = false;
+        if self.next >= 1 << self.bits {
+            // The old generation's translations are still in the TLB under
+            // the ASIDs we are about to hand out again
+            self.generation += 1;
+            self.next = 1;
+            self.released_len = 0;
+            self.stats.rollovers += 1;
+            self.stats.flushes_forced += 1;
+            flush = true;
+        } else {
+            // Never used in this generation, so nothing cached under it
+            self.stats.flushes_avoided += 1;
+        }
+
+        *id = AddressSpaceId {
+            generation: self.generation,
+            asid: self.next as u16,
+        };
+        self.next += 1;
+        (id.asid, flush)
+    }
+
+    /// Take back the ASID of an address space that is going away. Every
+    /// hart it ran on must have flushed it first. An ASID from an earlier
+    /// generation is already free to be handed out again.
+    pub fn release(&mut self, id: AddressSpaceId) {
+        if id.generation != self.generation || self.bits == 0 {
+            return;
+        }
+        if let Some(slot) = self.released.get_mut(self.released_len) {
+            *slot = id.asid;
+            self.released_len += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_address_space_keeps_its_asid_within_a_generation() {
+        let mut asids = AsidAllocator::new(4);
+        let mut id = AddressSpaceId::new();
+        assert_eq!(id.asid(), 0);
+
+        assert_eq!(asids.assign(&mut id), (1, false));
+        assert_eq!(asids.assign(&mut id), (1, false));
+        let mut other = AddressSpaceId::new();
+        assert_eq!(asids.assign(&mut other), (2, false));
+        assert_eq!(
+            asids.stats(),
+            AsidStats {
+                flushes_avoided: 3,
+                flushes_forced: 0,
+                rollovers: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn running_out_starts_a_new_generation_with_a_flush() {
+        // ASIDs 1 to 3; 0 is never handed out
+        let mut asids = AsidAllocator::new(2);
+        let mut ids = [AddressSpaceId::new(); 3];
+        for (i, id) in ids.iter_mut().enumerate() {
+            assert_eq!(asids.assign(id), (i as u16 + 1, false));
+        }
+
+        let mut fourth = AddressSpaceId::new();
+        assert_eq!(asids.assign(&mut fourth), (1, true));
+        assert_eq!(asids.stats().rollovers, 1);
+        // The others are from the old generation and get new ASIDs, which
+        // the rollover's flush already cleared
+        assert_eq!(asids.assign(&mut ids[0]), (2, false));
+        assert_eq!(asids.assign(&mut fourth), (1, false));
+        assert_eq!(asids.stats().flushes_forced, 1);
+    }
+
+    #[test]
+    fn released_asids_are_handed_out_before_fresh_ones() {
+        let mut asids = AsidAllocator::new(4);
+        let mut first = AddressSpaceId::new();
+        let mut second = AddressSpaceId::new();
+        asids.assign(&mut first);
+        asids.assign(&mut second);
+        asids.release(first);
+
+        let mut third = AddressSpaceId::new();
+        assert_eq!(asids.assign(&mut third), (1, false));
+        let mut fourth = AddressSpaceId::new();
+        assert_eq!(asids.assign(&mut fourth), (3, false));
+    }
+
+    #[test]
+    fn releases_from_an_old_generation_are_ignored() {
+        let mut asids = AsidAllocator::new(1);
+        let mut old = AddressSpaceId::new();
+        asids.assign(&mut old);
+        let mut new = AddressSpaceId::new();
+        assert_eq!(asids.assign(&mut new), (1, true));
+
+        // ASID 1 belongs to `new` now; handing it out again would share it
+        asids.release(old);
+        let mut other = AddressSpaceId::new();
+        assert_eq!(asids.assign(&mut other), (1, true));
+        assert_eq!(asids.stats().rollovers, 2);
+    }
+
+    #[test]
+    fn without_asid_bits_every_switch_flushes() {
+        let mut asids = AsidAllocator::new(0);
+        let mut id = AddressSpaceId::new();
+        assert_eq!(asids.assign(&mut id), (0, true));
+        assert_eq!(asids.assign(&mut id), (0, true));
+        asids.release(id);
+        assert_eq!(asids.stats().flushes_forced, 2);
+        assert_eq!(asids.stats().flushes_avoided, 0);
+    }
+}
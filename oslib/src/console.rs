@@ -0,0 +1,229 @@
+//! # Console sinks
+//!
+//! Where console output can go, and the sinks that don't need a UART:
+//! one that throws output away and one that captures it into memory.
+//! [`OutputBuffer`] is the batching a sink in front of a slow device
+//! uses.
+
+use spin::Mutex;
+
+/// Somewhere console output can go
+pub trait ConsoleSink: Sync {
+    fn write_bytes(&self, bytes: &[u8]);
+
+    /// Hand anything buffered to the hardware, without waiting for it to
+    /// go out
+    fn drain(&self) {}
+
+    /// Wait until everything written so far has gone out
+    fn flush(&self) {
+        self.drain();
+    }
+}
+
+/// Discards all output
+pub struct NullConsole;
+
+impl ConsoleSink for NullConsole {
+    fn write_bytes(&self, _bytes: &[u8]) {}
+}
+
+/// Captures output into a fixed size buffer. Once it is full further
+/// output is dropped and counted.
+pub struct MemConsole<const N: usize> {
+    inner: Mutex<MemBuffer<N>>,
+}
+
+struct MemBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+    dropped: usize,
+}
+
+impl<const N: usize> MemConsole<N> {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(MemBuffer {
+                bytes: [0; N],
+                len: 0,
+                dropped: 0,
+            }),
+        }
+    }
+
+    /// Copy the captured output into `out`, returning how many bytes were copied
+    pub fn read(&self, out: &mut [u8]) -> usize {
+        let inner = self.inner.lock();
+        let len = inner.len.min(out.len());
+        out[..len].copy_from_slice(&inner.bytes[..len]);
+        len
+    }
+
+    /// Number of bytes that didn't fit
+    pub fn dropped(&self) -> usize {
+        self.inner.lock().dropped
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock();
+        inner.len = 0;
+        inner.dropped = 0;
+    }
+}
+
+impl<const N: usize> Default for MemConsole<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> ConsoleSink for MemConsole<N> {
+    fn write_bytes(&self, bytes: &[u8]) {
+        let mut inner = self.inner.lock();
+        let start = inner.len;
+        let len = bytes.len().min(N - start);
+
+        inner.bytes[start..start + len].copy_from_slice(&bytes[..len]);
+        inner.len += len;
+        inner.dropped += bytes.len() - len;
+    }
+}
+
+/// Output held back until a line ends, the buffer passes its watermark
+/// or it is drained, then handed on in one go so a UART can be fed a FIFO
+/// at a time
+pub struct OutputBuffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> OutputBuffer<N> {
+    /// Bytes buffered at which a write drains without waiting for the end
+    /// of the line
+    pub const WATERMARK: usize = N * 3 / 4;
+
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Bytes waiting to be drained
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Buffer `bytes`, handing what is buffered to `out` whenever a line
+    /// ends or the watermark is reached
+    pub fn write(&mut self, mut bytes: &[u8], mut out: impl FnMut(&[u8])) {
+        while !bytes.is_empty() {
+            let start = self.len;
+            let len = bytes.len().min(N - start);
+            self.bytes[start..start + len].copy_from_slice(&bytes[..len]);
+            self.len += len;
+
+            if self.len >= Self::WATERMARK || bytes[..len].contains(&b'\n') {
+                self.drain(&mut out);
+            }
+            bytes = &bytes[len..];
+        }
+    }
+
+    /// Hand everything buffered to `out` and empty the buffer
+    pub fn drain(&mut self, mut out: impl FnMut(&[u8])) {
+        if self.len > 0 {
+            out(&self.bytes[..self.len]);
+            self.len = 0;
+        }
+    }
+}
+
+impl<const N: usize> Default for OutputBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn mem_console_captures_output() {
+        let console = MemConsole::<16>::new();
+        console.write_bytes(b"hello ");
+        console.write_bytes(b"world");
+
+        let mut out = [0; 16];
+        let len = console.read(&mut out);
+        assert_eq!(&out[..len], b"hello world");
+        assert_eq!(console.dropped(), 0);
+    }
+
+    #[test]
+    fn mem_console_truncates_and_counts_what_it_drops() {
+        let console = MemConsole::<8>::new();
+        console.write_bytes(b"0123456");
+        console.write_bytes(b"789");
+        console.write_bytes(b"ab");
+
+        let mut out = [0; 16];
+        let len = console.read(&mut out);
+        assert_eq!(&out[..len], b"01234567");
+        assert_eq!(console.dropped(), 4);
+
+        console.clear();
+        assert_eq!(console.read(&mut out), 0);
+        assert_eq!(console.dropped(), 0);
+    }
+
+    #[test]
+    fn output_is_held_back_until_a_line_ends() {
+        let mut buffer = OutputBuffer::<64>::new();
+        let mut out = Vec::new();
+        buffer.write(b"partial ", |bytes| out.push(bytes.to_vec()));
+        buffer.write(b"line", |bytes| out.push(bytes.to_vec()));
+        assert!(out.is_empty());
+        assert_eq!(buffer.len(), 12);
+
+        buffer.write(b"!\nnext", |bytes| out.push(bytes.to_vec()));
+        assert_eq!(out, [b"partial line!\nnext".to_vec()]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn long_output_drains_at_the_watermark() {
+        let mut buffer = OutputBuffer::<16>::new();
+        let mut out = Vec::new();
+        let bytes: Vec<u8> = (b'a'..=b'z').collect();
+        buffer.write(&bytes, |bytes| out.push(bytes.len()));
+        // 16 fit, which passes the watermark of 12; the other 10 don't reach it
+        assert_eq!(out, [16]);
+        assert_eq!(buffer.len(), 10);
+    }
+
+    #[test]
+    fn nothing_is_lost_or_reordered_across_a_drain_to_unbuffered_output() {
+        // What a panic does: drain what is buffered, then write straight
+        // to the device
+        let mut buffer = OutputBuffer::<32>::new();
+        let mut device = Vec::new();
+        for chunk in [&b"boot: ok\n"[..], b"half a ", b"line"] {
+            buffer.write(chunk, |bytes| device.extend_from_slice(bytes));
+        }
+        buffer.drain(|bytes| device.extend_from_slice(bytes));
+        device.extend_from_slice(b"\npanicked\n");
+
+        assert_eq!(device, b"boot: ok\nhalf a line\npanicked\n");
+        let mut drained = false;
+        buffer.drain(|_| drained = true);
+        assert!(!drained);
+    }
+}
@@ -0,0 +1,423 @@
+//! # CSR fields
+//!
+//! Typed views of the control and status registers that have fields, and
+//! how they encode to and decode from the raw bits. The instructions that
+//! move the bits stay in the kernel.
+
+use crate::page::PAGE_ORDER;
+
+/// Privilege levels, as encoded in MPP
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Privilege {
+    User,
+    Supervisor,
+    Machine,
+}
+
+impl Privilege {
+    pub const fn bits(self) -> usize {
+        match self {
+            Self::User => 0,
+            Self::Supervisor => 1,
+            Self::Machine => 3,
+        }
+    }
+
+    pub const fn from_bits(bits: usize) -> Option<Self> {
+        match bits {
+            0 => Some(Self::User),
+            1 => Some(Self::Supervisor),
+            3 => Some(Self::Machine),
+            _ => None,
+        }
+    }
+}
+
+/// The fields of `mstatus` the kernel cares about
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Mstatus {
+    /// Interrupts enabled in M-mode
+    pub mie: bool,
+    /// MIE before the last trap
+    pub mpie: bool,
+    /// Privilege before the last trap, and the one `mret` returns to
+    pub mpp: Privilege,
+    /// Floating point state: off, initial, clean or dirty
+    pub fs: u8,
+}
+
+impl Mstatus {
+    pub const MIE: usize = 1 << 3;
+    pub const MPIE: usize = 1 << 7;
+    const MPP_SHIFT: usize = 11;
+    const FS_SHIFT: usize = 13;
+
+    pub const fn from_bits(bits: usize) -> Self {
+        Self {
+            mie: bits & Self::MIE != 0,
+            mpie: bits & Self::MPIE != 0,
+            mpp: match Privilege::from_bits((bits >> Self::MPP_SHIFT) & 0b11) {
+                Some(mpp) => mpp,
+                // 2 is reserved; WARL means it reads back as something legal
+                None => Privilege::User,
+            },
+            fs: ((bits >> Self::FS_SHIFT) & 0b11) as u8,
+        }
+    }
+
+    /// These fields over `bits`, keeping the fields we don't model
+    pub const fn apply(self, bits: usize) -> usize {
+        let cleared =
+            bits & !(Self::MIE | Self::MPIE | (0b11 << Self::MPP_SHIFT) | (0b11 << Self::FS_SHIFT));
+        cleared
+            | if self.mie { Self::MIE } else { 0 }
+            | if self.mpie { Self::MPIE } else { 0 }
+            | (self.mpp.bits() << Self::MPP_SHIFT)
+            | ((self.fs as usize & 0b11) << Self::FS_SHIFT)
+    }
+}
+
+/// Translation modes, as encoded in the MODE field of `satp`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SatpMode {
+    Bare,
+    Sv39,
+    Sv48,
+}
+
+impl SatpMode {
+    pub const fn bits(self) -> usize {
+        match self {
+            Self::Bare => 0,
+            Self::Sv39 => 8,
+            Self::Sv48 => 9,
+        }
+    }
+
+    pub const fn from_bits(bits: usize) -> Option<Self> {
+        match bits {
+            0 => Some(Self::Bare),
+            8 => Some(Self::Sv39),
+            9 => Some(Self::Sv48),
+            _ => None,
+        }
+    }
+}
+
+/// The fields of `satp`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Satp {
+    /// `None` if the mode read back is one we don't know
+    pub mode: Option<SatpMode>,
+    pub asid: u16,
+    /// Page number of the root table
+    pub ppn: usize,
+}
+
+impl Satp {
+    const PPN_MASK: usize = (1 << 44) - 1;
+
+    /// Translate through the root table at physical address `root`
+    pub fn new(mode: SatpMode, asid: u16, root: usize) -> Self {
+        debug_assert!(
+            root.is_multiple_of(1 << PAGE_ORDER),
+            "root table {:#x} isn't page aligned",
+            root
+        );
+        Self {
+            mode: Some(mode),
+            asid,
+            ppn: root >> PAGE_ORDER,
+        }
+    }
+
+    pub const fn from_bits(bits: usize) -> Self {
+        Self {
+            mode: SatpMode::from_bits(bits >> 60),
+            asid: (bits >> 44) as u16,
+            ppn: bits & Self::PPN_MASK,
+        }
+    }
+
+    pub const fn bits(self) -> usize {
+        let mode = match self.mode {
+            Some(mode) => mode.bits(),
+            None => 0,
+        };
+        (mode << 60) | ((self.asid as usize) << 44) | (self.ppn & Self::PPN_MASK)
+    }
+}
+
+/// A locked PMP entry over a naturally aligned power-of-two region that
+/// allows no access to it. Being locked, it holds for M-mode too, and
+/// stays until the hart is reset.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PmpLockout {
+    pub base: usize,
+    /// At least 8 bytes, and a power of two `base` is aligned to
+    pub size: usize,
+}
+
+impl PmpLockout {
+    /// Locked, matching a NAPOT region, with none of R, W or X
+    pub const CFG: usize = (1 << 7) | (0b11 << 3);
+
+    /// The region's `pmpaddr`: its address over four, with the low bits
+    /// giving its size as ones up to half of it
+    pub const fn addr_bits(self) -> usize {
+        (self.base >> 2) | ((self.size >> 3) - 1)
+    }
+
+    /// Whether the region is one a single entry can match
+    pub const fn is_napot(self) -> bool {
+        self.size.is_power_of_two() && self.size >= 8 && self.base.is_multiple_of(self.size)
+    }
+}
+
+/// Interrupts, as numbered in `mcause`, `mie` and `mip`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Interrupt {
+    SupervisorSoftware,
+    MachineSoftware,
+    SupervisorTimer,
+    MachineTimer,
+    SupervisorExternal,
+    MachineExternal,
+}
+
+impl Interrupt {
+    /// The interrupt's number, which is also its bit in `mie` and `mip`
+    pub const fn code(self) -> usize {
+        match self {
+            Self::SupervisorSoftware => 1,
+            Self::MachineSoftware => 3,
+            Self::SupervisorTimer => 5,
+            Self::MachineTimer => 7,
+            Self::SupervisorExternal => 9,
+            Self::MachineExternal => 11,
+        }
+    }
+}
+
+/// Synchronous exceptions, as numbered in `mcause`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Exception {
+    InstructionMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadMisaligned,
+    LoadAccessFault,
+    StoreMisaligned,
+    StoreAccessFault,
+    UserEcall,
+    SupervisorEcall,
+    MachineEcall,
+    InstructionPageFault,
+    LoadPageFault,
+    StorePageFault,
+}
+
+/// Why a trap was taken
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Cause {
+    Interrupt(Interrupt),
+    Exception(Exception),
+    /// A code this list doesn't know
+    Unknown {
+        interrupt: bool,
+        code: usize,
+    },
+}
+
+/// The fields of `mcause`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Mcause {
+    pub interrupt: bool,
+    pub code: usize,
+}
+
+impl Mcause {
+    const INTERRUPT: usize = 1 << (usize::BITS - 1);
+
+    pub const fn from_bits(bits: usize) -> Self {
+        Self {
+            interrupt: bits & Self::INTERRUPT != 0,
+            code: bits & !Self::INTERRUPT,
+        }
+    }
+
+    pub const fn bits(self) -> usize {
+        if self.interrupt {
+            self.code | Self::INTERRUPT
+        } else {
+            self.code
+        }
+    }
+
+    pub const fn cause(self) -> Cause {
+        use Exception::*;
+        use Interrupt::*;
+
+        if self.interrupt {
+            let interrupt = match self.code {
+                1 => SupervisorSoftware,
+                3 => MachineSoftware,
+                5 => SupervisorTimer,
+                7 => MachineTimer,
+                9 => SupervisorExternal,
+                11 => MachineExternal,
+                code => {
+                    return Cause::Unknown {
+                        interrupt: true,
+                        code,
+                    }
+                }
+            };
+            return Cause::Interrupt(interrupt);
+        }
+
+        let exception = match self.code {
+            0 => InstructionMisaligned,
+            1 => InstructionAccessFault,
+            2 => IllegalInstruction,
+            3 => Breakpoint,
+            4 => LoadMisaligned,
+            5 => LoadAccessFault,
+            6 => StoreMisaligned,
+            7 => StoreAccessFault,
+            8 => UserEcall,
+            9 => SupervisorEcall,
+            11 => MachineEcall,
+            12 => InstructionPageFault,
+            13 => LoadPageFault,
+            15 => StorePageFault,
+            code => {
+                return Cause::Unknown {
+                    interrupt: false,
+                    code,
+                }
+            }
+        };
+        Cause::Exception(exception)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mstatus_round_trips_its_fields() {
+        let status = Mstatus {
+            mie: true,
+            mpie: false,
+            mpp: Privilege::Supervisor,
+            fs: 3,
+        };
+        let bits = status.apply(0);
+        assert_eq!(bits, Mstatus::MIE | (1 << 11) | (3 << 13));
+        assert_eq!(Mstatus::from_bits(bits), status);
+    }
+
+    #[test]
+    fn mstatus_apply_keeps_fields_it_doesnt_model() {
+        // SIE and MPRV, then every field we do model set
+        let other = (1 << 1) | (1 << 17);
+        let bits = other | Mstatus::MIE | Mstatus::MPIE | (0b11 << 11) | (0b11 << 13);
+        let status = Mstatus {
+            mie: false,
+            mpie: false,
+            mpp: Privilege::User,
+            fs: 0,
+        };
+        assert_eq!(status.apply(bits), other);
+    }
+
+    #[test]
+    fn reserved_mpp_reads_as_user() {
+        assert_eq!(Mstatus::from_bits(0b10 << 11).mpp, Privilege::User);
+        assert_eq!(Mstatus::from_bits(0b11 << 11).mpp, Privilege::Machine);
+    }
+
+    #[test]
+    fn satp_puts_mode_asid_and_root_in_their_fields() {
+        let satp = Satp::new(SatpMode::Sv39, 0x1234, 0x8020_0000);
+        assert_eq!(satp.ppn, 0x80200);
+        assert_eq!(satp.bits(), (8 << 60) | (0x1234 << 44) | 0x80200);
+        assert_eq!(Satp::from_bits(satp.bits()), satp);
+
+        // A mode we don't know decodes as none, and encodes as bare
+        let unknown = Satp::from_bits(10 << 60);
+        assert_eq!(unknown.mode, None);
+        assert_eq!(unknown.bits(), 0);
+    }
+
+    #[test]
+    fn mcause_splits_interrupts_from_exceptions() {
+        let timer = Mcause::from_bits((1 << (usize::BITS - 1)) | 7);
+        assert!(timer.interrupt);
+        assert_eq!(timer.cause(), Cause::Interrupt(Interrupt::MachineTimer));
+        assert_eq!(Mcause::from_bits(timer.bits()), timer);
+
+        assert_eq!(
+            Mcause::from_bits(15).cause(),
+            Cause::Exception(Exception::StorePageFault)
+        );
+        assert_eq!(
+            Mcause::from_bits(10).cause(),
+            Cause::Unknown {
+                interrupt: false,
+                code: 10
+            }
+        );
+    }
+
+    #[test]
+    fn interrupt_codes_decode_back_to_themselves() {
+        use Interrupt::*;
+
+        for interrupt in [
+            SupervisorSoftware,
+            MachineSoftware,
+            SupervisorTimer,
+            MachineTimer,
+            SupervisorExternal,
+            MachineExternal,
+        ] {
+            let mcause = Mcause {
+                interrupt: true,
+                code: interrupt.code(),
+            };
+            assert_eq!(mcause.cause(), Cause::Interrupt(interrupt));
+        }
+    }
+
+    #[test]
+    fn lockout_address_encodes_napot_size() {
+        let page = PmpLockout {
+            base: 0x8020_0000,
+            size: 4096,
+        };
+        assert!(page.is_napot());
+        // Address over four, then ones up to half the size: 4096 / 8 - 1
+        assert_eq!(page.addr_bits(), (0x8020_0000 >> 2) | 0x1ff);
+
+        let smallest = PmpLockout {
+            base: 0x1000,
+            size: 8,
+        };
+        assert_eq!(smallest.addr_bits(), 0x400);
+
+        assert!(!PmpLockout {
+            base: 0x1800,
+            size: 4096
+        }
+        .is_napot());
+        assert!(!PmpLockout {
+            base: 0x1000,
+            size: 4
+        }
+        .is_napot());
+    }
+}
@@ -0,0 +1,619 @@
+//! # Flattened device tree
+//!
+//! Just enough of a reader for the devicetree blob QEMU hands us in `a1` to
+//! look nodes up by path and read their properties. Everything is read in
+//! place; the blob has to stay where it is for as long as the kernel runs.
+//! Every offset in it is checked before use, so a corrupt blob reads as a
+//! shorter tree rather than running off the end.
+
+use crate::align::align_up;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+
+/// Size of the header, up to and including `size_dt_struct`
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FdtError {
+    /// No blob was passed to us
+    Null,
+    /// The header doesn't start with the devicetree magic
+    BadMagic,
+    /// The header's offsets point outside the blob
+    BadHeader,
+}
+
+/// Read a big endian u32 at `offset`
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Read the nul terminated string at `offset`
+fn read_str(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// Combine big endian cells into one number
+fn read_cells(cells: &[u8]) -> usize {
+    cells
+        .iter()
+        .fold(0, |value, &byte| (value << 8) | byte as usize)
+}
+
+/// A devicetree blob
+#[derive(Copy, Clone)]
+pub struct Fdt {
+    data: &'static [u8],
+    structs: &'static [u8],
+    strings: &'static [u8],
+}
+
+impl Fdt {
+    /// Validate the header of the blob at `addr`.
+    ///
+    /// # Safety
+    /// `addr` must either be 0 or point at memory that stays mapped and
+    /// unmodified for the rest of the kernel's life.
+    pub unsafe fn from_addr(addr: usize) -> Result<Self, FdtError> {
+        if addr == 0 {
+            return Err(FdtError::Null);
+        }
+
+        let header = core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE);
+        if read_u32(header, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = read_u32(header, 4).unwrap() as usize;
+        Self::from_bytes(core::slice::from_raw_parts(
+            addr as *const u8,
+            total_size.max(HEADER_SIZE),
+        ))
+    }
+
+    /// Validate the header of the blob in `data`
+    pub fn from_bytes(data: &'static [u8]) -> Result<Self, FdtError> {
+        let header = data.get(..HEADER_SIZE).ok_or(FdtError::BadHeader)?;
+        if read_u32(header, 0) != Some(FDT_MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+
+        let field = |offset| read_u32(header, offset).unwrap() as usize;
+        let data = data.get(..field(4)).ok_or(FdtError::BadHeader)?;
+        // The header is untrusted; a corrupt one mustn't overflow the end
+        let block = |offset: usize, size: usize| {
+            offset
+                .checked_add(size)
+                .and_then(|end| data.get(offset..end))
+                .ok_or(FdtError::BadHeader)
+        };
+        let structs = block(field(8), field(36))?;
+        let strings = block(field(12), field(32))?;
+
+        Ok(Self {
+            data,
+            structs,
+            strings,
+        })
+    }
+
+    /// Physical address of the blob
+    pub fn address(&self) -> usize {
+        self.data.as_ptr() as usize
+    }
+
+    /// Size of the blob in bytes
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The `(address, size)` pairs of the memory reservation block: memory
+    /// the firmware uses that the kernel must leave alone
+    pub fn reservations(&self) -> impl Iterator<Item = (usize, usize)> {
+        let data = self.data;
+        let start = read_u32(data, 16).unwrap_or(0) as usize;
+        data.get(start..)
+            .unwrap_or(&[])
+            .chunks_exact(16)
+            .map(|entry| {
+                let (address, size) = entry.split_at(8);
+                (read_cells(address), read_cells(size))
+            })
+            .take_while(|&(_, size)| size != 0)
+    }
+
+    /// The `(address, size)` ranges of RAM the `memory` nodes describe
+    pub fn memory(&self) -> impl Iterator<Item = (usize, usize)> {
+        let root = self.root();
+        root.into_iter().flat_map(|root| {
+            root.children()
+                .filter(|node| node.property_str("device_type") == Some("memory"))
+                .flat_map(move |node| node.reg(&root))
+        })
+    }
+
+    /// Every node of the tree in the order it appears in the blob
+    pub fn nodes(&self) -> Nodes {
+        Nodes {
+            fdt: *self,
+            offset: 0,
+            depth: 0,
+            min_depth: 0,
+        }
+    }
+
+    /// The root node
+    pub fn root(&self) -> Option<Node> {
+        self.nodes().next()
+    }
+
+    /// The node whose `phandle` is `phandle`
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node> {
+        self.nodes().find(|node| node.phandle() == Some(phandle))
+    }
+
+    /// Look up a node by its full path, like `/chosen` or `/soc/uart@10000000`.
+    /// Components without a unit address match any unit address.
+    pub fn find_node(&self, path: &str) -> Option<Node> {
+        let mut node = self.root()?;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            node = node
+                .children()
+                .find(|child| child.matches_name(component))?;
+        }
+        Some(node)
+    }
+}
+
+/// A node of the tree
+#[derive(Copy, Clone)]
+pub struct Node {
+    fdt: Fdt,
+    name: &'static str,
+    depth: usize,
+    /// Offset of the node's first property in the struct block
+    offset: usize,
+}
+
+impl Node {
+    /// The node's name including its unit address, empty for the root
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The name without the unit address
+    pub fn base_name(&self) -> &'static str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    /// The unit address part of the name, if any
+    pub fn unit_address(&self) -> Option<&'static str> {
+        self.name.split_once('@').map(|(_, address)| address)
+    }
+
+    /// How deep the node is nested, 0 for the root
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn matches_name(&self, component: &str) -> bool {
+        if component.contains('@') {
+            self.name == component
+        } else {
+            self.base_name() == component
+        }
+    }
+
+    /// The properties of this node
+    pub fn properties(&self) -> Properties {
+        Properties {
+            fdt: self.fdt,
+            offset: self.offset,
+        }
+    }
+
+    /// The raw value of the property called `name`
+    pub fn property(&self, name: &str) -> Option<&'static [u8]> {
+        self.properties()
+            .find(|prop| prop.name == name)
+            .map(|prop| prop.value)
+    }
+
+    /// A string property, without its nul terminator
+    pub fn property_str(&self, name: &str) -> Option<&'static str> {
+        let value = self.property(name)?;
+        let value = value.strip_suffix(&[0]).unwrap_or(value);
+        core::str::from_utf8(value).ok()
+    }
+
+    /// A single cell property
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        read_u32(self.property(name)?, 0)
+    }
+
+    /// A property of one or two cells read as one number, like `size` or
+    /// `alignment`
+    pub fn property_cells(&self, name: &str) -> Option<usize> {
+        let value = self.property(name)?;
+        matches!(value.len(), 4 | 8).then(|| read_cells(value))
+    }
+
+    /// The handle other nodes refer to this one by
+    pub fn phandle(&self) -> Option<u32> {
+        self.property_u32("phandle")
+            .or_else(|| self.property_u32("linux,phandle"))
+    }
+
+    /// The nodes the `memory-region` property refers to
+    pub fn memory_regions(&self) -> impl Iterator<Item = Node> {
+        let fdt = self.fdt;
+        self.property("memory-region")
+            .unwrap_or(&[])
+            .chunks_exact(4)
+            .filter_map(move |cell| fdt.find_phandle(read_cells(cell) as u32))
+    }
+
+    /// `#address-cells`, the number of cells in a child's `reg` addresses
+    pub fn address_cells(&self) -> usize {
+        self.property_u32("#address-cells").unwrap_or(2) as usize
+    }
+
+    /// `#size-cells`, the number of cells in a child's `reg` sizes
+    pub fn size_cells(&self) -> usize {
+        self.property_u32("#size-cells").unwrap_or(1) as usize
+    }
+
+    /// The `(address, size)` pairs of `reg`, laid out as `parent`'s
+    /// `#address-cells` and `#size-cells` say
+    pub fn reg(&self, parent: &Node) -> impl Iterator<Item = (usize, usize)> {
+        let address_cells = parent.address_cells();
+        let size_cells = parent.size_cells();
+        let entry = (address_cells + size_cells) * 4;
+
+        self.property("reg")
+            .unwrap_or(&[])
+            .chunks_exact(entry.max(1))
+            .map(move |chunk| {
+                let (address, size) = chunk.split_at(address_cells * 4);
+                (read_cells(address), read_cells(size))
+            })
+    }
+
+    /// The entries of the `compatible` string list
+    pub fn compatible(&self) -> impl Iterator<Item = &'static str> {
+        self.property_str("compatible")
+            .unwrap_or("")
+            .split('\0')
+            .filter(|c| !c.is_empty())
+    }
+
+    /// Whether `compatible` lists `compat`
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.compatible().any(|c| c == compat)
+    }
+
+    /// The direct children of this node
+    pub fn children(&self) -> impl Iterator<Item = Node> {
+        let depth = self.depth + 1;
+        Nodes {
+            fdt: self.fdt,
+            offset: self.offset,
+            depth,
+            min_depth: depth,
+        }
+        .filter(move |node| node.depth == depth)
+    }
+}
+
+/// Iterator over nodes, stopping once it leaves the subtree it started in
+pub struct Nodes {
+    fdt: Fdt,
+    offset: usize,
+    depth: usize,
+    min_depth: usize,
+}
+
+impl Iterator for Nodes {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        let structs = self.fdt.structs;
+        loop {
+            let token = read_u32(structs, self.offset)?;
+            self.offset += 4;
+
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_str(structs, self.offset)?;
+                    self.offset = align_up(self.offset + name.len() + 1, 4)?;
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        depth: self.depth,
+                        offset: self.offset,
+                    };
+                    self.depth += 1;
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    if self.depth == self.min_depth {
+                        return None;
+                    }
+                    self.depth -= 1;
+                }
+                FDT_PROP => {
+                    let len = read_u32(structs, self.offset)? as usize;
+                    self.offset = align_up(self.offset + 8 + len, 4)?;
+                }
+                FDT_NOP => {}
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// A property of a node
+#[derive(Copy, Clone)]
+pub struct Property {
+    pub name: &'static str,
+    pub value: &'static [u8],
+}
+
+/// Iterator over the properties of one node
+pub struct Properties {
+    fdt: Fdt,
+    offset: usize,
+}
+
+impl Iterator for Properties {
+    type Item = Property;
+
+    fn next(&mut self) -> Option<Property> {
+        let structs = self.fdt.structs;
+        loop {
+            match read_u32(structs, self.offset)? {
+                FDT_PROP => {
+                    let len = read_u32(structs, self.offset + 4)? as usize;
+                    let name_offset = read_u32(structs, self.offset + 8)? as usize;
+                    let value = structs.get(self.offset + 12..self.offset + 12 + len)?;
+                    self.offset = align_up(self.offset + 12 + len, 4)?;
+
+                    return Some(Property {
+                        name: read_str(self.fdt.strings, name_offset)?,
+                        value,
+                    });
+                }
+                FDT_NOP => self.offset += 4,
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// The end of the struct block
+    const FDT_END: u32 = 9;
+
+    /// Writes a devicetree blob the way dtc lays one out: header, memory
+    /// reservation block, struct block, strings block
+    pub(crate) struct Builder {
+        reservations: Vec<(u64, u64)>,
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl Builder {
+        pub(crate) fn new() -> Self {
+            Self {
+                reservations: Vec::new(),
+                structs: Vec::new(),
+                strings: Vec::new(),
+            }
+        }
+
+        fn token(&mut self, token: u32) {
+            self.structs.extend_from_slice(&token.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            while !self.structs.len().is_multiple_of(4) {
+                self.structs.push(0);
+            }
+        }
+
+        /// Add a `/memreserve/` entry
+        pub(crate) fn reserve(&mut self, address: u64, size: u64) -> &mut Self {
+            self.reservations.push((address, size));
+            self
+        }
+
+        pub(crate) fn begin(&mut self, name: &str) -> &mut Self {
+            self.token(FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        pub(crate) fn end(&mut self) -> &mut Self {
+            self.token(FDT_END_NODE);
+            self
+        }
+
+        pub(crate) fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+
+            self.token(FDT_PROP);
+            self.token(value.len() as u32);
+            self.token(name_offset);
+            self.structs.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        /// A property of big endian cells
+        pub(crate) fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        /// A string list property, like `compatible`
+        pub(crate) fn strs(&mut self, name: &str, strs: &[&str]) -> &mut Self {
+            let value: Vec<u8> = strs.iter().flat_map(|s| s.bytes().chain([0])).collect();
+            self.prop(name, &value)
+        }
+
+        /// The finished blob, leaked so it lives as long as a real one
+        pub(crate) fn blob(&mut self) -> &'static [u8] {
+            self.token(FDT_END);
+            let reservations = HEADER_SIZE;
+            let structs = reservations + (self.reservations.len() + 1) * 16;
+            let strings = structs + self.structs.len();
+            let total = strings + self.strings.len();
+
+            let mut blob = Vec::with_capacity(total);
+            for field in [
+                FDT_MAGIC,
+                total as u32,
+                structs as u32,
+                strings as u32,
+                reservations as u32,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structs.len() as u32,
+            ] {
+                blob.extend_from_slice(&field.to_be_bytes());
+            }
+            for &(address, size) in self.reservations.iter().chain([&(0, 0)]) {
+                blob.extend_from_slice(&address.to_be_bytes());
+                blob.extend_from_slice(&size.to_be_bytes());
+            }
+            blob.extend_from_slice(&self.structs);
+            blob.extend_from_slice(&self.strings);
+            Vec::leak(blob)
+        }
+
+        pub(crate) fn build(&mut self) -> Fdt {
+            Fdt::from_bytes(self.blob()).unwrap()
+        }
+    }
+
+    /// A cut down `virt` machine
+    fn virt() -> Fdt {
+        Builder::new()
+            .reserve(0x8000_0000, 0x8_0000)
+            .begin("")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .strs("compatible", &["riscv-virtio"])
+            .begin("memory@80000000")
+            .strs("device_type", &["memory"])
+            .cells("reg", &[0, 0x8000_0000, 0, 0x800_0000])
+            .end()
+            .begin("soc")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .begin("serial@10000000")
+            .strs("compatible", &["ns16550a"])
+            .cells("reg", &[0, 0x1000_0000, 0, 0x100])
+            .cells("phandle", &[3])
+            .end()
+            .begin("test@100000")
+            .strs("compatible", &["sifive,test1", "sifive,test0"])
+            .end()
+            .end()
+            .end()
+            .build()
+    }
+
+    #[test]
+    fn nodes_are_found_by_path() {
+        let fdt = virt();
+        let serial = fdt.find_node("/soc/serial@10000000").unwrap();
+        assert_eq!(serial.name(), "serial@10000000");
+        assert_eq!(serial.unit_address(), Some("10000000"));
+        assert_eq!(serial.depth(), 2);
+        assert_eq!(fdt.find_node("/soc/serial").unwrap().name(), serial.name());
+        assert!(fdt.find_node("/soc/serial@10000001").is_none());
+        assert_eq!(fdt.find_phandle(3).unwrap().name(), serial.name());
+
+        let soc = fdt.find_node("/soc").unwrap();
+        let children: Vec<&str> = soc.children().map(|node| node.name()).collect();
+        assert_eq!(children, ["serial@10000000", "test@100000"]);
+        assert_eq!(serial.reg(&soc).collect::<Vec<_>>(), [(0x1000_0000, 0x100)]);
+    }
+
+    #[test]
+    fn compatible_lists_every_entry() {
+        let fdt = virt();
+        let test = fdt.find_node("/soc/test").unwrap();
+        assert_eq!(
+            test.compatible().collect::<Vec<_>>(),
+            ["sifive,test1", "sifive,test0"]
+        );
+        assert!(test.is_compatible("sifive,test0"));
+        assert!(!test.is_compatible("sifive,test"));
+    }
+
+    #[test]
+    fn memory_and_reservations_are_read_from_the_blob() {
+        let fdt = virt();
+        assert_eq!(
+            fdt.memory().collect::<Vec<_>>(),
+            [(0x8000_0000, 0x800_0000)]
+        );
+        assert_eq!(
+            fdt.reservations().collect::<Vec<_>>(),
+            [(0x8000_0000, 0x8_0000)]
+        );
+    }
+
+    #[test]
+    fn headers_pointing_outside_the_blob_are_refused() {
+        let blob = Builder::new().begin("").end().blob().to_vec();
+
+        let mut bad_magic = blob.clone();
+        bad_magic[0] = 0;
+        assert_eq!(
+            Fdt::from_bytes(Vec::leak(bad_magic)).err(),
+            Some(FdtError::BadMagic)
+        );
+
+        // Struct block offset and size that would overflow if added
+        // unchecked on a 32-bit target, and run off the end on any
+        let mut overflowing = blob.clone();
+        overflowing[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        overflowing[36..40].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            Fdt::from_bytes(Vec::leak(overflowing)).err(),
+            Some(FdtError::BadHeader)
+        );
+
+        let mut too_long = blob.clone();
+        too_long[4..8].copy_from_slice(&(blob.len() as u32 + 1).to_be_bytes());
+        assert_eq!(
+            Fdt::from_bytes(Vec::leak(too_long)).err(),
+            Some(FdtError::BadHeader)
+        );
+        assert_eq!(
+            Fdt::from_bytes(&blob.leak()[..HEADER_SIZE - 1]).err(),
+            Some(FdtError::BadHeader)
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! # Once-initialized globals
+//!
+//! The cell the kernel's `globals` keep shared state in: written exactly
+//! once during boot and read afterwards, with reads before the write
+//! panicking with the global's name instead of returning garbage.
+
+use spin::Once;
+
+/// A value that is initialized once during boot and read afterwards
+pub struct Global<T> {
+    name: &'static str,
+    cell: Once<T>,
+}
+
+impl<T> Global<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            cell: Once::new(),
+        }
+    }
+
+    /// Store the value. Initializing the same global twice is a bug.
+    pub fn init(&self, value: T) -> &T {
+        assert!(!self.cell.is_completed(), "{} initialized twice", self.name);
+        self.cell.call_once(|| value)
+    }
+
+    /// The stored value, panicking if it hasn't been initialized yet
+    pub fn get(&self) -> &T {
+        match self.cell.get() {
+            Some(value) => value,
+            None => panic!("{} used before it was initialized", self.name),
+        }
+    }
+
+    /// The stored value, if it has been initialized
+    pub fn try_get(&self) -> Option<&T> {
+        self.cell.get()
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.cell.is_completed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "frame allocator region used before it was initialized")]
+    fn use_before_init_panics_with_the_name() {
+        let global: Global<u32> = Global::new("frame allocator region");
+        global.get();
+    }
+
+    #[test]
+    #[should_panic(expected = "console sink initialized twice")]
+    fn second_init_panics() {
+        let global = Global::new("console sink");
+        global.init(1);
+        global.init(2);
+    }
+
+    #[test]
+    fn reads_after_init_see_the_value() {
+        let global = Global::new("timebase frequency");
+        assert!(!global.is_initialized());
+        assert_eq!(global.try_get(), None);
+        global.init(10_000_000u64);
+        assert!(global.is_initialized());
+        assert_eq!(*global.get(), 10_000_000);
+        assert_eq!(global.try_get(), Some(&10_000_000));
+    }
+}
@@ -0,0 +1,64 @@
+//! # GPIO
+//!
+//! What the kernel needs from a GPIO controller, so code driving pins
+//! doesn't care whose controller they are on.
+
+use crate::mmio::MmioError;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+/// Which transitions of an input raise an interrupt
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Edge {
+    Rising,
+    Falling,
+    Both,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GpioError {
+    /// The device tree node has no `reg`
+    NoRegion,
+    /// The controller has no pin with that number
+    NoSuchPin(u32),
+    /// The device tree doesn't say which interrupt the pin raises
+    NoInterrupt(u32),
+    /// There is no interrupt controller to route the pin's interrupt through
+    NoPlic,
+    /// Nothing would handle the pin's interrupt, the PLIC source given
+    Unhandled(u32),
+    Mmio(MmioError),
+}
+
+impl From<MmioError> for GpioError {
+    fn from(error: MmioError) -> Self {
+        Self::Mmio(error)
+    }
+}
+
+/// A bank of general purpose pins
+pub trait GpioController {
+    /// Number of pins, numbered from 0
+    fn pins(&self) -> u32;
+
+    fn set_direction(&self, pin: u32, direction: Direction) -> Result<(), GpioError>;
+
+    /// Drive an output pin high or low
+    fn set(&self, pin: u32, high: bool) -> Result<(), GpioError>;
+
+    /// Read the level of an input pin
+    fn get(&self, pin: u32) -> Result<bool, GpioError>;
+
+    /// Interrupt on `edge` of the input `pin`, returning the PLIC source
+    /// the interrupt arrives on
+    fn enable_interrupt(&self, pin: u32, edge: Edge) -> Result<u32, GpioError>;
+
+    fn disable_interrupt(&self, pin: u32) -> Result<(), GpioError>;
+
+    /// Clear the latched edge of `pin`, so it can interrupt again
+    fn acknowledge(&self, pin: u32) -> Result<(), GpioError>;
+}
@@ -0,0 +1,72 @@
+//! # Heartbeat patterns
+//!
+//! How a heartbeat LED blinks to say what state the kernel is in, for
+//! boards where nothing else can be seen. Each pattern repeats forever:
+//!
+//! - healthy: on for half a second, off for half a second
+//! - panicked: a fast, even flicker, five times a second
+//! - boot failure `n`: `n` short blinks, then a long pause, so the
+//!   blinks can be counted to tell which subsystem didn't come up
+
+/// What the heartbeat shows
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Pattern {
+    Healthy,
+    Panicked,
+    /// The `n`th subsystem brought up at boot failed, counting from one
+    BootFailure(u8),
+}
+
+/// A blink of a boot failure pattern, lit for the first half
+const BLINK_MS: u64 = 400;
+
+/// Dark time after a boot failure's blinks before they repeat
+const PAUSE_MS: u64 = 1_500;
+
+impl Pattern {
+    /// Whether the LED is lit `ms` milliseconds into the pattern
+    pub fn is_lit(&self, ms: u64) -> bool {
+        match *self {
+            Self::Healthy => ms % 1_000 < 500,
+            Self::Panicked => ms % 200 < 100,
+            Self::BootFailure(n) => {
+                let blinks = u64::from(n) * BLINK_MS;
+                let at = ms % (blinks + PAUSE_MS);
+                at < blinks && at % BLINK_MS < BLINK_MS / 2
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How many times the LED turns on in the first `ms` milliseconds
+    fn flashes(pattern: Pattern, ms: u64) -> usize {
+        (0..ms)
+            .filter(|&t| pattern.is_lit(t) && (t == 0 || !pattern.is_lit(t - 1)))
+            .count()
+    }
+
+    #[test]
+    fn healthy_blinks_once_a_second() {
+        assert!(Pattern::Healthy.is_lit(0));
+        assert!(!Pattern::Healthy.is_lit(500));
+        assert_eq!(flashes(Pattern::Healthy, 10_000), 10);
+    }
+
+    #[test]
+    fn panicked_blinks_faster_than_healthy() {
+        assert_eq!(flashes(Pattern::Panicked, 1_000), 5);
+    }
+
+    #[test]
+    fn boot_failure_blinks_its_number_then_pauses() {
+        let pattern = Pattern::BootFailure(3);
+        let period = 3 * BLINK_MS + PAUSE_MS;
+        assert_eq!(flashes(pattern, period), 3);
+        assert!((3 * BLINK_MS..period).all(|t| !pattern.is_lit(t)));
+        assert_eq!(flashes(pattern, 2 * period), 6);
+    }
+}
@@ -0,0 +1,324 @@
+//! # I2C
+//!
+//! Buses whose devices are addressed by the bus rather than through MMIO.
+//! A controller implements [`I2cBus`]; drivers for the devices hanging off
+//! it, like [`Eeprom`], only ever see the trait, so they work on any
+//! controller or on a mock standing in for one.
+
+use crate::mmio::MmioError;
+use crate::time::Clock;
+
+/// How long a transfer may take before it is abandoned
+pub const TRANSFER_TIMEOUT_US: u64 = 10_000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum I2cError {
+    /// Nobody acknowledged the address or a byte
+    Nack,
+    /// Another master won the bus
+    ArbitrationLost,
+    /// The controller didn't finish within [`TRANSFER_TIMEOUT_US`]
+    Timeout,
+    /// The device tree node has no `reg`
+    NoRegion,
+    /// The bus can't be clocked at the frequency asked for, in Hz
+    BadFrequency(u32),
+    /// An access runs past the end of the device
+    OutOfRange {
+        offset: usize,
+        len: usize,
+    },
+    Mmio(MmioError),
+}
+
+impl From<MmioError> for I2cError {
+    fn from(error: MmioError) -> Self {
+        Self::Mmio(error)
+    }
+}
+
+/// An I2C bus master
+pub trait I2cBus {
+    /// Write `write` to the device at 7-bit `address`, then, if `read` isn't
+    /// empty, read into it after a repeated start. With both empty this just
+    /// checks something answers at `address`.
+    fn transfer(&self, address: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError>;
+}
+
+/// A device's `reg` as a 7-bit bus address, if it is one
+pub fn bus_address(reg: u32) -> Option<u8> {
+    u8::try_from(reg).ok().filter(|&address| address <= 0x7f)
+}
+
+/// Call `attempt` until it stops failing with a NACK or `timeout_us` passes
+/// on `clock`
+pub fn poll_ack(
+    clock: &Clock,
+    timeout_us: u64,
+    mut attempt: impl FnMut() -> Result<(), I2cError>,
+) -> Result<(), I2cError> {
+    let deadline = clock.deadline_us(timeout_us);
+    loop {
+        match attempt() {
+            Err(I2cError::Nack) if !clock.has_passed(&deadline) => core::hint::spin_loop(),
+            Err(I2cError::Nack) => return Err(I2cError::Timeout),
+            result => return result,
+        }
+    }
+}
+
+/// Largest page of any 24Cxx part
+const MAX_EEPROM_PAGE: usize = 256;
+
+/// How long a 24Cxx may take to commit a page write
+pub const EEPROM_WRITE_TIMEOUT_US: u64 = 10_000;
+
+/// A 24Cxx serial EEPROM
+pub struct Eeprom<'a, B: I2cBus> {
+    bus: &'a B,
+    /// What page writes are timed against
+    clock: Clock<'a>,
+    address: u8,
+    size: usize,
+    /// Writes can't cross a page boundary
+    page_size: usize,
+}
+
+impl<'a, B: I2cBus> Eeprom<'a, B> {
+    /// A part of `size` bytes with `page_size` byte pages at `address`
+    pub fn new(bus: &'a B, clock: Clock<'a>, address: u8, size: usize, page_size: usize) -> Self {
+        assert!(page_size > 0 && page_size <= MAX_EEPROM_PAGE && page_size.is_power_of_two());
+        Self {
+            bus,
+            clock,
+            address,
+            size,
+            page_size,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Parts over 2KiB take a two byte offset; smaller ones one byte, with
+    /// the high bits of the offset in the low bits of the bus address
+    fn addressing(&self, offset: usize) -> (u8, [u8; 2], usize) {
+        if self.size > 2048 {
+            (self.address, (offset as u16).to_be_bytes(), 2)
+        } else {
+            let device = self.address | ((offset >> 8) & 0x7) as u8;
+            (device, [offset as u8, 0], 1)
+        }
+    }
+
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), I2cError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            // Past the end the part wraps around; don't let it
+            _ => Err(I2cError::OutOfRange { offset, len }),
+        }
+    }
+
+    /// Read `buf.len()` bytes starting at `offset`
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), I2cError> {
+        self.check_range(offset, buf.len())?;
+        let (device, bytes, len) = self.addressing(offset);
+        self.bus.transfer(device, &bytes[..len], buf)
+    }
+
+    /// Write `data` starting at `offset`, a page at a time, waiting for
+    /// each page to be committed before the next
+    pub fn write(&self, offset: usize, data: &[u8]) -> Result<(), I2cError> {
+        self.check_range(offset, data.len())?;
+
+        let mut offset = offset;
+        let mut data = data;
+        while !data.is_empty() {
+            let room = self.page_size - offset % self.page_size;
+            let (chunk, rest) = data.split_at(room.min(data.len()));
+
+            let (device, bytes, len) = self.addressing(offset);
+            let mut message = [0; 2 + MAX_EEPROM_PAGE];
+            message[..len].copy_from_slice(&bytes[..len]);
+            message[len..len + chunk.len()].copy_from_slice(chunk);
+            self.bus
+                .transfer(device, &message[..len + chunk.len()], &mut [])?;
+
+            // The part ignores its address until the write is committed
+            poll_ack(&self.clock, EEPROM_WRITE_TIMEOUT_US, || {
+                self.bus.transfer(device, &[], &mut [])
+            })?;
+
+            offset += chunk.len();
+            data = rest;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::time::{MockTime, TimeSource};
+
+    /// Ticks per second of the mock clock
+    const FREQUENCY: u64 = 1_000_000;
+
+    /// A 24Cxx on a bus of its own. After each write it stays busy,
+    /// NACKing, for `busy_polls` polls, each of which moves the clock on
+    /// by 100us.
+    struct MockEeprom<'a> {
+        clock: &'a MockTime,
+        memory: RefCell<Vec<u8>>,
+        /// Bus address with the block bits clear
+        address: u8,
+        two_byte: bool,
+        busy_polls: usize,
+        busy: Cell<usize>,
+        /// The address and length of every write, in order
+        writes: RefCell<Vec<(u8, usize)>>,
+    }
+
+    impl<'a> MockEeprom<'a> {
+        fn new(clock: &'a MockTime, size: usize, busy_polls: usize) -> Self {
+            Self {
+                clock,
+                memory: RefCell::new(vec![0xff; size]),
+                address: 0x50,
+                two_byte: size > 2048,
+                busy_polls,
+                busy: Cell::new(0),
+                writes: RefCell::new(Vec::new()),
+            }
+        }
+
+        /// Where the transfer to `address` starting with `write` points
+        fn offset(&self, address: u8, write: &[u8]) -> usize {
+            if self.two_byte {
+                usize::from(u16::from_be_bytes([write[0], write[1]]))
+            } else {
+                (usize::from(address & 0x7) << 8) | usize::from(write[0])
+            }
+        }
+    }
+
+    impl I2cBus for MockEeprom<'_> {
+        fn transfer(&self, address: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError> {
+            let block_bits = if self.two_byte { 0 } else { 0x7 };
+            if address & !block_bits != self.address {
+                return Err(I2cError::Nack);
+            }
+            if self.busy.get() > 0 {
+                self.busy.set(self.busy.get() - 1);
+                self.clock.advance(100);
+                return Err(I2cError::Nack);
+            }
+            if write.is_empty() {
+                return Ok(());
+            }
+
+            let header = if self.two_byte { 2 } else { 1 };
+            let offset = self.offset(address, write);
+            let mut memory = self.memory.borrow_mut();
+            let data = &write[header..];
+            if !data.is_empty() {
+                memory[offset..offset + data.len()].copy_from_slice(data);
+                self.writes.borrow_mut().push((address, data.len()));
+                self.busy.set(self.busy_polls);
+            }
+            read.copy_from_slice(&memory[offset..offset + read.len()]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_are_split_at_page_boundaries() {
+        let clock = MockTime::new(0);
+        let bus = MockEeprom::new(&clock, 256, 2);
+        let eeprom = Eeprom::new(&bus, Clock::new(&clock, FREQUENCY), 0x50, 256, 16);
+
+        let data: Vec<u8> = (0..40).collect();
+        eeprom.write(10, &data).unwrap();
+        let lengths: Vec<usize> = bus.writes.borrow().iter().map(|&(_, len)| len).collect();
+        assert_eq!(lengths, [6, 16, 16, 2]);
+
+        let mut back = [0; 40];
+        eeprom.read(10, &mut back).unwrap();
+        assert_eq!(&back[..], &data[..]);
+    }
+
+    #[test]
+    fn small_parts_put_the_offset_high_bits_in_the_address() {
+        let clock = MockTime::new(0);
+        let bus = MockEeprom::new(&clock, 2048, 0);
+        let eeprom = Eeprom::new(&bus, Clock::new(&clock, FREQUENCY), 0x50, 2048, 16);
+
+        eeprom.write(0x3f0, &[1, 2]).unwrap();
+        assert_eq!(bus.writes.borrow()[0], (0x53, 2));
+        assert_eq!(&bus.memory.borrow()[0x3f0..0x3f2], &[1, 2]);
+    }
+
+    #[test]
+    fn large_parts_take_a_two_byte_offset() {
+        let clock = MockTime::new(0);
+        let bus = MockEeprom::new(&clock, 8192, 0);
+        let eeprom = Eeprom::new(&bus, Clock::new(&clock, FREQUENCY), 0x50, 8192, 32);
+
+        eeprom.write(0x1234, &[7]).unwrap();
+        assert_eq!(bus.writes.borrow()[0], (0x50, 1));
+        assert_eq!(bus.memory.borrow()[0x1234], 7);
+        let mut back = [0];
+        eeprom.read(0x1234, &mut back).unwrap();
+        assert_eq!(back, [7]);
+    }
+
+    #[test]
+    fn write_waits_out_a_busy_part() {
+        let clock = MockTime::new(0);
+        // 3ms busy, well within the timeout
+        let bus = MockEeprom::new(&clock, 256, 30);
+        let eeprom = Eeprom::new(&bus, Clock::new(&clock, FREQUENCY), 0x50, 256, 16);
+        eeprom.write(0, &[1, 2, 3]).unwrap();
+        assert_eq!(bus.busy.get(), 0);
+    }
+
+    #[test]
+    fn write_times_out_on_a_part_that_never_commits() {
+        let clock = MockTime::new(0);
+        let bus = MockEeprom::new(&clock, 256, usize::MAX);
+        let eeprom = Eeprom::new(&bus, Clock::new(&clock, FREQUENCY), 0x50, 256, 16);
+        assert_eq!(eeprom.write(0, &[1]), Err(I2cError::Timeout));
+        assert!(clock.now_ticks() >= EEPROM_WRITE_TIMEOUT_US);
+    }
+
+    #[test]
+    fn accesses_past_the_end_are_refused() {
+        let clock = MockTime::new(0);
+        let bus = MockEeprom::new(&clock, 256, 0);
+        let eeprom = Eeprom::new(&bus, Clock::new(&clock, FREQUENCY), 0x50, 256, 16);
+        assert_eq!(
+            eeprom.write(250, &[0; 8]),
+            Err(I2cError::OutOfRange {
+                offset: 250,
+                len: 8
+            })
+        );
+        assert!(matches!(
+            eeprom.read(usize::MAX, &mut [0; 2]),
+            Err(I2cError::OutOfRange { .. })
+        ));
+        assert!(bus.writes.borrow().is_empty());
+    }
+
+    #[test]
+    fn only_seven_bit_addresses_are_bus_addresses() {
+        assert_eq!(bus_address(0x50), Some(0x50));
+        assert_eq!(bus_address(0x7f), Some(0x7f));
+        assert_eq!(bus_address(0x80), None);
+        assert_eq!(bus_address(0x150), None);
+    }
+}
@@ -0,0 +1,33 @@
+//! # oslib
+//!
+//! The parts of the kernel that don't touch the hardware, kept in their
+//! own crate so they build for the host too and `cargo test` can run
+//! their tests there. The kernel re-exports them where they used to live.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod align;
+pub mod asid;
+pub mod console;
+pub mod csr;
+pub mod fdt;
+pub mod global;
+pub mod gpio;
+pub mod heartbeat;
+pub mod i2c;
+pub mod list;
+pub mod memmap;
+pub mod mmio;
+pub mod page;
+pub mod panic_code;
+pub mod platform;
+pub mod plic;
+pub mod reserved_memory;
+pub mod sifive_gpio;
+pub mod sifive_i2c;
+pub mod stack;
+pub mod time;
+pub mod tlb;
+pub mod uart;
+pub mod utf8;
+pub mod virtio;
@@ -0,0 +1,372 @@
+//! # Intrusive linked list
+//!
+//! A doubly linked list whose links live inside the items themselves, so
+//! it needs no allocation: an item embeds a [`ListNode`] and implements
+//! [`Linked`] to say where. Anything that keeps a list of blocks it
+//! doesn't otherwise own, like free frames, uses this rather than another
+//! hand rolled chain of raw pointers.
+//!
+//! The list never owns its items. Whoever pushes an item guarantees it
+//! stays put until it has been popped or removed again.
+//!
+//! A node knows whether it is on a list, so pushing an item that already
+//! is panics at the push, even when it is the only item of another list
+//! and its links are all empty. With `debug_checks` every pop and removal
+//! also checks the links it touches.
+
+use core::marker::PhantomData;
+use core::ptr::NonNull;
+
+/// The links embedded in an item
+pub struct ListNode {
+    next: Option<NonNull<ListNode>>,
+    prev: Option<NonNull<ListNode>>,
+    /// Whether the item is on a list
+    linked: bool,
+}
+
+impl ListNode {
+    pub const fn new() -> Self {
+        Self {
+            next: None,
+            prev: None,
+            linked: false,
+        }
+    }
+
+    /// Whether the item is on a list
+    pub fn is_linked(&self) -> bool {
+        self.linked
+    }
+}
+
+impl Default for ListNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// An item that can be put on an [`IntrusiveList`]
+///
+/// # Safety
+/// `node` and `from_node` must convert between an item and its embedded
+/// [`ListNode`] and back exactly.
+pub unsafe trait Linked {
+    /// The node embedded in `item`
+    fn node(item: NonNull<Self>) -> NonNull<ListNode>;
+
+    /// The item `node` is embedded in
+    ///
+    /// # Safety
+    /// `node` must have come from [`Linked::node`].
+    unsafe fn from_node(node: NonNull<ListNode>) -> NonNull<Self>;
+}
+
+/// A list of items linked through their embedded [`ListNode`]
+pub struct IntrusiveList<T: Linked> {
+    head: Option<NonNull<ListNode>>,
+    len: usize,
+    _items: PhantomData<NonNull<T>>,
+}
+
+// The list only holds pointers to items; moving it to another hart moves
+// access to them along with it.
+unsafe impl<T: Linked + Send> Send for IntrusiveList<T> {}
+
+impl<T: Linked> IntrusiveList<T> {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            len: 0,
+            _items: PhantomData,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// Number of items on the list
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Put `item` at the front of the list. Panics if it is already on a
+    /// list.
+    ///
+    /// # Safety
+    /// `item` must be valid, and stay valid and in place until it is
+    /// popped or removed.
+    pub unsafe fn push_front(&mut self, item: NonNull<T>) {
+        let mut node = T::node(item);
+        assert!(
+            !node.as_ref().linked,
+            "list corrupted: item pushed while already on a list"
+        );
+        node.as_mut().linked = true;
+        node.as_mut().prev = None;
+        node.as_mut().next = self.head;
+        if let Some(mut head) = self.head {
+            head.as_mut().prev = Some(node);
+        }
+        self.head = Some(node);
+        self.len += 1;
+    }
+
+    /// Take the item at the front of the list
+    pub fn pop_front(&mut self) -> Option<NonNull<T>> {
+        let head = self.head?;
+        unsafe {
+            self.unlink(head);
+            Some(T::from_node(head))
+        }
+    }
+
+    /// Take `item` out of the list, wherever it is
+    ///
+    /// # Safety
+    /// `item` must be on this list.
+    pub unsafe fn remove(&mut self, item: NonNull<T>) {
+        self.unlink(T::node(item));
+    }
+
+    unsafe fn unlink(&mut self, mut node: NonNull<ListNode>) {
+        let ListNode { next, prev, linked } = *node.as_ptr();
+        // The node must be on a list, its neighbours must point back at
+        // it, and the count must have room for it
+        #[cfg(feature = "debug_checks")]
+        {
+            let linked_from_prev = match prev {
+                Some(prev) => prev.as_ref().next == Some(node),
+                None => self.head == Some(node),
+            };
+            let linked_from_next = next.is_none_or(|next| next.as_ref().prev == Some(node));
+            assert!(
+                linked && linked_from_prev && linked_from_next && self.len > 0,
+                "list corrupted: links don't match on unlink"
+            );
+        }
+        #[cfg(not(feature = "debug_checks"))]
+        let _ = linked;
+        match prev {
+            Some(mut prev) => prev.as_mut().next = next,
+            None => self.head = next,
+        }
+        if let Some(mut next) = next {
+            next.as_mut().prev = prev;
+        }
+        *node.as_mut() = ListNode::new();
+        self.len -= 1;
+    }
+
+    /// The items from front to back
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            next: self.head,
+            #[cfg(feature = "debug_checks")]
+            remaining: self.len,
+            _list: PhantomData,
+        }
+    }
+}
+
+impl<T: Linked> Default for IntrusiveList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Iterator over the items of an [`IntrusiveList`]
+pub struct Iter<'a, T: Linked> {
+    next: Option<NonNull<ListNode>>,
+    /// Items left before the walk has seen as many as the list holds. A
+    /// list whose links have been corrupted into a cycle would otherwise
+    /// be walked forever.
+    #[cfg(feature = "debug_checks")]
+    remaining: usize,
+    _list: PhantomData<&'a IntrusiveList<T>>,
+}
+
+impl<T: Linked> Iterator for Iter<'_, T> {
+    type Item = NonNull<T>;
+
+    fn next(&mut self) -> Option<NonNull<T>> {
+        let node = self.next?;
+
+        #[cfg(feature = "debug_checks")]
+        {
+            assert!(self.remaining > 0, "list corrupted: cycle detected");
+            self.remaining -= 1;
+        }
+
+        unsafe {
+            self.next = node.as_ref().next;
+            Some(T::from_node(node))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+    use std::vec::Vec;
+
+    use super::*;
+
+    struct Item {
+        node: ListNode,
+        value: u32,
+    }
+
+    unsafe impl Linked for Item {
+        fn node(item: NonNull<Self>) -> NonNull<ListNode> {
+            unsafe { NonNull::new_unchecked(core::ptr::addr_of_mut!((*item.as_ptr()).node)) }
+        }
+
+        unsafe fn from_node(node: NonNull<ListNode>) -> NonNull<Self> {
+            // the node is the first field
+            node.cast()
+        }
+    }
+
+    fn item(value: u32) -> NonNull<Item> {
+        NonNull::from(Box::leak(Box::new(Item {
+            node: ListNode::new(),
+            value,
+        })))
+    }
+
+    fn values(list: &IntrusiveList<Item>) -> Vec<u32> {
+        list.iter()
+            .map(|item| unsafe { item.as_ref().value })
+            .collect()
+    }
+
+    fn is_linked(item: NonNull<Item>) -> bool {
+        unsafe { item.as_ref().node.is_linked() }
+    }
+
+    #[test]
+    fn items_come_off_in_reverse_order_of_pushing() {
+        let mut list = IntrusiveList::new();
+        assert!(list.is_empty());
+        for value in 1..=3 {
+            unsafe { list.push_front(item(value)) };
+        }
+        assert_eq!(list.len(), 3);
+        assert_eq!(values(&list), [3, 2, 1]);
+
+        let popped: Vec<u32> = core::iter::from_fn(|| list.pop_front())
+            .map(|item| unsafe { item.as_ref().value })
+            .collect();
+        assert_eq!(popped, [3, 2, 1]);
+        assert!(list.is_empty());
+        assert_eq!(list.len(), 0);
+    }
+
+    #[test]
+    fn remove_takes_items_from_anywhere() {
+        let mut list = IntrusiveList::new();
+        let items: Vec<_> = (1..=4).map(item).collect();
+        for &item in &items {
+            unsafe { list.push_front(item) };
+        }
+
+        unsafe { list.remove(items[1]) };
+        assert_eq!(values(&list), [4, 3, 1]);
+        unsafe { list.remove(items[3]) };
+        assert_eq!(values(&list), [3, 1]);
+        unsafe { list.remove(items[0]) };
+        assert_eq!(values(&list), [3]);
+        assert_eq!(list.len(), 1);
+        assert!(!is_linked(items[1]));
+    }
+
+    #[test]
+    fn an_item_can_move_between_lists_once_taken_off() {
+        let mut first = IntrusiveList::new();
+        let mut second = IntrusiveList::new();
+        let moving = item(7);
+        unsafe { first.push_front(moving) };
+        assert!(is_linked(moving));
+
+        let taken = first.pop_front().unwrap();
+        assert!(!is_linked(taken));
+        unsafe { second.push_front(taken) };
+        assert_eq!(values(&second), [7]);
+        assert!(first.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "already on a list")]
+    fn pushing_an_item_twice_panics() {
+        let mut list = IntrusiveList::new();
+        let twice = item(1);
+        unsafe {
+            list.push_front(twice);
+            list.push_front(item(2));
+            list.push_front(twice);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "already on a list")]
+    fn pushing_the_only_item_of_another_list_panics() {
+        // Its links are both empty, so only the flag gives it away
+        let mut first = IntrusiveList::new();
+        let mut second = IntrusiveList::new();
+        let only = item(1);
+        unsafe {
+            first.push_front(only);
+            second.push_front(only);
+        }
+    }
+
+    #[cfg(feature = "debug_checks")]
+    #[test]
+    #[should_panic(expected = "links don't match")]
+    fn removing_an_item_that_isnt_on_the_list_panics() {
+        let mut list = IntrusiveList::new();
+        unsafe {
+            list.push_front(item(1));
+            list.remove(item(2));
+        }
+    }
+
+    #[cfg(feature = "debug_checks")]
+    #[test]
+    #[should_panic(expected = "cycle detected")]
+    fn walking_a_cyclic_list_panics_instead_of_hanging() {
+        let mut list = IntrusiveList::new();
+        let items: Vec<_> = (1..=3).map(item).collect();
+        for &item in &items {
+            unsafe { list.push_front(item) };
+        }
+        // Point the back item at the front one, as a lost unlink would
+        unsafe {
+            (*items[0].as_ptr()).node.next = Some(Item::node(items[2]));
+        }
+        for _ in list.iter() {}
+    }
+
+    #[test]
+    fn len_follows_every_push_pop_and_removal() {
+        let mut list = IntrusiveList::new();
+        let items: Vec<_> = (1..=5).map(item).collect();
+        for (pushed, &item) in items.iter().enumerate() {
+            unsafe { list.push_front(item) };
+            assert_eq!(list.len(), pushed + 1);
+        }
+        unsafe { list.remove(items[2]) };
+        assert_eq!(list.len(), 4);
+        list.pop_front();
+        assert_eq!(list.len(), 3);
+        assert_eq!(list.iter().count(), list.len());
+
+        unsafe { list.push_front(items[2]) };
+        assert_eq!(values(&list), [3, 4, 2, 1]);
+        while list.pop_front().is_some() {}
+        assert_eq!(list.len(), 0);
+        assert!(items.iter().all(|&item| !is_linked(item)));
+    }
+}
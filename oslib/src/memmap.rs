@@ -0,0 +1,248 @@
+//! # Physical memory map
+//!
+//! One sorted table of what lives where in the physical address space.
+//! Regions nest, so the kernel shows up inside RAM, and stretches nothing
+//! describes are listed as gaps. The kernel fills it in; this only keeps
+//! it in order and renders it.
+
+use core::fmt;
+use core::ops::Range;
+
+/// Regions the map can hold. virt has a dozen or so.
+const MAX_REGIONS: usize = 32;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RegionKind {
+    Ram,
+    Reserved,
+    DeviceTree,
+    Kernel,
+    Heap,
+    Mmio,
+}
+
+impl RegionKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            RegionKind::Ram => "ram",
+            RegionKind::Reserved => "reserved",
+            RegionKind::DeviceTree => "fdt",
+            RegionKind::Kernel => "kernel",
+            RegionKind::Heap => "heap",
+            RegionKind::Mmio => "mmio",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub range: Range<usize>,
+    pub kind: RegionKind,
+    /// What the region belongs to, like a driver or device tree node
+    pub label: &'static str,
+}
+
+/// A set of regions, kept sorted by start address
+pub struct MemoryMap {
+    regions: [Option<Region>; MAX_REGIONS],
+    len: usize,
+    /// Regions that didn't fit
+    dropped: usize,
+}
+
+impl MemoryMap {
+    pub const fn new() -> Self {
+        Self {
+            regions: [const { None }; MAX_REGIONS],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Add a region. Empty ones are ignored. At the same start address
+    /// the larger region goes first, so it reads as the container.
+    pub fn add(&mut self, kind: RegionKind, label: &'static str, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        if self.len == MAX_REGIONS {
+            self.dropped += 1;
+            return;
+        }
+
+        let at = self
+            .regions()
+            .position(|region| {
+                (region.range.start, usize::MAX - region.range.end)
+                    > (range.start, usize::MAX - range.end)
+            })
+            .unwrap_or(self.len);
+        self.regions[at..=self.len].rotate_right(1);
+        self.regions[at] = Some(Region { range, kind, label });
+        self.len += 1;
+    }
+
+    /// The regions from lowest address to highest
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().flatten()
+    }
+
+    /// The first region that shares an address with `range`
+    pub fn overlapping(&self, range: &Range<usize>) -> Option<&Region> {
+        self.regions()
+            .find(|region| region.range.start < range.end && range.start < region.range.end)
+    }
+
+    /// Regions that didn't fit in the table
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// The lines of the rendered table: every region, indented by how many
+    /// earlier regions contain it, with a gap wherever nothing covers the
+    /// addresses in between
+    pub fn rows(&self) -> impl Iterator<Item = Row<'_>> {
+        let mut covered_to: Option<usize> = None;
+        let mut containers = [0; MAX_REGIONS];
+        let mut depth = 0;
+
+        self.regions().flat_map(move |region| {
+            let range = &region.range;
+            let gap = covered_to
+                .filter(|&end| end < range.start)
+                .map(|end| Row::Gap(end..range.start));
+
+            while depth > 0 && containers[depth - 1] <= range.start {
+                depth -= 1;
+            }
+            let row = Row::Region { region, depth };
+            containers[depth] = range.end;
+            depth += 1;
+            covered_to = Some(covered_to.map_or(range.end, |end| end.max(range.end)));
+
+            gap.into_iter().chain(Some(row))
+        })
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One line of the memory map
+pub enum Row<'a> {
+    /// Addresses no region covers
+    Gap(Range<usize>),
+    /// A region, nested inside `depth` others
+    Region { region: &'a Region, depth: usize },
+}
+
+impl fmt::Display for Row<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Row::Gap(range) => write!(
+                f,
+                "{:#012x}..{:#012x} gap ({})",
+                range.start,
+                range.end,
+                Size(range.len())
+            ),
+            Row::Region { region, depth } => write!(
+                f,
+                "{:#012x}..{:#012x} {:indent$}{} {} ({})",
+                region.range.start,
+                region.range.end,
+                "",
+                region.kind.name(),
+                region.label,
+                Size(region.range.len()),
+                indent = depth * 2
+            ),
+        }
+    }
+}
+
+/// A byte count in the largest unit that divides it
+struct Size(usize);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            size if size >= 1 << 30 && size % (1 << 30) == 0 => write!(f, "{} GiB", size >> 30),
+            size if size >= 1 << 20 && size % (1 << 20) == 0 => write!(f, "{} MiB", size >> 20),
+            size if size >= 1 << 10 && size % (1 << 10) == 0 => write!(f, "{} KiB", size >> 10),
+            size => write!(f, "{} B", size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn render(map: &MemoryMap) -> Vec<String> {
+        map.rows().map(|row| row.to_string()).collect()
+    }
+
+    #[test]
+    fn sizes_use_the_largest_unit_that_divides_them() {
+        assert_eq!(Size(2 << 30).to_string(), "2 GiB");
+        assert_eq!(Size(128 << 20).to_string(), "128 MiB");
+        assert_eq!(Size(0x1800).to_string(), "6 KiB");
+        assert_eq!(Size((1 << 20) + 512).to_string(), "1049088 B");
+        assert_eq!(Size(0).to_string(), "0 B");
+    }
+
+    #[test]
+    fn rows_nest_regions_and_show_gaps() {
+        let mut map = MemoryMap::new();
+        // Added out of order, the way the kernel gathers them
+        map.add(RegionKind::Kernel, "image", 0x8020_0000..0x8040_0000);
+        map.add(RegionKind::Mmio, "uart", 0x1000_0000..0x1000_0100);
+        map.add(RegionKind::Ram, "memory", 0x8000_0000..0x8800_0000);
+        map.add(RegionKind::Heap, "frames", 0x8040_0000..0x8800_0000);
+        map.add(RegionKind::Reserved, "empty", 0x8000_0000..0x8000_0000);
+
+        assert_eq!(
+            render(&map),
+            [
+                "0x0010000000..0x0010000100 mmio uart (256 B)",
+                "0x0010000100..0x0080000000 gap (1879047936 B)",
+                "0x0080000000..0x0088000000 ram memory (128 MiB)",
+                "0x0080200000..0x0080400000   kernel image (2 MiB)",
+                "0x0080400000..0x0088000000   heap frames (124 MiB)",
+            ]
+        );
+    }
+
+    #[test]
+    fn larger_region_at_the_same_start_contains_the_smaller() {
+        let mut map = MemoryMap::new();
+        map.add(RegionKind::Reserved, "rsvmap", 0x8000_0000..0x8008_0000);
+        map.add(RegionKind::Ram, "memory", 0x8000_0000..0x8800_0000);
+
+        let kinds: Vec<_> = map.regions().map(|region| region.kind).collect();
+        assert_eq!(kinds, [RegionKind::Ram, RegionKind::Reserved]);
+        assert_eq!(
+            map.overlapping(&(0x8007_f000..0x8008_0000)).unwrap().label,
+            "memory"
+        );
+        assert!(map.overlapping(&(0x8800_0000..0x8800_1000)).is_none());
+        assert!(render(&map)[1].contains("  reserved rsvmap (512 KiB)"));
+    }
+
+    #[test]
+    fn regions_past_the_table_are_counted() {
+        let mut map = MemoryMap::new();
+        for i in 0..MAX_REGIONS + 3 {
+            map.add(RegionKind::Mmio, "device", i * 0x1000..i * 0x1000 + 0x100);
+        }
+        assert_eq!(map.regions().count(), MAX_REGIONS);
+        assert_eq!(map.dropped(), 3);
+    }
+}
@@ -0,0 +1,106 @@
+//! # Register access
+//!
+//! The bounds and alignment rules every access through the kernel's
+//! `DeviceMemory` has to pass before it touches a register, and the
+//! [`Registers`] and [`ByteRegisters`] interfaces drivers use so they can
+//! be tested against something else.
+
+use crate::page::PageError;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MmioError {
+    /// An access of `width` bytes at `offset` runs past the end of the region
+    OutOfRange { offset: usize, width: usize },
+    /// An access isn't aligned to its width
+    Misaligned { offset: usize, width: usize },
+    /// The region overlaps one already claimed by the named driver
+    Overlap(&'static str),
+    /// Every claim slot is in use
+    TooManyClaims,
+    /// The region couldn't be mapped into the kernel page table
+    Map(PageError),
+}
+
+/// 32-bit registers, for drivers that are built against something other
+/// than real device memory in tests
+pub trait Registers {
+    fn read32(&self, offset: usize) -> Result<u32, MmioError>;
+
+    fn write32(&self, offset: usize, value: u32) -> Result<(), MmioError>;
+}
+
+/// 8-bit registers, the same for devices with byte wide registers like
+/// the NS16550A
+pub trait ByteRegisters {
+    fn read8(&self, offset: usize) -> Result<u8, MmioError>;
+
+    fn write8(&self, offset: usize, value: u8) -> Result<(), MmioError>;
+}
+
+/// Check that a `width` byte access at `offset` lies inside a region of
+/// `len` bytes and is aligned to its width
+pub fn check_access(len: usize, offset: usize, width: usize) -> Result<(), MmioError> {
+    if !offset.checked_add(width).is_some_and(|end| end <= len) {
+        return Err(MmioError::OutOfRange { offset, width });
+    }
+    if !offset.is_multiple_of(width) {
+        return Err(MmioError::Misaligned { offset, width });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accesses_inside_the_region_pass() {
+        assert_eq!(check_access(0x100, 0, 4), Ok(()));
+        assert_eq!(check_access(0x100, 0xfc, 4), Ok(()));
+        assert_eq!(check_access(0x100, 0xff, 1), Ok(()));
+    }
+
+    #[test]
+    fn accesses_past_the_end_are_out_of_range() {
+        assert_eq!(
+            check_access(0x100, 0x100, 1),
+            Err(MmioError::OutOfRange {
+                offset: 0x100,
+                width: 1
+            })
+        );
+        assert_eq!(
+            check_access(0x100, 0xfe, 4),
+            Err(MmioError::OutOfRange {
+                offset: 0xfe,
+                width: 4
+            })
+        );
+        // The end of the access overflowing is out of range too
+        assert_eq!(
+            check_access(0x100, usize::MAX, 8),
+            Err(MmioError::OutOfRange {
+                offset: usize::MAX,
+                width: 8
+            })
+        );
+    }
+
+    #[test]
+    fn unaligned_accesses_are_misaligned() {
+        assert_eq!(
+            check_access(0x100, 2, 4),
+            Err(MmioError::Misaligned {
+                offset: 2,
+                width: 4
+            })
+        );
+        assert_eq!(
+            check_access(0x100, 4, 8),
+            Err(MmioError::Misaligned {
+                offset: 4,
+                width: 8
+            })
+        );
+    }
+}
@@ -0,0 +1,1133 @@
+//! # Page tables
+//!
+//! Sv39 page tables and the reference counts of the frames they map. A
+//! [`PageTable`] walks and edits its tables through their physical
+//! addresses, which is how the kernel reaches all of memory, and gets its
+//! frames from a [`Frames`] implementation: the kernel's frame allocator,
+//! or in tests an arena of host memory whose addresses stand in for
+//! physical ones.
+//!
+//! Flushing the TLB and telling other harts about changes is left to the
+//! kernel, which passes in a [`TlbBatch`] to collect what changed.
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use crate::tlb::TlbBatch;
+
+pub const PAGE_ORDER: usize = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_ORDER;
+
+/// Start of the upper half of the Sv39 address space, which only the
+/// kernel maps
+pub const KERNEL_HALF_START: usize = 0xffff_ffc0_0000_0000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PageError {
+    /// No frame was left for an intermediate table
+    OutOfMemory,
+    /// The address is not page aligned
+    Misaligned,
+    /// Something is already mapped at the address
+    AlreadyMapped,
+    /// Nothing is mapped at the address
+    NotMapped,
+    /// The hart doesn't support the translation mode the table is built for
+    ModeUnsupported,
+    /// A user mapping would expose memory only the kernel may touch
+    KernelMemory,
+}
+
+// ///////////////////////////////////
+// / REFERENCE COUNTS
+// ///////////////////////////////////
+
+/// What a reference count found wrong
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RefCountError {
+    /// A reference was taken on a frame nobody had allocated
+    NotAllocated,
+    /// A reference was dropped on a frame that had none
+    Underflow,
+}
+
+/// Number of owners and mappings referencing each physical frame, and
+/// whether it holds a page table.
+///
+/// The frame allocator hands a frame out with a count of one, every
+/// mapping of it adds one, and unmapping or freeing it drops one. The
+/// frame only goes back to the allocator once the count reaches zero, so
+/// a frame that is still mapped in another address space is never
+/// recycled underneath it.
+///
+/// Frames handed out for page tables are marked, so a user mapping of one
+/// can be refused like one of the kernel image; allocating the frame again
+/// clears the mark.
+pub struct PageRefCount {
+    /// The frame the first counter is for
+    base: usize,
+    counts: &'static [AtomicU16],
+}
+
+impl PageRefCount {
+    /// The bit of a counter that marks a table frame
+    const TABLE: u16 = 1 << 15;
+    /// The bits of a counter that count references
+    const COUNT: u16 = Self::TABLE - 1;
+
+    /// Count references to the frames from `base` on, one counter each
+    pub fn new(base: usize, counts: &'static [AtomicU16]) -> Self {
+        Self { base, counts }
+    }
+
+    /// The frames counted
+    pub fn frames(&self) -> Range<usize> {
+        self.base..self.base + (self.counts.len() << PAGE_ORDER)
+    }
+
+    /// The counter for the frame containing `addr`, if it is one of ours
+    fn counter(&self, addr: usize) -> Option<&AtomicU16> {
+        let offset = addr.checked_sub(self.base)?;
+        self.counts.get(offset >> PAGE_ORDER)
+    }
+
+    /// Current reference count of the frame containing `addr`
+    pub fn get(&self, addr: usize) -> u16 {
+        self.counter(addr)
+            .map_or(0, |count| count.load(Ordering::Relaxed) & Self::COUNT)
+    }
+
+    /// Set the reference count of the frame containing `addr`, clearing
+    /// its table mark
+    pub fn set(&self, addr: usize, value: u16) {
+        if let Some(count) = self.counter(addr) {
+            count.store(value & Self::COUNT, Ordering::Relaxed);
+        }
+    }
+
+    /// Take a reference on an allocated frame. Frames that aren't counted
+    /// are left alone.
+    pub fn increment(&self, addr: usize) -> Result<(), RefCountError> {
+        if let Some(count) = self.counter(addr) {
+            if count.fetch_add(1, Ordering::Relaxed) & Self::COUNT == 0 {
+                count.fetch_sub(1, Ordering::Relaxed);
+                return Err(RefCountError::NotAllocated);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop a reference, returning true if it was the last one
+    pub fn decrement(&self, addr: usize) -> Result<bool, RefCountError> {
+        let Some(count) = self.counter(addr) else {
+            return Ok(false);
+        };
+        match count.fetch_sub(1, Ordering::AcqRel) & Self::COUNT {
+            0 => {
+                count.fetch_add(1, Ordering::Relaxed);
+                Err(RefCountError::Underflow)
+            }
+            old => Ok(old == 1),
+        }
+    }
+
+    /// Mark the frame containing `addr` as holding a page table
+    pub fn mark_table(&self, addr: usize) {
+        if let Some(count) = self.counter(addr) {
+            count.fetch_or(Self::TABLE, Ordering::Relaxed);
+        }
+    }
+
+    /// The frame containing `addr` no longer holds a page table
+    pub fn clear_table(&self, addr: usize) {
+        if let Some(count) = self.counter(addr) {
+            count.fetch_and(!Self::TABLE, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether the frame containing `addr` holds a page table
+    pub fn is_table(&self, addr: usize) -> bool {
+        self.counter(addr)
+            .is_some_and(|count| count.load(Ordering::Relaxed) & Self::TABLE != 0)
+    }
+
+    /// Whether any frame of `range` holds a page table
+    pub fn any_table(&self, range: Range<usize>) -> bool {
+        let frames = self.frames();
+        let start = range.start.max(frames.start);
+        let end = range.end.min(frames.end);
+        (start..end)
+            .step_by(PAGE_SIZE)
+            .any(|frame| self.is_table(frame))
+    }
+}
+
+// ///////////////////////////////////
+// / PAGE TABLES
+// ///////////////////////////////////
+
+/// Flag bits of a page table entry
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PageFlags(usize);
+
+impl PageFlags {
+    pub const NONE: Self = Self(0);
+    pub const VALID: Self = Self(1 << 0);
+    pub const READ: Self = Self(1 << 1);
+    pub const WRITE: Self = Self(1 << 2);
+    pub const EXECUTE: Self = Self(1 << 3);
+    pub const USER: Self = Self(1 << 4);
+    pub const GLOBAL: Self = Self(1 << 5);
+    pub const ACCESSED: Self = Self(1 << 6);
+    pub const DIRTY: Self = Self(1 << 7);
+
+    pub const READ_WRITE: Self = Self(Self::READ.0 | Self::WRITE.0);
+    pub const READ_EXECUTE: Self = Self(Self::READ.0 | Self::EXECUTE.0);
+
+    pub const fn bits(self) -> usize {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether these flags describe a leaf rather than a pointer to the next table
+    pub const fn is_leaf(self) -> bool {
+        self.0 & (Self::READ.0 | Self::WRITE.0 | Self::EXECUTE.0) != 0
+    }
+}
+
+impl core::ops::BitOr for PageFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// A single Sv39 page table entry
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct Entry(usize);
+
+impl Entry {
+    pub const fn new(phys: usize, flags: PageFlags) -> Self {
+        Self(((phys >> PAGE_ORDER) << 10) | flags.bits())
+    }
+
+    pub const fn flags(self) -> PageFlags {
+        PageFlags(self.0 & 0x3ff)
+    }
+
+    pub const fn is_valid(self) -> bool {
+        self.flags().contains(PageFlags::VALID)
+    }
+
+    pub const fn is_leaf(self) -> bool {
+        self.flags().is_leaf()
+    }
+
+    /// Physical address the entry points to, either a frame or the next table
+    pub const fn address(self) -> usize {
+        ((self.0 >> 10) & ((1 << 44) - 1)) << PAGE_ORDER
+    }
+}
+
+/// A page table, one frame of 512 entries
+#[repr(C, align(4096))]
+pub struct Table {
+    pub entries: [Entry; 512],
+}
+
+/// A mapping [`PageTable::audit`] objects to
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AuditViolation {
+    /// Writable and executable at once, so written data could be run
+    WritableExecutable,
+    /// A user page in the upper half, which belongs to the kernel
+    UserInKernelHalf,
+    /// A user page shared by every address space
+    UserGlobal,
+}
+
+/// The sizes a leaf can map, one for each level of the table
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PageSize {
+    /// A leaf in a level 0 table
+    Size4K,
+    /// A megapage, a leaf in a level 1 table
+    Size2M,
+    /// A gigapage, a leaf in the root table
+    Size1G,
+}
+
+impl PageSize {
+    pub const fn from_level(level: usize) -> Self {
+        match level {
+            0 => Self::Size4K,
+            1 => Self::Size2M,
+            _ => Self::Size1G,
+        }
+    }
+
+    /// Level of the table a leaf of this size sits in
+    pub const fn level(self) -> usize {
+        match self {
+            Self::Size4K => 0,
+            Self::Size2M => 1,
+            Self::Size1G => 2,
+        }
+    }
+
+    pub const fn bytes(self) -> usize {
+        1 << (PAGE_ORDER + 9 * self.level())
+    }
+}
+
+/// The leaf a virtual address resolves through
+#[derive(Copy, Clone, Debug)]
+pub struct Translation {
+    /// Physical address `virt` maps to, including the offset into the page
+    pub phys: usize,
+    pub flags: PageFlags,
+    pub page_size: PageSize,
+    /// Level of the table the leaf was found in
+    pub level: usize,
+}
+
+/// A leaf found while walking an address space
+#[derive(Copy, Clone, Debug)]
+pub struct Leaf {
+    pub virt: usize,
+    pub phys: usize,
+    pub flags: PageFlags,
+    pub page_size: PageSize,
+}
+
+/// Split a virtual address into its VPN[0], VPN[1] and VPN[2] table indices
+pub const fn get_table_indices(virt: usize) -> [usize; 3] {
+    [
+        (virt >> 12) & 0x1ff,
+        (virt >> 21) & 0x1ff,
+        (virt >> 30) & 0x1ff,
+    ]
+}
+
+/// Where a [`PageTable`] gets its frames, and how it tells the rest of
+/// the system about the ones it maps
+pub trait Frames {
+    /// A zeroed frame for a table, or `None` once memory has run out
+    fn alloc_table(&self) -> Option<usize>;
+
+    /// Give back a frame [`alloc_table`](Self::alloc_table) handed out
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    fn free_table(&self, table: usize);
+
+    /// A leaf now maps the frame at `frame`
+    fn map_frame(&self, frame: usize);
+
+    /// A leaf mapping the frame at `frame` is gone
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    fn unmap_frame(&self, frame: usize);
+
+    /// Whether a user mapping of `range` would expose memory only the
+    /// kernel may touch, page tables included
+    fn is_kernel_owned(&self, range: Range<usize>) -> bool;
+
+    /// Drop any translation of `virt` this hart has cached
+    fn flush(&self, virt: usize);
+}
+
+impl<F: Frames + ?Sized> Frames for &F {
+    fn alloc_table(&self) -> Option<usize> {
+        (**self).alloc_table()
+    }
+
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    fn free_table(&self, table: usize) {
+        (**self).free_table(table)
+    }
+
+    fn map_frame(&self, frame: usize) {
+        (**self).map_frame(frame)
+    }
+
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    fn unmap_frame(&self, frame: usize) {
+        (**self).unmap_frame(frame)
+    }
+
+    fn is_kernel_owned(&self, range: Range<usize>) -> bool {
+        (**self).is_kernel_owned(range)
+    }
+
+    fn flush(&self, virt: usize) {
+        (**self).flush(virt)
+    }
+}
+
+/// The tables of an Sv39 address space
+pub struct PageTable<F: Frames> {
+    root: *mut Table,
+    frames: F,
+}
+
+// The tables are only reachable through the PageTable that owns them
+unsafe impl<F: Frames + Send> Send for PageTable<F> {}
+
+impl<F: Frames> PageTable<F> {
+    /// Create an empty address space with its tables from `frames`
+    pub fn new(frames: F) -> Result<Self, PageError> {
+        let root = frames.alloc_table().ok_or(PageError::OutOfMemory)?;
+        Ok(Self {
+            root: root as *mut Table,
+            frames,
+        })
+    }
+
+    /// Physical address of the root table
+    pub fn root(&self) -> usize {
+        self.root as usize
+    }
+
+    /// Tear the address space down: drop the reference each mapping holds
+    /// on its frames, then free every table including the root.
+    ///
+    /// GLOBAL mappings belong to the kernel, which keeps its references to
+    /// them, so their frames are left alone.
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    pub fn destroy(self) {
+        destroy_table(&self.frames, self.root(), 2);
+    }
+
+    /// Map the 4KiB page at `virt` to the frame at `phys`, taking a
+    /// reference on the frame.
+    pub fn map_page(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        flags: PageFlags,
+    ) -> Result<(), PageError> {
+        self.map(virt, phys, flags, PageSize::Size4K)
+    }
+
+    /// Map a page of `size` at `virt` to the frames starting at `phys`,
+    /// taking a reference on each of them.
+    pub fn map(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        flags: PageFlags,
+        size: PageSize,
+    ) -> Result<(), PageError> {
+        assert!(flags.is_leaf(), "map needs at least one of R, W or X");
+        if !virt.is_multiple_of(size.bytes()) || !phys.is_multiple_of(size.bytes()) {
+            return Err(PageError::Misaligned);
+        }
+        if flags.contains(PageFlags::USER) && self.frames.is_kernel_owned(phys..phys + size.bytes())
+        {
+            return Err(PageError::KernelMemory);
+        }
+
+        let indices = get_table_indices(virt);
+        let mut table = unsafe { &mut *self.root };
+
+        // Walk down from VPN[2] to the level the leaf goes in, creating
+        // tables as needed
+        for &index in indices[size.level() + 1..].iter().rev() {
+            let entry = &mut table.entries[index];
+            if !entry.is_valid() {
+                let page = self.frames.alloc_table().ok_or(PageError::OutOfMemory)?;
+                *entry = Entry::new(page, PageFlags::VALID);
+            } else if entry.is_leaf() {
+                // Already covered by a larger page
+                return Err(PageError::AlreadyMapped);
+            }
+            table = unsafe { &mut *(entry.address() as *mut Table) };
+        }
+
+        // A valid entry here is either a leaf or a table of smaller pages
+        let leaf = &mut table.entries[indices[size.level()]];
+        if leaf.is_valid() {
+            return Err(PageError::AlreadyMapped);
+        }
+
+        for frame in (phys..phys + size.bytes()).step_by(PAGE_SIZE) {
+            self.frames.map_frame(frame);
+        }
+        *leaf = Entry::new(phys, flags | PageFlags::VALID);
+
+        #[cfg(feature = "debug_checks")]
+        self.check_mapping(virt, phys, size);
+        Ok(())
+    }
+
+    /// Check a mapping that was just made resolves back to where it was
+    /// asked to, catching a bad index decomposition or walk order at the
+    /// mapping site rather than at the first access through it
+    #[cfg(feature = "debug_checks")]
+    fn check_mapping(&self, virt: usize, phys: usize, size: PageSize) {
+        let [vpn0, vpn1, vpn2] = get_table_indices(virt);
+        let recombined = (vpn2 << 30) | (vpn1 << 21) | (vpn0 << 12) | (virt & (PAGE_SIZE - 1));
+        debug_assert_eq!(
+            recombined,
+            virt & ((1 << 39) - 1),
+            "table indices of {:#x} don't recombine to it",
+            virt
+        );
+
+        let translation = self.translate_detailed(virt);
+        debug_assert!(
+            translation.is_some_and(|t| t.phys == phys && t.page_size == size),
+            "{:#x} was mapped to {:#x} ({:?}) but translates as {:?}",
+            virt,
+            phys,
+            size,
+            translation
+        );
+    }
+
+    /// Map the `size` bytes starting at `virt` to consecutive frames
+    /// starting at `phys`
+    pub fn map_range(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        size: usize,
+        flags: PageFlags,
+    ) -> Result<(), PageError> {
+        let pages = size.div_ceil(PAGE_SIZE);
+        for i in 0..pages {
+            self.map_page(virt + i * PAGE_SIZE, phys + i * PAGE_SIZE, flags)?;
+        }
+        Ok(())
+    }
+
+    /// Map the 4KiB page at `addr` to the frame at the same address
+    pub fn map_identity(&mut self, addr: usize, flags: PageFlags) -> Result<(), PageError> {
+        self.map_page(addr, addr, flags)
+    }
+
+    /// Map `start..end` to the frames at the same addresses
+    pub fn map_identity_range(
+        &mut self,
+        start: usize,
+        end: usize,
+        flags: PageFlags,
+    ) -> Result<(), PageError> {
+        self.map_range(start, start, end.saturating_sub(start), flags)
+    }
+
+    /// Remove every mapping in the `size` bytes at `virt`, dropping their
+    /// references on the frames and adding the pages to `changed`.
+    /// Superpages that stick out of the range are split so only the part
+    /// inside it goes.
+    ///
+    /// Stops at the first page that isn't mapped, leaving the pages before
+    /// it unmapped.
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    pub fn unmap_pages(
+        &mut self,
+        virt: usize,
+        size: usize,
+        changed: &mut TlbBatch,
+    ) -> Result<(), PageError> {
+        let range = page_range(virt, size)?;
+        let mut addr = range.start;
+        while addr < range.end {
+            let (leaf, page_size) = self.leaf_within(addr, &range)?;
+            let phys = leaf.address();
+            *leaf = Entry(0);
+            self.frames.flush(addr);
+            changed.add(addr);
+
+            for frame in (phys..phys + page_size.bytes()).step_by(PAGE_SIZE) {
+                self.frames.unmap_frame(frame);
+            }
+            addr += page_size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Give every page in the `size` bytes at `virt` the permissions in
+    /// `flags`, adding the pages to `changed`. Superpages that stick out of
+    /// the range are split so only the part inside it changes.
+    ///
+    /// Stops at the first page that isn't mapped, leaving the pages before
+    /// it changed.
+    pub fn protect_pages(
+        &mut self,
+        virt: usize,
+        size: usize,
+        flags: PageFlags,
+        changed: &mut TlbBatch,
+    ) -> Result<(), PageError> {
+        assert!(flags.is_leaf(), "protect needs at least one of R, W or X");
+        let range = page_range(virt, size)?;
+        let mut addr = range.start;
+        while addr < range.end {
+            let (leaf, page_size) = self.leaf_within(addr, &range)?;
+            *leaf = Entry::new(leaf.address(), flags | PageFlags::VALID);
+            self.frames.flush(addr);
+            changed.add(addr);
+            addr += page_size.bytes();
+        }
+        Ok(())
+    }
+
+    /// The leaf `virt` maps through, after splitting it until it doesn't
+    /// stick out of `range`
+    fn leaf_within(
+        &mut self,
+        virt: usize,
+        range: &Range<usize>,
+    ) -> Result<(&mut Entry, PageSize), PageError> {
+        loop {
+            let (leaf, level) = find_leaf(self.root, virt).ok_or(PageError::NotMapped)?;
+            let page_size = PageSize::from_level(level);
+            let base = virt & !(page_size.bytes() - 1);
+            if range.start <= base && base + page_size.bytes() <= range.end {
+                return Ok((unsafe { &mut *leaf }, page_size));
+            }
+            split(&self.frames, unsafe { &mut *leaf }, level, base)?;
+        }
+    }
+
+    /// Find the physical address `virt` maps to
+    pub fn translate(&self, virt: usize) -> Option<usize> {
+        self.translate_detailed(virt)
+            .map(|translation| translation.phys)
+    }
+
+    /// Find the leaf `virt` maps through. The walk stops at the first leaf,
+    /// so superpages report the level they were found at.
+    pub fn translate_detailed(&self, virt: usize) -> Option<Translation> {
+        let indices = get_table_indices(virt);
+        let mut table = unsafe { &*self.root };
+
+        for level in (0..3).rev() {
+            let entry = table.entries[indices[level]];
+            if !entry.is_valid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                let page_size = PageSize::from_level(level);
+                return Some(Translation {
+                    phys: entry.address() | (virt & (page_size.bytes() - 1)),
+                    flags: entry.flags(),
+                    page_size,
+                    level,
+                });
+            }
+            table = unsafe { &*(entry.address() as *const Table) };
+        }
+
+        None
+    }
+
+    /// Check every mapping against the rules the kernel's address spaces
+    /// keep: nothing is both writable and executable, and user pages are
+    /// neither in the upper half nor global. Returns the first leaf that
+    /// breaks one.
+    ///
+    /// The lower half can't be required to be USER: the kernel identity
+    /// maps itself and its devices there.
+    pub fn audit(&self) -> Result<(), (Leaf, AuditViolation)> {
+        let mut violation = None;
+        self.walk(|leaf| {
+            if violation.is_some() {
+                return;
+            }
+            let flags = leaf.flags;
+            let user = flags.contains(PageFlags::USER);
+            violation = if flags.contains(PageFlags::WRITE) && flags.contains(PageFlags::EXECUTE) {
+                Some((leaf, AuditViolation::WritableExecutable))
+            } else if user && leaf.virt >= KERNEL_HALF_START {
+                Some((leaf, AuditViolation::UserInKernelHalf))
+            } else if user && flags.contains(PageFlags::GLOBAL) {
+                Some((leaf, AuditViolation::UserGlobal))
+            } else {
+                None
+            };
+        });
+        violation.map_or(Ok(()), Err)
+    }
+
+    /// Call `visit` with every leaf, in ascending order of virtual address
+    pub fn walk(&self, mut visit: impl FnMut(Leaf)) {
+        walk_table(unsafe { &*self.root }, 2, 0, &mut visit);
+    }
+
+    /// A hash of every mapping in the address space. It only changes when
+    /// the layout does, so a change to the boot mappings shows up as a
+    /// different fingerprint.
+    pub fn layout_fingerprint(&self) -> u64 {
+        // FNV-1a over the fields of each leaf
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        self.walk(|leaf| {
+            let fields = [
+                leaf.virt as u64,
+                leaf.phys as u64,
+                // hardware sets A and D as the pages are used
+                (leaf.flags.bits() & !(PageFlags::ACCESSED | PageFlags::DIRTY).bits()) as u64,
+                leaf.page_size.bytes() as u64,
+            ];
+            for byte in fields.iter().flat_map(|field| field.to_le_bytes()) {
+                hash ^= u64::from(byte);
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        });
+        hash
+    }
+}
+
+/// The page aligned range covering the `size` bytes at `virt`
+fn page_range(virt: usize, size: usize) -> Result<Range<usize>, PageError> {
+    if !virt.is_multiple_of(PAGE_SIZE) {
+        return Err(PageError::Misaligned);
+    }
+    Ok(virt..virt + size.div_ceil(PAGE_SIZE) * PAGE_SIZE)
+}
+
+/// The leaf entry `virt` resolves through in the tables under `root`, and
+/// the level of the table it is in
+fn find_leaf(root: *mut Table, virt: usize) -> Option<(*mut Entry, usize)> {
+    let indices = get_table_indices(virt);
+    let mut table = root;
+
+    for level in (0..3).rev() {
+        let entry = unsafe { &mut (*table).entries[indices[level]] };
+        if !entry.is_valid() {
+            return None;
+        }
+        if entry.is_leaf() {
+            return Some((entry, level));
+        }
+        table = entry.address() as *mut Table;
+    }
+    None
+}
+
+/// Replace the superpage `leaf`, found at `level` and mapping `virt`, with
+/// a table of leaves one size down that map the same frames with the same
+/// flags. The frames keep the one reference the superpage held on each.
+fn split(
+    frames: &impl Frames,
+    leaf: &mut Entry,
+    level: usize,
+    virt: usize,
+) -> Result<(), PageError> {
+    debug_assert!(level > 0, "a 4KiB page can't be split");
+    let table = frames.alloc_table().ok_or(PageError::OutOfMemory)?;
+    let page_size = PageSize::from_level(level - 1).bytes();
+
+    let entries = unsafe { &mut (*(table as *mut Table)).entries };
+    for (index, entry) in entries.iter_mut().enumerate() {
+        *entry = Entry::new(leaf.address() + index * page_size, leaf.flags());
+    }
+    *leaf = Entry::new(table, PageFlags::VALID);
+
+    // The cached superpage matches every address inside it, so flushing
+    // through its base drops the whole of it
+    frames.flush(virt);
+    Ok(())
+}
+
+/// Release the frames mapped through the table at `table` and its
+/// subtables, then the tables themselves
+#[cfg_attr(feature = "page_poison", track_caller)]
+fn destroy_table(frames: &impl Frames, table: usize, level: usize) {
+    let entries = unsafe { &(*(table as *const Table)).entries };
+    for entry in entries.iter().filter(|entry| entry.is_valid()) {
+        if entry.is_leaf() {
+            if entry.flags().contains(PageFlags::GLOBAL) {
+                continue;
+            }
+            let size = PageSize::from_level(level).bytes();
+            for frame in (entry.address()..entry.address() + size).step_by(PAGE_SIZE) {
+                frames.unmap_frame(frame);
+            }
+        } else if level > 0 {
+            destroy_table(frames, entry.address(), level - 1);
+        }
+    }
+    frames.free_table(table);
+}
+
+fn walk_table(table: &Table, level: usize, base: usize, visit: &mut impl FnMut(Leaf)) {
+    for (index, entry) in table.entries.iter().enumerate() {
+        if !entry.is_valid() {
+            continue;
+        }
+
+        let page_size = PageSize::from_level(level);
+        let mut virt = base | (index << (PAGE_ORDER + 9 * level));
+        if level == 2 && index & 0x100 != 0 {
+            // Sv39 addresses are sign extended from bit 38
+            virt |= !((1 << 39) - 1);
+        }
+
+        if entry.is_leaf() {
+            visit(Leaf {
+                virt,
+                phys: entry.address(),
+                flags: entry.flags(),
+                page_size,
+            });
+        } else if level > 0 {
+            let next = unsafe { &*(entry.address() as *const Table) };
+            walk_table(next, level - 1, virt, visit);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    use super::*;
+
+    /// Frames for tests: a block of host memory whose addresses stand in
+    /// for physical ones, with the same reference counting the kernel's
+    /// frame allocator does
+    pub(crate) struct Arena {
+        refs: PageRefCount,
+        free: Mutex<Vec<usize>>,
+        /// Memory user mappings may not point at
+        kernel_owned: Range<usize>,
+        /// Where the last frame was unmapped or table freed from
+        #[cfg(feature = "page_poison")]
+        freed_at: Mutex<Option<&'static core::panic::Location<'static>>>,
+    }
+
+    impl Arena {
+        pub(crate) fn new(frames: usize) -> Self {
+            let memory: Vec<Table> = (0..frames)
+                .map(|_| Table {
+                    entries: [Entry(0); 512],
+                })
+                .collect();
+            let memory = Vec::leak(memory);
+            let counts = Vec::leak((0..frames).map(|_| AtomicU16::new(0)).collect());
+            let base = memory.as_ptr() as usize;
+            let free = (0..frames).rev().map(|i| base + i * PAGE_SIZE).collect();
+            Self {
+                refs: PageRefCount::new(base, counts),
+                free: Mutex::new(free),
+                kernel_owned: 0..0,
+                #[cfg(feature = "page_poison")]
+                freed_at: Mutex::new(None),
+            }
+        }
+
+        /// Allocate a frame with one reference, owned by the caller
+        fn alloc(&self) -> Option<usize> {
+            let frame = self.free.lock().unwrap().pop()?;
+            unsafe { (frame as *mut u8).write_bytes(0, PAGE_SIZE) };
+            self.refs.set(frame, 1);
+            Some(frame)
+        }
+
+        /// Drop a reference, putting the frame back once it was the last
+        fn free(&self, frame: usize) {
+            if self.refs.decrement(frame).unwrap() {
+                self.free.lock().unwrap().push(frame);
+            }
+        }
+
+        fn is_free(&self, frame: usize) -> bool {
+            self.free.lock().unwrap().contains(&frame)
+        }
+
+        fn free_frames(&self) -> usize {
+            self.free.lock().unwrap().len()
+        }
+    }
+
+    impl Frames for Arena {
+        fn alloc_table(&self) -> Option<usize> {
+            let table = self.alloc()?;
+            self.refs.mark_table(table);
+            Some(table)
+        }
+
+        #[cfg_attr(feature = "page_poison", track_caller)]
+        fn free_table(&self, table: usize) {
+            #[cfg(feature = "page_poison")]
+            self.freed_at
+                .lock()
+                .unwrap()
+                .replace(core::panic::Location::caller());
+            self.refs.clear_table(table);
+            self.free(table)
+        }
+
+        fn map_frame(&self, frame: usize) {
+            self.refs.increment(frame).unwrap()
+        }
+
+        #[cfg_attr(feature = "page_poison", track_caller)]
+        fn unmap_frame(&self, frame: usize) {
+            #[cfg(feature = "page_poison")]
+            self.freed_at
+                .lock()
+                .unwrap()
+                .replace(core::panic::Location::caller());
+            if self.refs.frames().contains(&frame) {
+                self.free(frame)
+            }
+        }
+
+        fn is_kernel_owned(&self, range: Range<usize>) -> bool {
+            (range.start < self.kernel_owned.end && self.kernel_owned.start < range.end)
+                || self.refs.any_table(range)
+        }
+
+        fn flush(&self, _virt: usize) {}
+    }
+
+    #[test]
+    fn a_shared_frame_is_freed_by_the_last_unmap() {
+        let arena = Arena::new(16);
+        let mut first = PageTable::new(&arena).unwrap();
+        let mut second = PageTable::new(&arena).unwrap();
+        let mut changed = TlbBatch::new();
+
+        let frame = arena.alloc().unwrap();
+        first
+            .map_page(0x4000, frame, PageFlags::READ_WRITE)
+            .unwrap();
+        second.map_page(0x9000, frame, PageFlags::READ).unwrap();
+        assert_eq!(arena.refs.get(frame), 3);
+
+        // The mappings keep it alive once its owner lets go
+        arena.free(frame);
+        assert_eq!(arena.refs.get(frame), 2);
+        assert!(!arena.is_free(frame));
+
+        first.unmap_pages(0x4000, PAGE_SIZE, &mut changed).unwrap();
+        assert_eq!(arena.refs.get(frame), 1);
+        assert!(!arena.is_free(frame));
+        assert_eq!(second.translate(0x9000), Some(frame));
+
+        second.unmap_pages(0x9000, PAGE_SIZE, &mut changed).unwrap();
+        assert_eq!(arena.refs.get(frame), 0);
+        assert!(arena.is_free(frame));
+    }
+
+    #[test]
+    fn mapping_a_frame_nobody_allocated_is_an_error() {
+        let arena = Arena::new(4);
+        let frame = arena.alloc().unwrap();
+        arena.free(frame);
+        assert_eq!(
+            arena.refs.increment(frame),
+            Err(RefCountError::NotAllocated)
+        );
+        assert_eq!(arena.refs.get(frame), 0);
+        assert_eq!(arena.refs.decrement(frame), Err(RefCountError::Underflow));
+    }
+
+    #[test]
+    fn frames_outside_the_table_are_not_counted() {
+        let arena = Arena::new(4);
+        assert_eq!(arena.refs.increment(0x1000), Ok(()));
+        assert_eq!(arena.refs.decrement(0x1000), Ok(false));
+        assert_eq!(arena.refs.get(0x1000), 0);
+    }
+
+    #[test]
+    fn map_identity_translates_to_the_same_address() {
+        let arena = Arena::new(8);
+        let mut table = PageTable::new(&arena).unwrap();
+
+        table
+            .map_identity(0x8000_0000, PageFlags::READ_EXECUTE)
+            .unwrap();
+        assert_eq!(table.translate(0x8000_0000), Some(0x8000_0000));
+        assert_eq!(table.translate(0x8000_0123), Some(0x8000_0123));
+
+        table
+            .map_identity_range(0x1000_0000, 0x1000_3000, PageFlags::READ_WRITE)
+            .unwrap();
+        for addr in (0x1000_0000..0x1000_3000).step_by(PAGE_SIZE) {
+            assert_eq!(table.translate(addr + 8), Some(addr + 8));
+        }
+        assert_eq!(table.translate(0x1000_3000), None);
+    }
+
+    #[test]
+    fn destroy_returns_every_frame_and_table() {
+        let arena = Arena::new(16);
+        let free = arena.free_frames();
+
+        let mut table = PageTable::new(&arena).unwrap();
+        // Far enough apart to need tables of their own
+        for virt in [0x4000_0000, 0x8000_0000, 0x8020_0000] {
+            let frame = arena.alloc().unwrap();
+            table.map_page(virt, frame, PageFlags::READ_WRITE).unwrap();
+            // The mapping holds the only reference now
+            arena.free(frame);
+        }
+        assert!(arena.free_frames() < free);
+
+        table.destroy();
+        assert_eq!(arena.free_frames(), free);
+    }
+
+    #[test]
+    fn destroy_leaves_global_frames_alone() {
+        let arena = Arena::new(8);
+        let frame = arena.alloc().unwrap();
+
+        let mut table = PageTable::new(&arena).unwrap();
+        table
+            .map_page(0x4000, frame, PageFlags::READ_WRITE | PageFlags::GLOBAL)
+            .unwrap();
+        table.destroy();
+
+        // The kernel keeps the reference the GLOBAL mapping took
+        assert_eq!(arena.refs.get(frame), 2);
+        assert!(!arena.is_free(frame));
+    }
+
+    /// A boot layout like the kernel's: its image identity mapped with
+    /// the permissions of each section, then a UART
+    fn map_boot_layout(table: &mut PageTable<&Arena>, uart_first: bool) {
+        let uart = |table: &mut PageTable<&Arena>| {
+            table
+                .map_identity(0x1000_0000, PageFlags::READ_WRITE)
+                .unwrap()
+        };
+        if uart_first {
+            uart(table);
+        }
+        table
+            .map_identity_range(0x8000_0000, 0x8000_6000, PageFlags::READ_EXECUTE)
+            .unwrap();
+        table
+            .map_identity_range(0x8000_6000, 0x8001_0000, PageFlags::READ_WRITE)
+            .unwrap();
+        if !uart_first {
+            uart(table);
+        }
+    }
+
+    #[test]
+    fn boot_layout_fingerprint_is_pinned() {
+        let arena = Arena::new(8);
+        let mut table = PageTable::new(&arena).unwrap();
+        map_boot_layout(&mut table, false);
+        // Changes whenever the mappings do; update it when they are meant to
+        assert_eq!(table.layout_fingerprint(), 0x69e9_d846_494c_61f2);
+    }
+
+    #[test]
+    fn fingerprint_ignores_mapping_order_and_hardware_bits() {
+        let arena = Arena::new(16);
+        let mut first = PageTable::new(&arena).unwrap();
+        let mut second = PageTable::new(&arena).unwrap();
+        map_boot_layout(&mut first, false);
+        map_boot_layout(&mut second, true);
+        assert_eq!(first.layout_fingerprint(), second.layout_fingerprint());
+
+        let (leaf, _) = find_leaf(second.root, 0x1000_0000).unwrap();
+        unsafe {
+            *leaf = Entry::new(
+                0x1000_0000,
+                PageFlags::READ_WRITE | PageFlags::VALID | PageFlags::ACCESSED | PageFlags::DIRTY,
+            )
+        };
+        assert_eq!(first.layout_fingerprint(), second.layout_fingerprint());
+
+        let mut changed = TlbBatch::new();
+        second
+            .protect_pages(0x1000_0000, PAGE_SIZE, PageFlags::READ, &mut changed)
+            .unwrap();
+        assert_ne!(first.layout_fingerprint(), second.layout_fingerprint());
+    }
+
+    #[cfg(feature = "page_poison")]
+    #[test]
+    fn frees_are_attributed_to_whoever_unmapped() {
+        let arena = Arena::new(8);
+        let mut table = PageTable::new(&arena).unwrap();
+        let frame = arena.alloc().unwrap();
+        table.map_page(0x4000, frame, PageFlags::READ).unwrap();
+
+        let mut changed = TlbBatch::new();
+        let (unmapped, line) = (table.unmap_pages(0x4000, PAGE_SIZE, &mut changed), line!());
+        unmapped.unwrap();
+        let freed_at = arena.freed_at.lock().unwrap().unwrap();
+        assert_eq!((freed_at.file(), freed_at.line()), (file!(), line));
+
+        let line = line!() + 1;
+        table.destroy();
+        let freed_at = arena.freed_at.lock().unwrap().unwrap();
+        assert_eq!((freed_at.file(), freed_at.line()), (file!(), line));
+    }
+
+    #[test]
+    fn user_mappings_of_kernel_memory_are_refused() {
+        let mut arena = Arena::new(8);
+        arena.kernel_owned = 0x8000_0000..0x8020_0000;
+        let mut table = PageTable::new(&arena).unwrap();
+
+        let user = PageFlags::READ_WRITE | PageFlags::USER;
+        assert_eq!(
+            table.map_page(0x4000, 0x8000_1000, user),
+            Err(PageError::KernelMemory)
+        );
+        assert_eq!(
+            table.map(0x4000_0000, 0x8000_0000, user, PageSize::Size2M),
+            Err(PageError::KernelMemory)
+        );
+        assert_eq!(table.translate(0x4000), None);
+        // The kernel may still map itself
+        table
+            .map_identity(0x8000_1000, PageFlags::READ_WRITE)
+            .unwrap();
+    }
+
+    #[test]
+    fn user_mappings_of_page_tables_are_refused() {
+        let arena = Arena::new(8);
+        let mut table = PageTable::new(&arena).unwrap();
+        let frame = arena.alloc().unwrap();
+        table.map_page(0x4000, frame, PageFlags::READ).unwrap();
+
+        let user = PageFlags::READ_WRITE | PageFlags::USER;
+        let root = table.root();
+        assert!(arena.refs.is_table(root));
+        assert_eq!(
+            table.map_page(0x8000, root, user),
+            Err(PageError::KernelMemory)
+        );
+        // Nor the tables below the root
+        let mut tables = 0;
+        for candidate in arena.refs.frames().step_by(PAGE_SIZE) {
+            if candidate != root && arena.refs.is_table(candidate) {
+                tables += 1;
+                assert_eq!(
+                    table.map_page(0x8000, candidate, user),
+                    Err(PageError::KernelMemory)
+                );
+            }
+        }
+        assert_eq!(tables, 2);
+        // A data frame is fine
+        table.map_page(0x8000, frame, user).unwrap();
+    }
+
+    #[test]
+    fn freed_tables_lose_their_mark() {
+        let arena = Arena::new(4);
+        let table = PageTable::new(&arena).unwrap();
+        let root = table.root();
+        table.destroy();
+        assert!(!arena.refs.is_table(root));
+        assert_eq!(arena.refs.get(root), 0);
+    }
+}
@@ -0,0 +1,200 @@
+//! # Panic codes
+//!
+//! What kind of failure a panic was, for scripts watching the kernel, and
+//! the single line in a fixed format the panic handler ends its report
+//! with,
+//!
+//! ```text
+//! ##PANIC code=Corruption subsystem=page hart=0 at=src/page.rs:150##
+//! ```
+//!
+//! which doesn't change when the human readable message above it does.
+//! Under `panic=exit` QEMU also exits with a status that says which code
+//! it was, instead of 1 for every panic.
+
+use core::fmt;
+
+/// How memory was being accessed when it faulted
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// What kind of failure a panic was
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PanicCode {
+    /// Memory ran out where the kernel can't do without it
+    OutOfMemory,
+    /// An access to `addr` faulted with nowhere to recover
+    PageFault { addr: usize, access: Access },
+    /// A check on the kernel's own logic failed
+    AssertionFailed,
+    /// The hardware reported an error, with its `mcause`
+    HardwareFault { cause: usize },
+    /// Something waited on a lock or event that was never going to come
+    Deadlock,
+    /// `subsystem`'s data structures were found damaged
+    Corruption { subsystem: &'static str },
+    /// A plain `panic!`
+    Explicit,
+}
+
+impl PanicCode {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::OutOfMemory => "OutOfMemory",
+            Self::PageFault { .. } => "PageFault",
+            Self::AssertionFailed => "AssertionFailed",
+            Self::HardwareFault { .. } => "HardwareFault",
+            Self::Deadlock => "Deadlock",
+            Self::Corruption { .. } => "Corruption",
+            Self::Explicit => "Explicit",
+        }
+    }
+
+    /// Status QEMU exits with for this code under `panic=exit`.
+    /// `Explicit` keeps the 1 every panic exits with otherwise.
+    pub const fn exit_status(&self) -> u16 {
+        match self {
+            Self::Explicit => 1,
+            Self::OutOfMemory => 2,
+            Self::PageFault { .. } => 3,
+            Self::AssertionFailed => 4,
+            Self::HardwareFault { .. } => 5,
+            Self::Deadlock => 6,
+            Self::Corruption { .. } => 7,
+        }
+    }
+}
+
+/// The code and its data as `key=value` pairs
+impl fmt::Display for PanicCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "code={}", self.name())?;
+        match self {
+            Self::PageFault { addr, access } => write!(f, " addr={:#x} access={:?}", addr, access),
+            Self::HardwareFault { cause } => write!(f, " cause={:#x}", cause),
+            Self::Corruption { subsystem } => write!(f, " subsystem={}", subsystem),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A panic as the footer reports it
+pub struct KernelPanic<'a> {
+    pub code: PanicCode,
+    pub hart: usize,
+    pub location: Option<&'a core::panic::Location<'a>>,
+    /// Whether to exit with the code's own status, as `panic=exit` asks
+    pub exit_codes: bool,
+}
+
+impl KernelPanic<'_> {
+    /// Status QEMU should exit with
+    pub fn exit_status(&self) -> u16 {
+        if self.exit_codes {
+            self.code.exit_status()
+        } else {
+            PanicCode::Explicit.exit_status()
+        }
+    }
+}
+
+/// The footer line, without the line ending
+impl fmt::Display for KernelPanic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "##PANIC {} hart={}", self.code, self.hart)?;
+        if let Some(location) = self.location {
+            write!(f, " at={}:{}", location.file(), location.line())?;
+        }
+        write!(f, "##")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::format;
+
+    use super::*;
+
+    #[test]
+    fn codes_carry_their_data_as_key_value_pairs() {
+        let fault = PanicCode::PageFault {
+            addr: 0x8020_1000,
+            access: Access::Write,
+        };
+        assert_eq!(
+            format!("{}", fault),
+            "code=PageFault addr=0x80201000 access=Write"
+        );
+        assert_eq!(
+            format!("{}", PanicCode::HardwareFault { cause: 5 }),
+            "code=HardwareFault cause=0x5"
+        );
+        assert_eq!(
+            format!("{}", PanicCode::Corruption { subsystem: "list" }),
+            "code=Corruption subsystem=list"
+        );
+        assert_eq!(format!("{}", PanicCode::Deadlock), "code=Deadlock");
+    }
+
+    #[test]
+    fn footer_has_a_fixed_format() {
+        let location = core::panic::Location::caller();
+        let report = KernelPanic {
+            code: PanicCode::Corruption { subsystem: "page" },
+            hart: 1,
+            location: Some(location),
+            exit_codes: false,
+        };
+        assert_eq!(
+            format!("{}", report),
+            format!(
+                "##PANIC code=Corruption subsystem=page hart=1 at={}:{}##",
+                location.file(),
+                location.line()
+            )
+        );
+
+        let report = KernelPanic {
+            location: None,
+            ..report
+        };
+        assert_eq!(
+            format!("{}", report),
+            "##PANIC code=Corruption subsystem=page hart=1##"
+        );
+    }
+
+    #[test]
+    fn exit_status_says_which_code_only_when_asked() {
+        let codes = [
+            PanicCode::Explicit,
+            PanicCode::OutOfMemory,
+            PanicCode::PageFault {
+                addr: 0,
+                access: Access::Read,
+            },
+            PanicCode::AssertionFailed,
+            PanicCode::HardwareFault { cause: 0 },
+            PanicCode::Deadlock,
+            PanicCode::Corruption { subsystem: "" },
+        ];
+        for (status, code) in (1..).zip(codes) {
+            let report = KernelPanic {
+                code,
+                hart: 0,
+                location: None,
+                exit_codes: true,
+            };
+            assert_eq!(report.exit_status(), status);
+            let report = KernelPanic {
+                exit_codes: false,
+                ..report
+            };
+            assert_eq!(report.exit_status(), 1);
+        }
+    }
+}
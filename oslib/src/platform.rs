@@ -0,0 +1,87 @@
+//! # Platform detection
+//!
+//! Telling from the device tree whether we run under QEMU, where
+//! QEMU-only devices are safe to use, or on something else.
+
+use crate::fdt::Fdt;
+
+/// What the kernel is running on, as far as the device tree tells
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Environment {
+    /// QEMU, where QEMU-only devices like `sifive,test0` are safe to use
+    Qemu,
+    /// Real hardware, or something we don't recognise
+    Unknown,
+}
+
+/// Work out whether we are running under QEMU. The virt machine says so
+/// in its root `compatible`; sifive_u claims to be a HiFive Unleashed, but
+/// gives itself away with the `sifive,test0` device real boards lack.
+pub fn detect(fdt: Option<&Fdt>) -> Environment {
+    let Some(fdt) = fdt else {
+        return Environment::Unknown;
+    };
+
+    let qemu_root = fdt.root().is_some_and(|root| {
+        root.compatible()
+            .any(|c| c == "riscv-virtio" || c.starts_with("qemu,"))
+    });
+    let test_device = fdt.nodes().any(|node| node.is_compatible("sifive,test0"));
+
+    if qemu_root || test_device {
+        Environment::Qemu
+    } else {
+        Environment::Unknown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fdt::tests::Builder;
+
+    #[test]
+    fn virt_is_qemu_by_its_root_compatible() {
+        let fdt = Builder::new()
+            .begin("")
+            .strs("compatible", &["riscv-virtio"])
+            .end()
+            .build();
+        assert_eq!(detect(Some(&fdt)), Environment::Qemu);
+
+        let fdt = Builder::new()
+            .begin("")
+            .strs("compatible", &["qemu,spike", "riscv-spike"])
+            .end()
+            .build();
+        assert_eq!(detect(Some(&fdt)), Environment::Qemu);
+    }
+
+    #[test]
+    fn sifive_u_is_qemu_by_its_test_device() {
+        let fdt = Builder::new()
+            .begin("")
+            .strs("compatible", &["sifive,hifive-unleashed-a00"])
+            .begin("soc")
+            .begin("test@100000")
+            .strs("compatible", &["sifive,test1", "sifive,test0"])
+            .end()
+            .end()
+            .end()
+            .build();
+        assert_eq!(detect(Some(&fdt)), Environment::Qemu);
+    }
+
+    #[test]
+    fn real_boards_are_unknown() {
+        let fdt = Builder::new()
+            .begin("")
+            .strs("compatible", &["sifive,hifive-unleashed-a00"])
+            .begin("soc")
+            .end()
+            .end()
+            .build();
+        assert_eq!(detect(Some(&fdt)), Environment::Unknown);
+        assert_eq!(detect(None), Environment::Unknown);
+    }
+}
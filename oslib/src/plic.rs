@@ -0,0 +1,256 @@
+//! # Platform-Level Interrupt Controller
+//!
+//! The PLIC's registers for one context, driven through [`Registers`] so
+//! they can be tested against a fake controller, and the per-IRQ counters
+//! [`dispatch`] keeps for working out why an interrupt never arrives.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::mmio::Registers;
+
+/// Interrupt sources we keep state for. Both virt and sifive_u have fewer.
+pub const MAX_IRQS: usize = 64;
+
+/// Contexts covered by the register window the kernel claims
+const CONTEXTS: usize = 2;
+
+const PRIORITY: usize = 0x00_0000;
+const PENDING: usize = 0x00_1000;
+const ENABLE: usize = 0x00_2000;
+const ENABLE_STRIDE: usize = 0x80;
+const THRESHOLD: usize = 0x20_0000;
+const CLAIM: usize = 0x20_0004;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// Size of the register window up to the last context we use
+pub const MMIO_SIZE: usize = THRESHOLD + CONTEXTS * CONTEXT_STRIDE;
+
+pub struct Plic<R: Registers> {
+    regs: R,
+    context: usize,
+}
+
+impl<R: Registers> Plic<R> {
+    pub fn new(regs: R, context: usize) -> Self {
+        Self { regs, context }
+    }
+
+    /// Set the priority of `irq`. Priority 0 never interrupts.
+    pub fn set_priority(&self, irq: u32, priority: u32) {
+        let _ = self.regs.write32(PRIORITY + 4 * irq as usize, priority);
+    }
+
+    pub fn enable(&self, irq: u32) {
+        self.set_enabled(irq, true);
+    }
+
+    pub fn disable(&self, irq: u32) {
+        self.set_enabled(irq, false);
+    }
+
+    fn set_enabled(&self, irq: u32, on: bool) {
+        let offset = ENABLE + self.context * ENABLE_STRIDE + 4 * (irq as usize / 32);
+        let bit = 1 << (irq % 32);
+        let enabled = self.regs.read32(offset).unwrap_or(0);
+        let enabled = if on { enabled | bit } else { enabled & !bit };
+        let _ = self.regs.write32(offset, enabled);
+    }
+
+    /// Only IRQs with a priority above `threshold` interrupt this context
+    pub fn set_threshold(&self, threshold: u32) {
+        let _ = self
+            .regs
+            .write32(THRESHOLD + self.context * CONTEXT_STRIDE, threshold);
+    }
+
+    /// Take the highest priority pending IRQ, if any
+    pub fn claim(&self) -> Option<u32> {
+        match self.regs.read32(CLAIM + self.context * CONTEXT_STRIDE) {
+            Ok(0) | Err(_) => None,
+            Ok(irq) => Some(irq),
+        }
+    }
+
+    /// Tell the PLIC we are done with `irq`, so it can be raised again
+    pub fn complete(&self, irq: u32) {
+        let _ = self
+            .regs
+            .write32(CLAIM + self.context * CONTEXT_STRIDE, irq);
+    }
+
+    /// Pending bits of the first [`MAX_IRQS`] sources
+    pub fn pending(&self) -> u64 {
+        let low = self.regs.read32(PENDING).unwrap_or(0);
+        let high = self.regs.read32(PENDING + 4).unwrap_or(0);
+        (u64::from(high) << 32) | u64::from(low)
+    }
+}
+
+/// Times each IRQ has been claimed, in a fixed array indexed by IRQ.
+/// Sources past [`MAX_IRQS`] aren't counted.
+pub struct IrqCounters {
+    counts: [AtomicUsize; MAX_IRQS],
+}
+
+impl IrqCounters {
+    pub const fn new() -> Self {
+        Self {
+            counts: [const { AtomicUsize::new(0) }; MAX_IRQS],
+        }
+    }
+
+    /// Count one claim of `irq`
+    pub fn record(&self, irq: u32) {
+        if let Some(count) = self.counts.get(irq as usize) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The counts as they are now
+    pub fn counts(&self) -> [usize; MAX_IRQS] {
+        core::array::from_fn(|irq| self.counts[irq].load(Ordering::Relaxed))
+    }
+}
+
+impl Default for IrqCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Claim every pending IRQ from `plic`, count it in `counters`, hand it to
+/// `handler` and complete it
+pub fn dispatch<R: Registers>(
+    plic: &Plic<R>,
+    counters: &IrqCounters,
+    mut handler: impl FnMut(u32),
+) {
+    while let Some(irq) = plic.claim() {
+        counters.record(irq);
+        handler(irq);
+        plic.complete(irq);
+    }
+}
+
+/// A snapshot of the interrupt counters
+pub struct IrqStats {
+    /// Times each IRQ has been dispatched
+    pub counts: [usize; MAX_IRQS],
+    /// Which IRQs the PLIC has pending right now
+    pub pending: u64,
+    /// Input the console UART dropped because it wasn't read in time
+    pub rx_overruns: usize,
+}
+
+impl IrqStats {
+    pub fn is_pending(&self, irq: u32) -> bool {
+        irq < MAX_IRQS as u32 && self.pending & (1 << irq) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::mmio::{check_access, MmioError};
+
+    /// A PLIC with `raised` waiting to be claimed, highest priority first.
+    /// Claimed IRQs stop being pending; completions are recorded.
+    #[derive(Default)]
+    struct FakePlic {
+        raised: RefCell<Vec<u32>>,
+        completed: RefCell<Vec<u32>>,
+    }
+
+    impl FakePlic {
+        fn raising(irqs: &[u32]) -> Self {
+            Self {
+                raised: RefCell::new(irqs.to_vec()),
+                completed: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn pending_bits(&self, word: usize) -> u32 {
+            self.raised
+                .borrow()
+                .iter()
+                .filter(|&&irq| irq as usize / 32 == word)
+                .fold(0, |bits, irq| bits | 1 << (irq % 32))
+        }
+    }
+
+    impl Registers for FakePlic {
+        fn read32(&self, offset: usize) -> Result<u32, MmioError> {
+            check_access(MMIO_SIZE, offset, 4)?;
+            Ok(match offset {
+                PENDING => self.pending_bits(0),
+                o if o == PENDING + 4 => self.pending_bits(1),
+                CLAIM => {
+                    let mut raised = self.raised.borrow_mut();
+                    if raised.is_empty() {
+                        0
+                    } else {
+                        raised.remove(0)
+                    }
+                }
+                _ => 0,
+            })
+        }
+
+        fn write32(&self, offset: usize, value: u32) -> Result<(), MmioError> {
+            check_access(MMIO_SIZE, offset, 4)?;
+            if offset == CLAIM {
+                self.completed.borrow_mut().push(value);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn dispatch_counts_every_claimed_irq() {
+        let counters = IrqCounters::new();
+        let plic = Plic::new(FakePlic::raising(&[10, 1, 10]), 0);
+        let mut handled = Vec::new();
+        dispatch(&plic, &counters, |irq| handled.push(irq));
+        assert_eq!(handled, [10, 1, 10]);
+        assert_eq!(*plic.regs.completed.borrow(), [10, 1, 10]);
+
+        plic.regs.raised.borrow_mut().push(1);
+        dispatch(&plic, &counters, |_| {});
+
+        let counts = counters.counts();
+        assert_eq!(counts[1], 2);
+        assert_eq!(counts[10], 2);
+        assert_eq!(counts.iter().sum::<usize>(), 4);
+    }
+
+    #[test]
+    fn irqs_past_the_table_are_handled_but_not_counted() {
+        let counters = IrqCounters::new();
+        let plic = Plic::new(FakePlic::raising(&[MAX_IRQS as u32]), 0);
+        let mut handled = 0;
+        dispatch(&plic, &counters, |_| handled += 1);
+        assert_eq!(handled, 1);
+        assert_eq!(counters.counts().iter().sum::<usize>(), 0);
+    }
+
+    #[test]
+    fn stats_tell_pending_from_delivered() {
+        let counters = IrqCounters::new();
+        counters.record(3);
+        // 7 and 40 are raised but nothing has claimed them
+        let plic = Plic::new(FakePlic::raising(&[7, 40]), 0);
+        let stats = IrqStats {
+            counts: counters.counts(),
+            pending: plic.pending(),
+            rx_overruns: 0,
+        };
+
+        assert!(stats.is_pending(7) && stats.is_pending(40));
+        assert!(!stats.is_pending(3));
+        assert_eq!(stats.counts[3], 1);
+        assert!(!stats.is_pending(MAX_IRQS as u32));
+    }
+}
@@ -0,0 +1,286 @@
+//! # Reserved memory
+//!
+//! Memory the device tree says the kernel mustn't hand out. The header's
+//! memory reservation block (`/memreserve/` in dts) lists ranges the
+//! firmware keeps for itself. `/reserved-memory` lists carveouts, like a
+//! framebuffer a display engine scans out of or a firmware's working
+//! memory. A child either names its range with `reg`, or asks for `size`
+//! bytes, optionally aligned to `alignment`, and leaves it to the kernel
+//! to pick where. All of them are kept out of the frame allocator;
+//! devices find their carveouts through the phandles in their
+//! `memory-region` property.
+
+use core::ops::Range;
+
+use crate::align::{align_down, align_up};
+use crate::fdt::{Fdt, Node};
+use crate::page::PAGE_SIZE;
+
+/// Carveouts we keep track of
+const MAX_CARVEOUTS: usize = 8;
+
+/// A region of RAM set aside for one user
+#[derive(Clone, Debug)]
+pub struct Carveout {
+    /// Name of the `/reserved-memory` child
+    pub name: &'static str,
+    pub phandle: Option<u32>,
+    pub range: Range<usize>,
+    /// The region must not be mapped unless its user asks for it
+    pub no_map: bool,
+}
+
+impl Carveout {
+    fn new(node: &Node, range: Range<usize>) -> Self {
+        Self {
+            name: node.name(),
+            phandle: node.phandle(),
+            range,
+            no_map: node.property("no-map").is_some(),
+        }
+    }
+}
+
+/// Why a `/reserved-memory` child got no carveout
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Skipped {
+    /// We already track [`MAX_CARVEOUTS`]
+    Full,
+    /// It has neither `reg` nor `size`
+    NoSize,
+    /// Nothing left of the frames fits `size` bytes aligned to `alignment`
+    NoRoom { size: usize, alignment: usize },
+}
+
+/// Every carveout, in the order the device tree lists them
+pub struct Carveouts {
+    entries: [Option<Carveout>; MAX_CARVEOUTS],
+    len: usize,
+}
+
+impl Carveouts {
+    pub const fn new() -> Self {
+        Self {
+            entries: [const { None }; MAX_CARVEOUTS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, carveout: Carveout) -> Result<(), Skipped> {
+        let slot = self.entries.get_mut(self.len).ok_or(Skipped::Full)?;
+        *slot = Some(carveout);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Carveout> {
+        self.entries.iter().flatten()
+    }
+
+    /// The carveout the device tree node `node` refers to
+    pub fn for_node(&self, node: &Node) -> Option<&Carveout> {
+        let phandle = node.phandle()?;
+        self.iter()
+            .find(|carveout| carveout.phandle == Some(phandle))
+    }
+
+    /// The carveouts `device`'s `memory-region` property refers to
+    pub fn for_device<'a>(&'a self, device: &Node) -> impl Iterator<Item = &'a Carveout> {
+        device
+            .memory_regions()
+            .filter_map(move |region| self.for_node(&region))
+    }
+}
+
+impl Default for Carveouts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What is left of `frames` once `range` is out of it. A range inside
+/// cuts it short below, or pushes its start past it if that loses less.
+fn exclude(frames: Range<usize>, range: Range<usize>) -> Range<usize> {
+    if range.start >= frames.end || frames.start >= range.end {
+        return frames;
+    }
+    let below = align_down(range.start, PAGE_SIZE).saturating_sub(frames.start);
+    let above = frames.end.saturating_sub(range.end);
+    if below >= above {
+        frames.start..align_down(range.start, PAGE_SIZE).max(frames.start)
+    } else {
+        let start = align_up(range.end, PAGE_SIZE).unwrap_or(frames.end);
+        start.min(frames.end)..frames.end
+    }
+}
+
+/// Take the reserved memory `fdt` describes out of `frames`, the range
+/// the frame allocator is about to hand out, returning the carveouts and
+/// what is left of the range. Children that get no carveout are passed to
+/// `skipped`.
+///
+/// The reservation block and static carveouts go first, so dynamic
+/// carveouts can't land on top of them; those are then taken from the top
+/// of what is left.
+pub fn carve(
+    fdt: &Fdt,
+    mut frames: Range<usize>,
+    mut skipped: impl FnMut(&Node, Skipped),
+) -> (Carveouts, Range<usize>) {
+    for (base, size) in fdt.reservations() {
+        frames = exclude(frames, base..base.saturating_add(size));
+    }
+
+    let mut carveouts = Carveouts::new();
+    let Some(parent) = fdt.find_node("/reserved-memory") else {
+        return (carveouts, frames);
+    };
+
+    for node in parent.children() {
+        let Some((base, size)) = node.reg(&parent).next() else {
+            continue;
+        };
+        let range = base..base.saturating_add(size);
+        frames = exclude(frames, range.clone());
+        if let Err(reason) = carveouts.push(Carveout::new(&node, range)) {
+            skipped(&node, reason);
+        }
+    }
+
+    for node in parent.children() {
+        if node.property("reg").is_some() {
+            continue;
+        }
+        let Some(size) = node.property_cells("size") else {
+            skipped(&node, Skipped::NoSize);
+            continue;
+        };
+        let alignment = node
+            .property_cells("alignment")
+            .unwrap_or(PAGE_SIZE)
+            .max(PAGE_SIZE);
+
+        let start = frames
+            .end
+            .checked_sub(size)
+            .filter(|_| alignment.is_power_of_two())
+            .map(|end| align_down(end, alignment))
+            .filter(|&start| start >= frames.start);
+        let Some(start) = start else {
+            skipped(&node, Skipped::NoRoom { size, alignment });
+            continue;
+        };
+        match carveouts.push(Carveout::new(&node, start..start + size)) {
+            Ok(()) => frames.end = start,
+            Err(reason) => skipped(&node, reason),
+        }
+    }
+
+    (carveouts, frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::fdt::tests::Builder;
+
+    /// RAM from 2GiB, the kernel image in its first 2MiB
+    const FRAMES: Range<usize> = 0x8020_0000..0x8800_0000;
+
+    fn carve_all(fdt: &Fdt) -> (Carveouts, Range<usize>, Vec<(&'static str, Skipped)>) {
+        let mut skips = Vec::new();
+        let (carveouts, frames) = carve(fdt, FRAMES, |node, reason| {
+            skips.push((node.name(), reason))
+        });
+        (carveouts, frames, skips)
+    }
+
+    #[test]
+    fn reservation_block_entries_are_kept_out() {
+        let fdt = Builder::new()
+            .reserve(0x87f0_0000, 0x10_0000)
+            .reserve(0x8030_0000, 0x800)
+            .begin("")
+            .end()
+            .build();
+        let (carveouts, frames, skips) = carve_all(&fdt);
+        // The top one cuts the end short; the one near the bottom loses
+        // less by pushing the start up
+        assert_eq!(frames, 0x8030_1000..0x87f0_0000);
+        assert_eq!(carveouts.iter().count(), 0);
+        assert!(skips.is_empty());
+    }
+
+    #[test]
+    fn static_and_dynamic_carveouts_are_taken_out() {
+        let fdt = Builder::new()
+            .begin("")
+            .begin("reserved-memory")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .begin("framebuffer@87000000")
+            .cells("reg", &[0, 0x8700_0000, 0, 0x100_0000])
+            .prop("no-map", &[])
+            .cells("phandle", &[1])
+            .end()
+            .begin("firmware")
+            .cells("size", &[0, 0x4_0000])
+            .cells("alignment", &[0, 0x10_0000])
+            .cells("phandle", &[2])
+            .end()
+            .begin("broken")
+            .end()
+            .end()
+            .begin("display")
+            .cells("memory-region", &[1, 2])
+            .end()
+            .end()
+            .build();
+        let (carveouts, frames, skips) = carve_all(&fdt);
+
+        let ranges: Vec<_> = carveouts
+            .iter()
+            .map(|carveout| (carveout.name, carveout.range.clone(), carveout.no_map))
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                ("framebuffer@87000000", 0x8700_0000..0x8800_0000, true),
+                ("firmware", 0x86f0_0000..0x86f4_0000, false),
+            ]
+        );
+        assert_eq!(frames, FRAMES.start..0x86f0_0000);
+        assert_eq!(skips, [("broken", Skipped::NoSize)]);
+
+        let display = fdt.find_node("/display").unwrap();
+        assert_eq!(carveouts.for_device(&display).count(), 2);
+    }
+
+    #[test]
+    fn dynamic_carveouts_that_dont_fit_are_skipped() {
+        let fdt = Builder::new()
+            .begin("")
+            .begin("reserved-memory")
+            .begin("huge")
+            .cells("size", &[0x2000_0000])
+            .end()
+            .end()
+            .end()
+            .build();
+        let (carveouts, frames, skips) = carve_all(&fdt);
+        assert_eq!(carveouts.iter().count(), 0);
+        assert_eq!(frames, FRAMES);
+        assert_eq!(
+            skips,
+            [(
+                "huge",
+                Skipped::NoRoom {
+                    size: 0x2000_0000,
+                    alignment: PAGE_SIZE
+                }
+            )]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use crate::gpio::{Direction, Edge, GpioController, GpioError};
+use crate::mmio::Registers;
+
+const INPUT_VAL: usize = 0x00;
+const INPUT_EN: usize = 0x04;
+const OUTPUT_EN: usize = 0x08;
+const OUTPUT_VAL: usize = 0x0c;
+const RISE_IE: usize = 0x18;
+/// Latched rising edges. Writing 1 to a bit clears it.
+const RISE_IP: usize = 0x1c;
+const FALL_IE: usize = 0x20;
+/// Latched falling edges. Writing 1 to a bit clears it.
+const FALL_IP: usize = 0x24;
+
+/// # SiFive GPIO
+///
+/// The `sifive,gpio0` controller of the HiFive Unleashed, which QEMU's
+/// sifive_u models. Each pin has its own bit in every register, and its
+/// own interrupt line into the PLIC.
+pub struct SifiveGpio<R: Registers> {
+    regs: R,
+    pins: u32,
+    /// The device tree's `interrupts`, one PLIC source per pin
+    interrupts: &'static [u8],
+}
+
+impl<R: Registers> SifiveGpio<R> {
+    /// A controller with `pins` pins at `regs`, raising the interrupts the
+    /// device tree's `interrupts` lists
+    pub fn new(regs: R, pins: u32, interrupts: &'static [u8]) -> Self {
+        Self {
+            regs,
+            pins,
+            interrupts,
+        }
+    }
+
+    fn bit(&self, pin: u32) -> Result<u32, GpioError> {
+        if pin < self.pins.min(32) {
+            Ok(1 << pin)
+        } else {
+            Err(GpioError::NoSuchPin(pin))
+        }
+    }
+
+    /// Set or clear `pin`'s bit in the register at `offset`
+    fn update(&self, offset: usize, pin: u32, on: bool) -> Result<(), GpioError> {
+        let bit = self.bit(pin)?;
+        let value = self.regs.read32(offset)?;
+        let value = if on { value | bit } else { value & !bit };
+        Ok(self.regs.write32(offset, value)?)
+    }
+
+    fn irq(&self, pin: u32) -> Option<u32> {
+        let cell = self
+            .interrupts
+            .get(pin as usize * 4..pin as usize * 4 + 4)?;
+        Some(u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+    }
+}
+
+impl<R: Registers> GpioController for SifiveGpio<R> {
+    fn pins(&self) -> u32 {
+        self.pins
+    }
+
+    fn set_direction(&self, pin: u32, direction: Direction) -> Result<(), GpioError> {
+        let output = direction == Direction::Output;
+        self.update(INPUT_EN, pin, !output)?;
+        self.update(OUTPUT_EN, pin, output)
+    }
+
+    fn set(&self, pin: u32, high: bool) -> Result<(), GpioError> {
+        self.update(OUTPUT_VAL, pin, high)
+    }
+
+    fn get(&self, pin: u32) -> Result<bool, GpioError> {
+        let bit = self.bit(pin)?;
+        Ok(self.regs.read32(INPUT_VAL)? & bit != 0)
+    }
+
+    fn enable_interrupt(&self, pin: u32, edge: Edge) -> Result<u32, GpioError> {
+        let irq = self.irq(pin).ok_or(GpioError::NoInterrupt(pin))?;
+        // Don't fire on an edge that was latched before we were listening
+        self.acknowledge(pin)?;
+        self.update(RISE_IE, pin, edge != Edge::Falling)?;
+        self.update(FALL_IE, pin, edge != Edge::Rising)?;
+        Ok(irq)
+    }
+
+    fn disable_interrupt(&self, pin: u32) -> Result<(), GpioError> {
+        self.update(RISE_IE, pin, false)?;
+        self.update(FALL_IE, pin, false)
+    }
+
+    fn acknowledge(&self, pin: u32) -> Result<(), GpioError> {
+        let bit = self.bit(pin)?;
+        self.regs.write32(RISE_IP, bit)?;
+        Ok(self.regs.write32(FALL_IP, bit)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::mmio::{check_access, MmioError};
+
+    /// Registers that remember what was written, and every write in order
+    #[derive(Default)]
+    struct FakeRegisters {
+        values: RefCell<[u32; 16]>,
+        writes: RefCell<Vec<(usize, u32)>>,
+    }
+
+    impl FakeRegisters {
+        fn value(&self, offset: usize) -> u32 {
+            self.values.borrow()[offset / 4]
+        }
+    }
+
+    impl Registers for FakeRegisters {
+        fn read32(&self, offset: usize) -> Result<u32, MmioError> {
+            check_access(64, offset, 4)?;
+            Ok(self.values.borrow()[offset / 4])
+        }
+
+        fn write32(&self, offset: usize, value: u32) -> Result<(), MmioError> {
+            check_access(64, offset, 4)?;
+            self.values.borrow_mut()[offset / 4] = value;
+            self.writes.borrow_mut().push((offset, value));
+            Ok(())
+        }
+    }
+
+    /// Interrupt cells as the device tree has them, big endian
+    const INTERRUPTS: &[u8] = &[0, 0, 0, 7, 0, 0, 0, 8, 0, 0, 1, 0];
+
+    fn gpio(pins: u32) -> SifiveGpio<FakeRegisters> {
+        SifiveGpio::new(FakeRegisters::default(), pins, INTERRUPTS)
+    }
+
+    #[test]
+    fn bit_stops_at_the_pin_count_and_the_register_width() {
+        assert_eq!(gpio(16).bit(0), Ok(1));
+        assert_eq!(gpio(16).bit(15), Ok(1 << 15));
+        assert_eq!(gpio(16).bit(16), Err(GpioError::NoSuchPin(16)));
+        assert_eq!(gpio(64).bit(31), Ok(1 << 31));
+        assert_eq!(gpio(64).bit(32), Err(GpioError::NoSuchPin(32)));
+    }
+
+    #[test]
+    fn irq_decodes_big_endian_cells() {
+        let gpio = gpio(16);
+        assert_eq!(gpio.irq(0), Some(7));
+        assert_eq!(gpio.irq(1), Some(8));
+        assert_eq!(gpio.irq(2), Some(256));
+        assert_eq!(gpio.irq(3), None);
+    }
+
+    #[test]
+    fn update_leaves_other_pins_alone() {
+        let gpio = gpio(16);
+        gpio.regs.values.borrow_mut()[OUTPUT_VAL / 4] = 0b1010;
+
+        gpio.set(0, true).unwrap();
+        assert_eq!(gpio.regs.value(OUTPUT_VAL), 0b1011);
+        gpio.set(3, false).unwrap();
+        assert_eq!(gpio.regs.value(OUTPUT_VAL), 0b0011);
+        assert_eq!(gpio.set(16, true), Err(GpioError::NoSuchPin(16)));
+        assert_eq!(gpio.regs.value(OUTPUT_VAL), 0b0011);
+    }
+
+    #[test]
+    fn set_direction_switches_input_and_output_enables() {
+        let gpio = gpio(16);
+        gpio.set_direction(2, Direction::Output).unwrap();
+        assert_eq!(gpio.regs.value(INPUT_EN), 0);
+        assert_eq!(gpio.regs.value(OUTPUT_EN), 1 << 2);
+
+        gpio.set_direction(2, Direction::Input).unwrap();
+        assert_eq!(gpio.regs.value(INPUT_EN), 1 << 2);
+        assert_eq!(gpio.regs.value(OUTPUT_EN), 0);
+    }
+
+    #[test]
+    fn enable_interrupt_clears_latched_edges_first() {
+        let gpio = gpio(16);
+        assert_eq!(gpio.enable_interrupt(1, Edge::Rising), Ok(8));
+        assert_eq!(
+            gpio.regs.writes.borrow()[..2],
+            [(RISE_IP, 1 << 1), (FALL_IP, 1 << 1)]
+        );
+        assert_eq!(gpio.regs.value(RISE_IE), 1 << 1);
+        assert_eq!(gpio.regs.value(FALL_IE), 0);
+
+        assert_eq!(
+            gpio.enable_interrupt(3, Edge::Both),
+            Err(GpioError::NoInterrupt(3))
+        );
+    }
+}
@@ -0,0 +1,179 @@
+use crate::i2c::{I2cBus, I2cError, TRANSFER_TIMEOUT_US};
+use crate::mmio::Registers;
+use crate::time::Clock;
+
+// The registers are a byte wide but spaced four bytes apart
+const PRESCALE_LOW: usize = 0x00;
+const PRESCALE_HIGH: usize = 0x04;
+const CONTROL: usize = 0x08;
+/// Transmit on write, receive on read
+const DATA: usize = 0x0c;
+/// Command on write, status on read
+const COMMAND: usize = 0x10;
+
+const CONTROL_ENABLE: u8 = 1 << 7;
+
+const CMD_START: u8 = 1 << 7;
+const CMD_STOP: u8 = 1 << 6;
+const CMD_READ: u8 = 1 << 5;
+const CMD_WRITE: u8 = 1 << 4;
+/// Answer a received byte with a NACK, ending a read
+const CMD_NACK: u8 = 1 << 3;
+
+/// The addressed device didn't acknowledge
+const STATUS_NO_ACK: u8 = 1 << 7;
+const STATUS_ARBITRATION_LOST: u8 = 1 << 5;
+const STATUS_TRANSFER_IN_PROGRESS: u8 = 1 << 1;
+
+/// The prescaler value that clocks the bus at `bus_hz` from an `input_hz`
+/// peripheral clock. The core runs at five times the bus clock, divided
+/// down from the input by the prescaler plus one. A bus slower than the
+/// prescaler can reach gets the slowest clock it can; one faster than the
+/// input allows is an error.
+pub fn prescale(input_hz: u32, bus_hz: u32) -> Result<u16, I2cError> {
+    let divisor = 5 * u64::from(bus_hz);
+    let prescale = u64::from(input_hz)
+        .checked_div(divisor)
+        .and_then(|ratio| ratio.checked_sub(1))
+        .ok_or(I2cError::BadFrequency(bus_hz))?;
+    Ok(u16::try_from(prescale).unwrap_or(u16::MAX))
+}
+
+/// # SiFive I2C
+///
+/// The OpenCores I2C master SiFive uses as `sifive,i2c0`, with its byte
+/// registers four bytes apart. Every byte on the bus is one command: the
+/// controller sends or receives it, optionally with a start before or a
+/// stop after, and reports whether it was acknowledged.
+pub struct SifiveI2c<R: Registers> {
+    regs: R,
+    /// What commands are timed against
+    clock: Clock<'static>,
+}
+
+impl<R: Registers> SifiveI2c<R> {
+    /// The controller at `regs`, still disabled
+    pub fn new(regs: R, clock: Clock<'static>) -> Self {
+        Self { regs, clock }
+    }
+
+    /// Clock the bus at `bus_hz` from an `input_hz` peripheral clock and
+    /// enable the controller
+    pub fn enable(&self, input_hz: u32, bus_hz: u32) -> Result<(), I2cError> {
+        let prescale = prescale(input_hz, bus_hz)?;
+
+        // The prescaler can only be changed while the core is disabled
+        self.write(CONTROL, 0)?;
+        self.write(PRESCALE_LOW, prescale as u8)?;
+        self.write(PRESCALE_HIGH, (prescale >> 8) as u8)?;
+        self.write(CONTROL, CONTROL_ENABLE)
+    }
+
+    fn write(&self, offset: usize, value: u8) -> Result<(), I2cError> {
+        Ok(self.regs.write32(offset, u32::from(value))?)
+    }
+
+    fn read(&self, offset: usize) -> Result<u8, I2cError> {
+        Ok(self.regs.read32(offset)? as u8)
+    }
+
+    /// Issue `command` and wait for it to finish, checking for a lost
+    /// arbitration and, if `check_ack`, for a missing acknowledge
+    fn command(&self, command: u8, check_ack: bool) -> Result<(), I2cError> {
+        self.write(COMMAND, command)?;
+
+        let deadline = self.clock.deadline_us(TRANSFER_TIMEOUT_US);
+        let status = loop {
+            let status = self.read(COMMAND)?;
+            if status & STATUS_TRANSFER_IN_PROGRESS == 0 {
+                break status;
+            }
+            if self.clock.has_passed(&deadline) {
+                return Err(I2cError::Timeout);
+            }
+        };
+
+        if status & STATUS_ARBITRATION_LOST != 0 {
+            return Err(I2cError::ArbitrationLost);
+        }
+        if check_ack && status & STATUS_NO_ACK != 0 {
+            return Err(I2cError::Nack);
+        }
+        Ok(())
+    }
+
+    /// Send `byte` with `command`
+    fn send(&self, byte: u8, command: u8) -> Result<(), I2cError> {
+        self.write(DATA, byte)?;
+        self.command(command | CMD_WRITE, true)
+    }
+
+    fn transfer_inner(&self, address: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError> {
+        if !write.is_empty() || read.is_empty() {
+            let stop = if read.is_empty() && write.is_empty() {
+                CMD_STOP
+            } else {
+                0
+            };
+            self.send(address << 1, CMD_START | stop)?;
+            for (i, &byte) in write.iter().enumerate() {
+                let last = i + 1 == write.len() && read.is_empty();
+                self.send(byte, if last { CMD_STOP } else { 0 })?;
+            }
+        }
+
+        if !read.is_empty() {
+            self.send((address << 1) | 1, CMD_START)?;
+            let len = read.len();
+            for (i, byte) in read.iter_mut().enumerate() {
+                let last = if i + 1 == len { CMD_NACK | CMD_STOP } else { 0 };
+                self.command(CMD_READ | last, false)?;
+                *byte = self.read(DATA)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Registers> I2cBus for SifiveI2c<R> {
+    fn transfer(&self, address: u8, write: &[u8], read: &mut [u8]) -> Result<(), I2cError> {
+        let result = self.transfer_inner(address, write, read);
+        if matches!(result, Err(I2cError::Nack | I2cError::Timeout)) {
+            // Let go of the bus for the next transfer
+            let _ = self.command(CMD_STOP, false);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prescale_divides_the_input_down_to_five_times_the_bus() {
+        // 500MHz / (5 * 100kHz) - 1
+        assert_eq!(prescale(500_000_000, 100_000), Ok(999));
+        assert_eq!(prescale(500_000_000, 400_000), Ok(249));
+        // As fast as it goes
+        assert_eq!(prescale(500_000_000, 100_000_000), Ok(0));
+    }
+
+    #[test]
+    fn prescale_refuses_a_bus_faster_than_the_input_allows() {
+        assert_eq!(
+            prescale(500_000_000, 200_000_000),
+            Err(I2cError::BadFrequency(200_000_000))
+        );
+        assert_eq!(
+            prescale(500_000_000, u32::MAX),
+            Err(I2cError::BadFrequency(u32::MAX))
+        );
+        assert_eq!(prescale(500_000_000, 0), Err(I2cError::BadFrequency(0)));
+    }
+
+    #[test]
+    fn prescale_clamps_a_bus_slower_than_it_can_reach() {
+        assert_eq!(prescale(500_000_000, 1), Ok(u16::MAX));
+    }
+}
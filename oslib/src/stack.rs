@@ -0,0 +1,76 @@
+//! # Kernel stack layout
+//!
+//! A kernel stack is a run of physically contiguous frames with the lowest
+//! one kept back as a guard. The stack is identity mapped, since the
+//! kernel runs in M-mode and uses physical addresses, and the guard is
+//! left out of the mapping so a stack that overflows faults there instead
+//! of writing over whatever lies below it.
+
+use crate::page::{Frames, PageError, PageFlags, PageTable, PAGE_SIZE};
+
+/// Where a stack is
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct StackInfo {
+    /// Initial stack pointer. The stack grows down from here.
+    pub top: usize,
+    /// Lowest usable address
+    pub bottom: usize,
+    /// The unmapped page just below `bottom`
+    pub guard: usize,
+}
+
+impl StackInfo {
+    /// A stack of `pages` pages in the frames from `base` on, the first of
+    /// which is its guard
+    pub const fn new(base: usize, pages: usize) -> Self {
+        Self {
+            top: base + (pages + 1) * PAGE_SIZE,
+            bottom: base + PAGE_SIZE,
+            guard: base,
+        }
+    }
+
+    /// Frames the stack takes, guard included
+    pub const fn frames(&self) -> usize {
+        (self.top - self.guard) / PAGE_SIZE
+    }
+
+    /// Map the stack where it is, leaving the guard page out
+    pub fn map<F: Frames>(&self, table: &mut PageTable<F>) -> Result<(), PageError> {
+        table.map_identity_range(
+            self.bottom,
+            self.top,
+            PageFlags::READ_WRITE | PageFlags::GLOBAL,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::tests::Arena;
+
+    #[test]
+    fn stack_sits_above_its_guard() {
+        let stack = StackInfo::new(0x8010_0000, 4);
+        assert_eq!(stack.guard, 0x8010_0000);
+        assert_eq!(stack.bottom, 0x8010_1000);
+        assert_eq!(stack.top, 0x8010_5000);
+        assert_eq!(stack.frames(), 5);
+    }
+
+    #[test]
+    fn mapped_stack_top_is_aligned_and_guard_unmapped() {
+        let arena = Arena::new(8);
+        let mut table = PageTable::new(&arena).unwrap();
+        let stack = StackInfo::new(0x8010_0000, 4);
+        stack.map(&mut table).unwrap();
+
+        assert!(stack.top.is_multiple_of(PAGE_SIZE));
+        assert_eq!(table.translate(stack.top - 8), Some(stack.top - 8));
+        assert_eq!(table.translate(stack.bottom), Some(stack.bottom));
+        assert_eq!(table.translate(stack.guard), None);
+        assert_eq!(table.translate(stack.bottom - 8), None);
+        assert_eq!(table.translate(stack.top), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! # Time sources
+//!
+//! What the kernel reads the time from, and deadlines measured against
+//! it. The kernel's source is the CLINT's `mtime`; tests use a
+//! [`MockTime`] that only moves when they move it.
+
+/// Something that counts ticks at the timebase frequency
+pub trait TimeSource: Sync {
+    /// Ticks since the source started counting
+    fn now_ticks(&self) -> u64;
+
+    /// Whether the source is counting. One that isn't reads a fixed value
+    /// forever.
+    fn is_running(&self) -> bool {
+        true
+    }
+}
+
+/// A point `ticks` ticks after the moment it was made
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Deadline {
+    start: u64,
+    ticks: u64,
+}
+
+impl Deadline {
+    /// `ticks` ticks from now on `source`
+    pub fn after(source: &dyn TimeSource, ticks: u64) -> Self {
+        Self {
+            start: source.now_ticks(),
+            ticks,
+        }
+    }
+
+    /// Whether `source` has reached the deadline. A source that isn't
+    /// running never would, so its deadlines count as passed rather than
+    /// leaving whoever waits on them spinning forever.
+    pub fn has_passed(&self, source: &dyn TimeSource) -> bool {
+        !source.is_running() || source.now_ticks().wrapping_sub(self.start) >= self.ticks
+    }
+}
+
+/// A time source and the frequency it ticks at: everything measuring time
+/// in microseconds needs
+#[derive(Copy, Clone)]
+pub struct Clock<'a> {
+    source: &'a dyn TimeSource,
+    /// Ticks per second
+    frequency: u64,
+}
+
+impl<'a> Clock<'a> {
+    pub const fn new(source: &'a dyn TimeSource, frequency: u64) -> Self {
+        Self { source, frequency }
+    }
+
+    pub fn source(&self) -> &'a dyn TimeSource {
+        self.source
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    /// Microseconds since the source started counting
+    pub fn uptime_us(&self) -> u64 {
+        ticks_to_us(self.source.now_ticks(), self.frequency)
+    }
+
+    /// A deadline `us` microseconds from now
+    pub fn deadline_us(&self, us: u64) -> Deadline {
+        Deadline::after(self.source, us_to_ticks(us, self.frequency))
+    }
+
+    /// Whether `deadline` has passed. Without a running source every
+    /// deadline has.
+    pub fn has_passed(&self, deadline: &Deadline) -> bool {
+        deadline.has_passed(self.source)
+    }
+
+    /// Busy wait until `us` microseconds have elapsed, or not at all if
+    /// the source isn't running to measure them
+    pub fn delay_us(&self, us: u64) {
+        let deadline = self.deadline_us(us);
+        while !self.has_passed(&deadline) {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// `us` microseconds in ticks of a `frequency` Hz timebase. Spans too
+/// long to count saturate rather than wrapping round to a short one.
+pub fn us_to_ticks(us: u64, frequency: u64) -> u64 {
+    let ticks = u128::from(us) * u128::from(frequency) / 1_000_000;
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// `ticks` ticks of a `frequency` Hz timebase in microseconds
+pub fn ticks_to_us(ticks: u64, frequency: u64) -> u64 {
+    let us = u128::from(ticks) * 1_000_000 / u128::from(frequency.max(1));
+    u64::try_from(us).unwrap_or(u64::MAX)
+}
+
+/// A clock that only moves when told to
+#[cfg(test)]
+pub struct MockTime {
+    ticks: core::sync::atomic::AtomicU64,
+    running: bool,
+}
+
+#[cfg(test)]
+impl MockTime {
+    /// A running clock reading `start`
+    pub const fn new(start: u64) -> Self {
+        Self {
+            ticks: core::sync::atomic::AtomicU64::new(start),
+            running: true,
+        }
+    }
+
+    /// A clock stuck at zero, like a timer that was never found
+    pub const fn stopped() -> Self {
+        Self {
+            running: false,
+            ..Self::new(0)
+        }
+    }
+
+    /// Move the clock on by `ticks`
+    pub fn advance(&self, ticks: u64) {
+        self.ticks
+            .fetch_add(ticks, core::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+impl TimeSource for MockTime {
+    fn now_ticks(&self) -> u64 {
+        self.ticks.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn is_running(&self) -> bool {
+        self.running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_passes_once_the_clock_gets_there() {
+        let clock = MockTime::new(1_000);
+        let deadline = Deadline::after(&clock, 100);
+        assert!(!deadline.has_passed(&clock));
+
+        clock.advance(99);
+        assert!(!deadline.has_passed(&clock));
+        clock.advance(1);
+        assert!(deadline.has_passed(&clock));
+        clock.advance(1_000);
+        assert!(deadline.has_passed(&clock));
+    }
+
+    #[test]
+    fn deadline_survives_the_counter_wrapping() {
+        let clock = MockTime::new(u64::MAX - 10);
+        let deadline = Deadline::after(&clock, 20);
+        clock.advance(15);
+        assert!(!deadline.has_passed(&clock));
+        clock.advance(5);
+        assert!(deadline.has_passed(&clock));
+    }
+
+    #[test]
+    fn deadline_on_a_stopped_clock_has_passed() {
+        let clock = MockTime::stopped();
+        assert!(Deadline::after(&clock, 1_000_000).has_passed(&clock));
+    }
+
+    #[test]
+    fn zero_tick_deadline_has_passed_already() {
+        let clock = MockTime::new(5);
+        assert!(Deadline::after(&clock, 0).has_passed(&clock));
+    }
+
+    #[test]
+    fn microseconds_convert_to_ticks_of_the_timebase() {
+        assert_eq!(us_to_ticks(1_000_000, 10_000_000), 10_000_000);
+        assert_eq!(us_to_ticks(1, 10_000_000), 10);
+        assert_eq!(us_to_ticks(1, 32_768), 0);
+        assert_eq!(ticks_to_us(10_000_000, 10_000_000), 1_000_000);
+    }
+
+    #[test]
+    fn long_timeouts_saturate_instead_of_wrapping() {
+        assert_eq!(us_to_ticks(u64::MAX / 2, 10_000_000), u64::MAX);
+        assert_eq!(us_to_ticks(u64::MAX, u64::MAX), u64::MAX);
+        // Still representable once divided back down
+        assert_eq!(us_to_ticks(u64::MAX / 20, 10_000_000), u64::MAX / 20 * 10);
+        assert_eq!(ticks_to_us(u64::MAX, 1), u64::MAX);
+        assert_eq!(ticks_to_us(5, 0), 5_000_000);
+    }
+
+    /// Moves one tick every time it is read, like a real counter would
+    /// between polls
+    struct Ticking(core::sync::atomic::AtomicU64);
+
+    impl TimeSource for Ticking {
+        fn now_ticks(&self) -> u64 {
+            self.0.fetch_add(1, core::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn clock_deadlines_expire_as_the_mock_advances() {
+        let source = MockTime::new(0);
+        let clock = Clock::new(&source, 10_000_000);
+        let deadline = clock.deadline_us(100);
+        assert!(!clock.has_passed(&deadline));
+
+        source.advance(999);
+        assert!(!clock.has_passed(&deadline));
+        source.advance(1);
+        assert!(clock.has_passed(&deadline));
+        assert_eq!(clock.uptime_us(), 100);
+    }
+
+    #[test]
+    fn clock_delay_returns_once_the_time_has_passed() {
+        let source = Ticking(core::sync::atomic::AtomicU64::new(0));
+        let clock = Clock::new(&source, 1_000_000);
+        clock.delay_us(50);
+        assert!(source.now_ticks() >= 50);
+    }
+
+    #[test]
+    fn clock_delay_without_a_running_source_returns_at_once() {
+        let source = MockTime::stopped();
+        Clock::new(&source, 10_000_000).delay_us(u64::MAX);
+    }
+}
@@ -0,0 +1,276 @@
+//! # TLB shootdown bookkeeping
+//!
+//! The parts of a shootdown that don't run `sfence.vma`: the batch of
+//! changed translations an initiator collects, and the per-hart mailboxes
+//! batches wait in until their hart flushes and acknowledges them. The
+//! kernel's `tlb` module does the flushing and the interrupting.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+/// Pages a batch lists before it flushes the whole ASID instead
+pub const BATCH_PAGES: usize = 16;
+
+/// Bit of `hart` in a hart mask
+pub const fn hart_bit(hart: usize) -> usize {
+    1 << hart
+}
+
+/// Translations that have changed and must be flushed
+#[derive(Copy, Clone, Debug)]
+pub struct TlbBatch {
+    pages: [usize; BATCH_PAGES],
+    len: usize,
+    /// Too many pages to list, flush them all
+    full: bool,
+}
+
+impl TlbBatch {
+    pub const fn new() -> Self {
+        Self {
+            pages: [0; BATCH_PAGES],
+            len: 0,
+            full: false,
+        }
+    }
+
+    /// Add the page at `virt`
+    pub fn add(&mut self, virt: usize) {
+        if self.full {
+            return;
+        }
+        match self.pages.get_mut(self.len) {
+            Some(page) => {
+                *page = virt;
+                self.len += 1;
+            }
+            None => self.full = true,
+        }
+    }
+
+    /// Ask for the whole ASID to be flushed
+    pub fn add_all(&mut self) {
+        self.full = true;
+    }
+
+    /// Add everything in `other`
+    pub fn merge(&mut self, other: &TlbBatch) {
+        if other.full {
+            self.full = true;
+        }
+        for &page in other.pages() {
+            self.add(page);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && !self.full
+    }
+
+    /// Whether the batch flushes the whole ASID
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// The pages listed, if the batch isn't full
+    pub fn pages(&self) -> &[usize] {
+        &self.pages[..self.len]
+    }
+}
+
+impl Default for TlbBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A batch for one hart to flush, and the ASID it was made under. No ASID
+/// means translations of more than one address space are waiting and the
+/// hart flushes everything.
+#[derive(Copy, Clone, Debug)]
+pub struct Shootdown {
+    pub asid: Option<u16>,
+    pub batch: TlbBatch,
+}
+
+/// A mailbox for each of `HARTS` harts, and which of them have a batch
+/// they haven't acknowledged yet
+pub struct Mailboxes<const HARTS: usize> {
+    boxes: [Mutex<Option<Shootdown>>; HARTS],
+    pending: AtomicUsize,
+}
+
+impl<const HARTS: usize> Mailboxes<HARTS> {
+    pub const fn new() -> Self {
+        Self {
+            boxes: [const { Mutex::new(None) }; HARTS],
+            pending: AtomicUsize::new(0),
+        }
+    }
+
+    /// Leave `batch`, made under `asid`, for `hart`. A batch still waiting
+    /// there has this one merged into it.
+    pub fn post(&self, hart: usize, asid: u16, batch: &TlbBatch) {
+        let mut mailbox = self.boxes[hart].lock();
+        *mailbox = Some(match mailbox.take() {
+            Some(mut waiting) => {
+                if waiting.asid != Some(asid) {
+                    waiting.asid = None;
+                }
+                waiting.batch.merge(batch);
+                waiting
+            }
+            None => Shootdown {
+                asid: Some(asid),
+                batch: *batch,
+            },
+        });
+        self.pending.fetch_or(hart_bit(hart), Ordering::Release);
+    }
+
+    /// Hand whatever is waiting for `hart` to `flush`, then acknowledge it.
+    /// A hart without a mailbox, or with nothing waiting, only
+    /// acknowledges.
+    pub fn take(&self, hart: usize, flush: impl FnOnce(&Shootdown)) {
+        let Some(mailbox) = self.boxes.get(hart) else {
+            return;
+        };
+        // Acknowledged with the mailbox held, so a batch merged in after we
+        // looked can't have its acknowledgement taken by this one
+        let mut mailbox = mailbox.lock();
+        if let Some(shootdown) = mailbox.take() {
+            flush(&shootdown);
+        }
+        self.pending.fetch_and(!hart_bit(hart), Ordering::Release);
+    }
+
+    /// Which of the harts in `harts` haven't acknowledged their batch
+    pub fn waiting(&self, harts: usize) -> usize {
+        self.pending.load(Ordering::Acquire) & harts
+    }
+}
+
+impl<const HARTS: usize> Default for Mailboxes<HARTS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch(pages: &[usize]) -> TlbBatch {
+        let mut batch = TlbBatch::new();
+        for &page in pages {
+            batch.add(page);
+        }
+        batch
+    }
+
+    #[test]
+    fn batch_lists_pages_until_it_overflows() {
+        let mut batch = TlbBatch::new();
+        assert!(batch.is_empty());
+        for page in 0..BATCH_PAGES {
+            batch.add(page << 12);
+        }
+        assert!(!batch.is_full());
+        assert_eq!(batch.pages().len(), BATCH_PAGES);
+
+        batch.add(BATCH_PAGES << 12);
+        assert!(batch.is_full());
+        assert!(!batch.is_empty());
+    }
+
+    #[test]
+    fn merging_a_full_batch_makes_the_result_full() {
+        let mut all = TlbBatch::new();
+        all.add_all();
+        let mut pages = batch(&[0x1000]);
+        pages.merge(&all);
+        assert!(pages.is_full());
+    }
+
+    #[test]
+    fn take_flushes_and_acknowledges() {
+        let mailboxes = Mailboxes::<4>::new();
+        mailboxes.post(1, 7, &batch(&[0x1000, 0x2000]));
+        assert_eq!(mailboxes.waiting(0b1111), hart_bit(1));
+
+        let mut flushed = None;
+        mailboxes.take(1, |shootdown| flushed = Some(*shootdown));
+        let flushed = flushed.expect("nothing was flushed");
+        assert_eq!(flushed.asid, Some(7));
+        assert_eq!(flushed.batch.pages(), &[0x1000, 0x2000]);
+        assert_eq!(mailboxes.waiting(0b1111), 0);
+    }
+
+    #[test]
+    fn batches_waiting_for_a_hart_are_merged() {
+        let mailboxes = Mailboxes::<4>::new();
+        mailboxes.post(2, 7, &batch(&[0x1000]));
+        mailboxes.post(2, 7, &batch(&[0x2000]));
+
+        let mut flushed = None;
+        mailboxes.take(2, |shootdown| flushed = Some(*shootdown));
+        let flushed = flushed.unwrap();
+        assert_eq!(flushed.asid, Some(7));
+        assert_eq!(flushed.batch.pages(), &[0x1000, 0x2000]);
+    }
+
+    #[test]
+    fn batches_of_different_asids_flush_everything() {
+        let mailboxes = Mailboxes::<4>::new();
+        mailboxes.post(0, 7, &batch(&[0x1000]));
+        mailboxes.post(0, 8, &batch(&[0x1000]));
+
+        let mut asid = Some(0);
+        mailboxes.take(0, |shootdown| asid = shootdown.asid);
+        assert_eq!(asid, None);
+    }
+
+    #[test]
+    fn only_the_harts_asked_about_are_waiting() {
+        let mailboxes = Mailboxes::<4>::new();
+        mailboxes.post(1, 7, &batch(&[0x1000]));
+        mailboxes.post(3, 7, &batch(&[0x1000]));
+        assert_eq!(mailboxes.waiting(hart_bit(1)), hart_bit(1));
+        assert_eq!(mailboxes.waiting(hart_bit(2)), 0);
+
+        mailboxes.take(3, |_| {});
+        assert_eq!(mailboxes.waiting(0b1111), hart_bit(1));
+    }
+
+    #[test]
+    fn take_without_a_batch_only_acknowledges() {
+        let mailboxes = Mailboxes::<4>::new();
+        let mut called = false;
+        mailboxes.take(1, |_| called = true);
+        mailboxes.take(9, |_| called = true);
+        assert!(!called);
+        assert_eq!(mailboxes.waiting(usize::MAX), 0);
+    }
+
+    #[test]
+    fn remote_hart_acknowledges_what_the_initiator_waits_on() {
+        // A thread stands in for the remote hart, taking its mailbox the
+        // way the trap handler would on each interrupt
+        static MAILBOXES: Mailboxes<2> = Mailboxes::new();
+        MAILBOXES.post(1, 3, &batch(&[0x5000]));
+
+        let remote = std::thread::spawn(|| {
+            let mut flushed = 0;
+            while MAILBOXES.waiting(hart_bit(1)) != 0 {
+                MAILBOXES.take(1, |shootdown| flushed += shootdown.batch.pages().len());
+            }
+            flushed
+        });
+
+        while MAILBOXES.waiting(hart_bit(1)) != 0 {
+            std::hint::spin_loop();
+        }
+        assert_eq!(remote.join().unwrap(), 1);
+    }
+}
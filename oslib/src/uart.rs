@@ -0,0 +1,334 @@
+//! # NS16550A UART
+//!
+//! The register logic of the 16550 compatible UART QEMU's virt machine
+//! puts its console on, driven through [`ByteRegisters`] so it can be
+//! tested against a fake UART.
+
+use core::fmt::{Error, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::mmio::ByteRegisters;
+
+/// Bytes the transmit FIFO holds
+const TX_FIFO_DEPTH: usize = 16;
+
+/// Modem control register
+const MCR: usize = 4;
+/// Line status register
+const LSR: usize = 5;
+/// Modem status register
+const MSR: usize = 6;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_PARITY: u8 = 1 << 2;
+const LSR_FRAMING: u8 = 1 << 3;
+const LSR_BREAK: u8 = 1 << 4;
+/// The transmit holding register, and with it the FIFO, is empty
+const LSR_THR_EMPTY: u8 = 1 << 5;
+/// Both the holding register and the shift register are empty
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+/// Clear to send
+pub const MSR_CTS: u8 = 1 << 4;
+/// Data set ready
+pub const MSR_DSR: u8 = 1 << 5;
+/// Ring indicator
+pub const MSR_RI: u8 = 1 << 6;
+/// Data carrier detect
+pub const MSR_DCD: u8 = 1 << 7;
+
+/// Receive errors reported by the line status register
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UartError {
+    /// A byte arrived while the FIFO was full and was lost. The bytes
+    /// still in the FIFO are fine.
+    Overrun,
+    /// The received byte failed its parity check and was discarded
+    Parity,
+    /// The received byte had no valid stop bit and was discarded
+    Framing,
+    /// The line was held low for longer than a whole byte
+    Break,
+}
+
+/// # Universal Async Reciever Transmitter
+///
+///
+pub struct Uart<R: ByteRegisters> {
+    regs: R,
+    /// Times the receive FIFO filled up and dropped input before we read it
+    overruns: AtomicUsize,
+}
+
+impl<R: ByteRegisters> Uart<R> {
+    /// Wrap the structure
+    pub fn new(regs: R) -> Self {
+        Self {
+            regs,
+            overruns: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many receive overruns [`Uart::get`] has seen
+    pub fn overruns(&self) -> usize {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    /// initialise
+    pub fn init(&mut self) {
+        let regs = &self.regs;
+
+        // First, set the word length, which
+        // are bits 0 and 1 of the line control register (LCR)
+        // which is at base_address + 3
+        // We can easily write the value 3 here or 0b11, but I'm
+        // extending it so that it is clear we're setting two individual
+        // fields
+        let _ = regs.write8(3, (1 << 0) | (1 << 1));
+
+        // Now, enable the FIFO, which is bit index 0 of the FIFO
+        // control register (FCR at offset 2).
+        // Again, we can just write 1 here, but when we use left shift,
+        // it's easier to see that we're trying to write bit index #0.
+        let _ = regs.write8(2, 1 << 0);
+
+        // Receive interrupts (bit 0 of the IER at offset 1) stay off:
+        // nothing claims and completes PLIC sources yet, and the trap
+        // vector would return straight back into a pending interrupt.
+        // Input is polled instead.
+    }
+
+    pub fn put(&self, c: u8) {
+        // writes a single byte at the position
+        let _ = self.regs.write8(0, c);
+    }
+
+    /// Write `bytes` a FIFO's worth at a time, checking the line status
+    /// once per FIFO rather than once per byte
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        for chunk in bytes.chunks(TX_FIFO_DEPTH) {
+            while self
+                .regs
+                .read8(LSR)
+                .is_ok_and(|lsr| lsr & LSR_THR_EMPTY == 0)
+            {
+                core::hint::spin_loop();
+            }
+            for &c in chunk {
+                let _ = self.regs.write8(0, c);
+            }
+        }
+    }
+
+    /// Wait until every byte written so far has left the shift register
+    pub fn flush(&self) {
+        while self
+            .regs
+            .read8(LSR)
+            .is_ok_and(|lsr| lsr & LSR_TRANSMITTER_EMPTY == 0)
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Read a byte if one has arrived, ignoring receive errors. Overruns
+    /// are counted; the bytes still in the FIFO are good and are read.
+    pub fn get(&self) -> Option<u8> {
+        match self.read_byte() {
+            Ok(byte) => byte,
+            Err(UartError::Overrun) => {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+                self.read_byte().ok().flatten()
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Read a byte if one has arrived, reporting receive errors. Reading
+    /// the line status register clears its error bits, so each error is
+    /// only reported once.
+    pub fn read_byte(&self) -> Result<Option<u8>, UartError> {
+        let lsr = self.regs.read8(LSR).unwrap_or(0);
+
+        if lsr & (LSR_BREAK | LSR_FRAMING | LSR_PARITY) != 0 {
+            // The byte at the head of the FIFO is the bad one; drop it
+            let _ = self.regs.read8(0);
+            return Err(if lsr & LSR_BREAK != 0 {
+                UartError::Break
+            } else if lsr & LSR_FRAMING != 0 {
+                UartError::Framing
+            } else {
+                UartError::Parity
+            });
+        }
+        if lsr & LSR_OVERRUN != 0 {
+            return Err(UartError::Overrun);
+        }
+
+        if lsr & LSR_DATA_READY == 0 {
+            // The DR bit is 0, meaning no data
+            Ok(None)
+        } else {
+            // The DR bit is 1, meaning data!
+            Ok(self.regs.read8(0).ok())
+        }
+    }
+
+    /// Raise or drop Request To Send, telling the other end whether we can
+    /// take more input
+    pub fn set_rts(&self, on: bool) {
+        self.set_mcr(MCR_RTS, on);
+    }
+
+    /// Raise or drop Data Terminal Ready
+    pub fn set_dtr(&self, on: bool) {
+        self.set_mcr(MCR_DTR, on);
+    }
+
+    fn set_mcr(&self, bit: u8, on: bool) {
+        let mcr = self.regs.read8(MCR).unwrap_or(0);
+        let mcr = if on { mcr | bit } else { mcr & !bit };
+        let _ = self.regs.write8(MCR, mcr);
+    }
+
+    /// The modem status register, see the `MSR_*` bits
+    pub fn modem_status(&self) -> u8 {
+        self.regs.read8(MSR).unwrap_or(0)
+    }
+}
+
+impl<R: ByteRegisters> Write for Uart<R> {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        for c in out.bytes() {
+            self.put(c);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::mmio::{check_access, MmioError};
+
+    /// A 16550 with bytes waiting in its receive FIFO. Error bits set in
+    /// `lsr_errors` read back once from the line status register, as on
+    /// the real part.
+    #[derive(Default)]
+    struct FakeUart {
+        rx: RefCell<VecDeque<u8>>,
+        lsr_errors: Cell<u8>,
+        lsr_reads: Cell<usize>,
+        mcr: Cell<u8>,
+        tx: RefCell<Vec<u8>>,
+    }
+
+    impl FakeUart {
+        fn receiving(bytes: &[u8]) -> Self {
+            let uart = Self::default();
+            uart.rx.borrow_mut().extend(bytes);
+            uart
+        }
+    }
+
+    impl ByteRegisters for FakeUart {
+        fn read8(&self, offset: usize) -> Result<u8, MmioError> {
+            check_access(8, offset, 1)?;
+            Ok(match offset {
+                0 => self.rx.borrow_mut().pop_front().unwrap_or(0),
+                LSR => {
+                    self.lsr_reads.set(self.lsr_reads.get() + 1);
+                    let ready = if self.rx.borrow().is_empty() {
+                        0
+                    } else {
+                        LSR_DATA_READY
+                    };
+                    ready | self.lsr_errors.take() | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+                }
+                MCR => self.mcr.get(),
+                _ => 0,
+            })
+        }
+
+        fn write8(&self, offset: usize, value: u8) -> Result<(), MmioError> {
+            check_access(8, offset, 1)?;
+            match offset {
+                0 => self.tx.borrow_mut().push(value),
+                MCR => self.mcr.set(value),
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bytes_are_read_in_order_until_the_fifo_is_empty() {
+        let uart = Uart::new(FakeUart::receiving(b"hi"));
+        assert_eq!(uart.get(), Some(b'h'));
+        assert_eq!(uart.get(), Some(b'i'));
+        assert_eq!(uart.get(), None);
+        assert_eq!(uart.overruns(), 0);
+    }
+
+    #[test]
+    fn overruns_are_counted_and_the_fifo_still_read() {
+        let fake = FakeUart::receiving(b"ok");
+        fake.lsr_errors.set(LSR_OVERRUN);
+        let uart = Uart::new(fake);
+
+        assert_eq!(uart.get(), Some(b'o'));
+        assert_eq!(uart.overruns(), 1);
+        // The error bit cleared when it was read; the next byte is clean
+        assert_eq!(uart.get(), Some(b'k'));
+        assert_eq!(uart.overruns(), 1);
+
+        uart.regs.rx.borrow_mut().push_back(b'!');
+        uart.regs.lsr_errors.set(LSR_OVERRUN);
+        assert_eq!(uart.read_byte(), Err(UartError::Overrun));
+        assert_eq!(uart.get(), Some(b'!'));
+        // read_byte reports overruns but leaves counting them to get
+        assert_eq!(uart.overruns(), 1);
+    }
+
+    #[test]
+    fn bad_bytes_are_dropped_and_reported() {
+        let fake = FakeUart::receiving(b"xy");
+        fake.lsr_errors.set(LSR_FRAMING | LSR_PARITY);
+        let uart = Uart::new(fake);
+
+        assert_eq!(uart.read_byte(), Err(UartError::Framing));
+        assert_eq!(uart.get(), Some(b'y'));
+
+        uart.regs.rx.borrow_mut().push_back(0);
+        uart.regs.lsr_errors.set(LSR_BREAK | LSR_FRAMING);
+        assert_eq!(uart.get(), None);
+        assert!(uart.regs.rx.borrow().is_empty());
+    }
+
+    #[test]
+    fn writes_check_the_line_status_once_per_fifo() {
+        let uart = Uart::new(FakeUart::default());
+        let bytes: Vec<u8> = (0..40).collect();
+        uart.write_bytes(&bytes);
+        assert_eq!(*uart.regs.tx.borrow(), bytes);
+        assert_eq!(uart.regs.lsr_reads.get(), 3);
+    }
+
+    #[test]
+    fn modem_control_bits_are_set_independently() {
+        let uart = Uart::new(FakeUart::default());
+        uart.set_rts(true);
+        uart.set_dtr(true);
+        uart.set_rts(false);
+        assert_eq!(uart.regs.mcr.get(), MCR_DTR);
+    }
+}
@@ -0,0 +1,139 @@
+//! # UTF-8 decoding
+//!
+//! Turning bytes that arrive one at a time, like console input, back into
+//! characters. A byte that can't continue or start a valid sequence comes
+//! out as [`REPLACEMENT`] and decoding picks up again at the next byte
+//! that can start one, so one bad byte costs one character rather than
+//! everything after it.
+
+/// What an invalid sequence decodes to
+pub const REPLACEMENT: char = '\u{fffd}';
+
+/// Where the last character of `bytes`, which must be valid UTF-8, starts.
+/// Taking it back, say for backspace, leaves `bytes[..start]`.
+pub fn last_char_start(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .rposition(|&byte| byte & 0xc0 != 0x80)
+        .unwrap_or(0)
+}
+
+/// Decodes a byte stream a byte at a time
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Utf8Decoder {
+    /// Bits of the character decoded so far
+    partial: u32,
+    /// Continuation bytes still to come
+    needed: u8,
+    /// Length of the sequence being decoded
+    len: u8,
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Self {
+        Self {
+            partial: 0,
+            needed: 0,
+            len: 0,
+        }
+    }
+
+    /// Whether a sequence has been started and not finished
+    pub fn is_pending(&self) -> bool {
+        self.needed > 0
+    }
+
+    /// Decode `byte`, returning the characters it completes. That is
+    /// usually none or one; a byte that cuts a sequence short yields a
+    /// replacement for the sequence and then whatever it starts itself.
+    pub fn push(&mut self, byte: u8) -> [Option<char>; 2] {
+        let mut cut_short = None;
+        if self.needed > 0 {
+            if byte & 0xc0 == 0x80 {
+                self.partial = (self.partial << 6) | u32::from(byte & 0x3f);
+                self.needed -= 1;
+                return match self.needed {
+                    0 => [Some(self.finish()), None],
+                    _ => [None, None],
+                };
+            }
+            self.needed = 0;
+            cut_short = Some(REPLACEMENT);
+        }
+
+        let (len, bits) = match byte {
+            0x00..=0x7f => return [cut_short, Some(char::from(byte))],
+            0xc2..=0xdf => (2, byte & 0x1f),
+            0xe0..=0xef => (3, byte & 0x0f),
+            0xf0..=0xf4 => (4, byte & 0x07),
+            // Stray continuation bytes, overlong leads and leads past
+            // U+10FFFF
+            _ => return [cut_short, Some(REPLACEMENT)],
+        };
+        self.partial = u32::from(bits);
+        self.len = len;
+        self.needed = len - 1;
+        [cut_short, None]
+    }
+
+    /// The character of a complete sequence, or a replacement if it was
+    /// overlong, a surrogate or past U+10FFFF
+    fn finish(&self) -> char {
+        let shortest = match self.len {
+            2 => 0x80,
+            3 => 0x800,
+            _ => 0x1_0000,
+        };
+        if self.partial < shortest {
+            return REPLACEMENT;
+        }
+        char::from_u32(self.partial).unwrap_or(REPLACEMENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> String {
+        let mut decoder = Utf8Decoder::new();
+        let out = bytes
+            .iter()
+            .flat_map(|&byte| decoder.push(byte))
+            .flatten()
+            .collect();
+        assert!(!decoder.is_pending());
+        out
+    }
+
+    #[test]
+    fn decodes_valid_sequences() {
+        assert_eq!(
+            decode("a\u{e9}\u{20ac}\u{1f600}".as_bytes()),
+            "a\u{e9}\u{20ac}\u{1f600}"
+        );
+    }
+
+    #[test]
+    fn bad_bytes_cost_one_character() {
+        // A stray continuation byte, then a lead past U+10FFFF
+        assert_eq!(decode(b"a\x80b\xf8c"), "a\u{fffd}b\u{fffd}c");
+        // A sequence cut short by ASCII and by another lead
+        assert_eq!(decode(b"\xe2\x82a\xc3\xc3\xa9"), "\u{fffd}a\u{fffd}\u{e9}");
+    }
+
+    #[test]
+    fn rejects_overlong_and_surrogate_sequences() {
+        assert_eq!(decode(b"\xe0\x80\x80"), "\u{fffd}");
+        assert_eq!(decode(b"\xed\xa0\x80"), "\u{fffd}");
+    }
+
+    #[test]
+    fn last_char_start_backs_up_a_whole_character() {
+        let text = "a\u{e9}\u{20ac}".as_bytes();
+        assert_eq!(last_char_start(text), 3);
+        assert_eq!(last_char_start(&text[..3]), 1);
+        assert_eq!(last_char_start(&text[..1]), 0);
+        assert_eq!(last_char_start(b""), 0);
+    }
+}
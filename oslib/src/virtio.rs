@@ -0,0 +1,450 @@
+//! # Virtio MMIO transport
+//!
+//! The register interface of `virtio,mmio` devices (virtio 1.1 section 4.2),
+//! driven through [`Registers`] so it can be tested against fake devices.
+
+use crate::mmio::{MmioError, Registers};
+
+pub const COMPATIBLE: &str = "virtio,mmio";
+
+/// "virt" in little endian
+const MAGIC: u32 = 0x7472_6976;
+
+pub const MAGIC_VALUE: usize = 0x000;
+pub const VERSION: usize = 0x004;
+pub const DEVICE_ID: usize = 0x008;
+pub const VENDOR_ID: usize = 0x00c;
+pub const DEVICE_FEATURES: usize = 0x010;
+pub const DEVICE_FEATURES_SEL: usize = 0x014;
+pub const DRIVER_FEATURES: usize = 0x020;
+pub const DRIVER_FEATURES_SEL: usize = 0x024;
+/// Legacy only
+pub const GUEST_PAGE_SIZE: usize = 0x028;
+pub const QUEUE_SEL: usize = 0x030;
+pub const QUEUE_NUM_MAX: usize = 0x034;
+pub const QUEUE_NUM: usize = 0x038;
+/// Legacy only
+pub const QUEUE_ALIGN: usize = 0x03c;
+/// Legacy only
+pub const QUEUE_PFN: usize = 0x040;
+pub const QUEUE_READY: usize = 0x044;
+pub const QUEUE_NOTIFY: usize = 0x050;
+pub const INTERRUPT_STATUS: usize = 0x060;
+pub const INTERRUPT_ACK: usize = 0x064;
+pub const STATUS: usize = 0x070;
+pub const QUEUE_DESC_LOW: usize = 0x080;
+pub const QUEUE_DRIVER_LOW: usize = 0x090;
+pub const QUEUE_DEVICE_LOW: usize = 0x0a0;
+/// Start of the device specific configuration space
+pub const CONFIG: usize = 0x100;
+
+// Device IDs
+pub const DEVICE_NET: u32 = 1;
+pub const DEVICE_BLOCK: u32 = 2;
+pub const DEVICE_CONSOLE: u32 = 3;
+pub const DEVICE_RNG: u32 = 4;
+pub const DEVICE_GPU: u32 = 16;
+pub const DEVICE_INPUT: u32 = 18;
+
+/// A short name for a device ID, for logs
+pub fn device_name(device_id: u32) -> &'static str {
+    match device_id {
+        DEVICE_NET => "net",
+        DEVICE_BLOCK => "block",
+        DEVICE_CONSOLE => "console",
+        DEVICE_RNG => "rng",
+        DEVICE_GPU => "gpu",
+        DEVICE_INPUT => "input",
+        _ => "unknown",
+    }
+}
+
+// Device status bits
+pub const STATUS_ACKNOWLEDGE: u32 = 1;
+pub const STATUS_DRIVER: u32 = 2;
+pub const STATUS_DRIVER_OK: u32 = 4;
+pub const STATUS_FEATURES_OK: u32 = 8;
+pub const STATUS_FAILED: u32 = 128;
+
+// Interrupt status bits
+/// A queue's used ring has new entries
+pub const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+/// The device configuration changed
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+// Feature bits common to every device
+pub const VIRTIO_F_RING_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_RING_EVENT_IDX: u64 = 1 << 29;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Block device feature bits
+pub const BLK_SIZE_MAX: u64 = 1 << 1;
+pub const BLK_SEG_MAX: u64 = 1 << 2;
+pub const BLK_RO: u64 = 1 << 5;
+pub const BLK_BLK_SIZE: u64 = 1 << 6;
+pub const BLK_FLUSH: u64 = 1 << 9;
+
+// Network device feature bits
+pub const NET_CSUM: u64 = 1 << 0;
+pub const NET_MAC: u64 = 1 << 5;
+pub const NET_STATUS: u64 = 1 << 16;
+
+// GPU feature bits
+pub const GPU_VIRGL: u64 = 1 << 0;
+pub const GPU_EDID: u64 = 1 << 1;
+
+/// The features both sides can use: those the device offers that the
+/// driver wants
+pub const fn negotiate(offered: u64, wanted: u64) -> u64 {
+    offered & wanted
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum VirtioError {
+    /// The node has no `reg`
+    NoRegion,
+    /// The window is too small to hold the common registers
+    RegionTooSmall(usize),
+    /// The window doesn't start with the virtio magic
+    BadMagic(u32),
+    /// A version of the MMIO interface we don't speak
+    UnsupportedVersion(u32),
+    /// The device didn't accept the features we acknowledged
+    FeaturesRejected,
+    /// The queue holds fewer entries than we use, 0 if it doesn't exist
+    QueueTooSmall(u32),
+    /// Not enough free descriptors for the request
+    QueueFull,
+    /// No frame was left for a queue
+    OutOfMemory,
+    /// The device handed back a chain that isn't one of ours, by its head
+    CorruptChain(u32),
+    /// The device didn't finish a request in time
+    Timeout,
+    Mmio(MmioError),
+}
+
+impl From<MmioError> for VirtioError {
+    fn from(error: MmioError) -> Self {
+        Self::Mmio(error)
+    }
+}
+
+/// The registers of one virtio-mmio device
+pub struct VirtioMmio<R: Registers> {
+    regs: R,
+    /// Features accepted by `negotiate_features`
+    features: u64,
+}
+
+impl<R: Registers> VirtioMmio<R> {
+    /// Check `regs` hold a virtio device whose interface we speak
+    pub fn new(regs: R) -> Result<Self, VirtioError> {
+        let transport = Self { regs, features: 0 };
+
+        let magic = transport.read32(MAGIC_VALUE)?;
+        if magic != MAGIC {
+            return Err(VirtioError::BadMagic(magic));
+        }
+        let version = transport.read32(VERSION)?;
+        if !(1..=2).contains(&version) {
+            return Err(VirtioError::UnsupportedVersion(version));
+        }
+        Ok(transport)
+    }
+
+    /// The registers the device is driven through
+    pub fn registers(&self) -> &R {
+        &self.regs
+    }
+
+    /// Version of the MMIO interface, 1 for legacy devices
+    pub fn version(&self) -> Result<u32, VirtioError> {
+        self.read32(VERSION)
+    }
+
+    /// What kind of device this is. 0 means the slot is empty.
+    pub fn device_id(&self) -> Result<u32, VirtioError> {
+        self.read32(DEVICE_ID)
+    }
+
+    pub fn vendor_id(&self) -> Result<u32, VirtioError> {
+        self.read32(VENDOR_ID)
+    }
+
+    pub fn status(&self) -> Result<u32, VirtioError> {
+        self.read32(STATUS)
+    }
+
+    pub fn set_status(&self, status: u32) -> Result<(), VirtioError> {
+        self.write32(STATUS, status)
+    }
+
+    /// All the features the device offers
+    pub fn device_features(&self) -> Result<u64, VirtioError> {
+        self.write32(DEVICE_FEATURES_SEL, 0)?;
+        let low = self.read32(DEVICE_FEATURES)?;
+        self.write32(DEVICE_FEATURES_SEL, 1)?;
+        let high = self.read32(DEVICE_FEATURES)?;
+        Ok((u64::from(high) << 32) | u64::from(low))
+    }
+
+    /// Acknowledge the offered features that are in `wanted` and remember
+    /// them for [`has_feature`]. Modern devices must then confirm with
+    /// FEATURES_OK, which is checked.
+    ///
+    /// [`has_feature`]: VirtioMmio::has_feature
+    pub fn negotiate_features(&mut self, wanted: u64) -> Result<u64, VirtioError> {
+        let accepted = negotiate(self.device_features()?, wanted);
+
+        self.write32(DRIVER_FEATURES_SEL, 0)?;
+        self.write32(DRIVER_FEATURES, accepted as u32)?;
+        self.write32(DRIVER_FEATURES_SEL, 1)?;
+        self.write32(DRIVER_FEATURES, (accepted >> 32) as u32)?;
+
+        // Legacy devices have no FEATURES_OK handshake
+        if self.version()? >= 2 {
+            self.set_status(self.status()? | STATUS_FEATURES_OK)?;
+            if self.status()? & STATUS_FEATURES_OK == 0 {
+                return Err(VirtioError::FeaturesRejected);
+            }
+        }
+
+        self.features = accepted;
+        Ok(accepted)
+    }
+
+    /// Whether `feature` was accepted during negotiation
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
+
+    /// Read which interrupts the device has raised and acknowledge them,
+    /// returning the `INTERRUPT_*` bits
+    pub fn acknowledge_interrupt(&self) -> Result<u32, VirtioError> {
+        let status = self.read32(INTERRUPT_STATUS)?;
+        if status != 0 {
+            self.write32(INTERRUPT_ACK, status)?;
+        }
+        Ok(status)
+    }
+
+    /// Read a register, failing if it lies outside the window
+    pub fn read32(&self, offset: usize) -> Result<u32, VirtioError> {
+        Ok(self.regs.read32(offset)?)
+    }
+
+    /// Write a register, failing if it lies outside the window
+    pub fn write32(&self, offset: usize, value: u32) -> Result<(), VirtioError> {
+        Ok(self.regs.write32(offset, value)?)
+    }
+}
+
+/// A virtio-mmio slot with a device behind it
+#[derive(Copy, Clone, Debug)]
+pub struct Slot {
+    pub base: usize,
+    pub device_id: u32,
+}
+
+/// Look at `count` windows `stride` bytes apart from `base` and return the
+/// ones holding a device. Each window is read through what `claim` makes
+/// of it, and skipped if that is nothing. Windows without the magic, or
+/// whose device ID is 0 because the slot is empty, are skipped too.
+pub fn probe_all<R: Registers>(
+    base: usize,
+    count: usize,
+    stride: usize,
+    mut claim: impl FnMut(usize, usize) -> Option<R>,
+) -> impl Iterator<Item = Slot> {
+    (0..count).filter_map(move |index| {
+        let base = base + index * stride;
+        probe(&claim(base, stride)?, base)
+    })
+}
+
+fn probe<R: Registers>(regs: &R, base: usize) -> Option<Slot> {
+    if regs.read32(MAGIC_VALUE).ok()? != MAGIC {
+        return None;
+    }
+    match regs.read32(DEVICE_ID).ok()? {
+        0 => None,
+        device_id => Some(Slot { base, device_id }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::mmio::check_access;
+
+    /// Size of a fake device's register window
+    const WINDOW: usize = 0x200;
+
+    /// A virtio-mmio device offering `features`. Modern ones refuse
+    /// FEATURES_OK when the driver acknowledged something they don't
+    /// offer; legacy ones have no FEATURES_OK to refuse.
+    #[derive(Clone)]
+    struct FakeDevice {
+        regs: Rc<RefCell<[u32; WINDOW / 4]>>,
+        features: u64,
+        acknowledged: Rc<RefCell<u64>>,
+        writes: Rc<RefCell<Vec<(usize, u32)>>>,
+    }
+
+    impl FakeDevice {
+        fn new(magic: u32, version: u32, device_id: u32, features: u64) -> Self {
+            let mut regs = [0; WINDOW / 4];
+            regs[MAGIC_VALUE / 4] = magic;
+            regs[VERSION / 4] = version;
+            regs[DEVICE_ID / 4] = device_id;
+            regs[VENDOR_ID / 4] = 0x554d_4551;
+            Self {
+                regs: Rc::new(RefCell::new(regs)),
+                features,
+                acknowledged: Rc::new(RefCell::new(0)),
+                writes: Rc::new(RefCell::new(Vec::new())),
+            }
+        }
+
+        fn legacy(device_id: u32, features: u64) -> Self {
+            Self::new(MAGIC, 1, device_id, features)
+        }
+
+        fn modern(device_id: u32, features: u64) -> Self {
+            Self::new(MAGIC, 2, device_id, features)
+        }
+
+        fn reg(&self, offset: usize) -> u32 {
+            self.regs.borrow()[offset / 4]
+        }
+
+        fn wrote(&self, offset: usize) -> bool {
+            self.writes.borrow().iter().any(|&(at, _)| at == offset)
+        }
+    }
+
+    impl Registers for FakeDevice {
+        fn read32(&self, offset: usize) -> Result<u32, MmioError> {
+            check_access(WINDOW, offset, 4)?;
+            if offset == DEVICE_FEATURES {
+                let shift = 32 * self.reg(DEVICE_FEATURES_SEL);
+                return Ok((self.features >> shift) as u32);
+            }
+            Ok(self.reg(offset))
+        }
+
+        fn write32(&self, offset: usize, value: u32) -> Result<(), MmioError> {
+            check_access(WINDOW, offset, 4)?;
+            self.writes.borrow_mut().push((offset, value));
+            let mut value = value;
+            match offset {
+                DRIVER_FEATURES => {
+                    let shift = 32 * self.reg(DRIVER_FEATURES_SEL);
+                    *self.acknowledged.borrow_mut() |= u64::from(value) << shift;
+                }
+                STATUS if *self.acknowledged.borrow() & !self.features != 0 => {
+                    value &= !STATUS_FEATURES_OK;
+                }
+                INTERRUPT_ACK => self.regs.borrow_mut()[INTERRUPT_STATUS / 4] &= !value,
+                _ => {}
+            }
+            self.regs.borrow_mut()[offset / 4] = value;
+            Ok(())
+        }
+    }
+
+    const BASE: usize = 0x1000_1000;
+    const STRIDE: usize = 0x1000;
+
+    #[test]
+    fn probe_finds_legacy_and_modern_devices_only() {
+        let slots = [
+            Some(FakeDevice::legacy(DEVICE_BLOCK, 0)),
+            // Empty slot
+            Some(FakeDevice::modern(0, 0)),
+            Some(FakeDevice::new(0x1234_5678, 2, DEVICE_NET, 0)),
+            // Claimed by another driver
+            None,
+            Some(FakeDevice::modern(DEVICE_RNG, 0)),
+        ];
+        let found: Vec<_> = probe_all(BASE, slots.len(), STRIDE, |base, len| {
+            assert_eq!(len, STRIDE);
+            slots[(base - BASE) / STRIDE].clone()
+        })
+        .map(|slot| (slot.base, device_name(slot.device_id)))
+        .collect();
+        assert_eq!(found, [(BASE, "block"), (BASE + 4 * STRIDE, "rng")]);
+    }
+
+    #[test]
+    fn transports_refuse_bad_magic_and_unknown_versions() {
+        assert_eq!(
+            VirtioMmio::new(FakeDevice::new(0x1234_5678, 2, DEVICE_NET, 0)).err(),
+            Some(VirtioError::BadMagic(0x1234_5678))
+        );
+        assert_eq!(
+            VirtioMmio::new(FakeDevice::new(MAGIC, 3, DEVICE_NET, 0)).err(),
+            Some(VirtioError::UnsupportedVersion(3))
+        );
+        let transport = VirtioMmio::new(FakeDevice::legacy(DEVICE_NET, 0)).unwrap();
+        assert_eq!(transport.version(), Ok(1));
+        assert_eq!(transport.device_id(), Ok(DEVICE_NET));
+    }
+
+    #[test]
+    fn legacy_devices_negotiate_without_features_ok() {
+        let device = FakeDevice::legacy(DEVICE_BLOCK, BLK_RO | BLK_FLUSH);
+        let mut transport = VirtioMmio::new(device.clone()).unwrap();
+
+        let accepted = transport.negotiate_features(BLK_FLUSH | BLK_SIZE_MAX);
+        assert_eq!(accepted, Ok(BLK_FLUSH));
+        assert!(transport.has_feature(BLK_FLUSH));
+        assert!(!transport.has_feature(BLK_RO));
+        assert_eq!(*device.acknowledged.borrow(), BLK_FLUSH);
+        assert!(!device.wrote(STATUS));
+    }
+
+    #[test]
+    fn modern_devices_confirm_features_ok() {
+        let offered = VIRTIO_F_VERSION_1 | NET_MAC;
+        let device = FakeDevice::modern(DEVICE_NET, offered);
+        let mut transport = VirtioMmio::new(device.clone()).unwrap();
+
+        assert_eq!(transport.negotiate_features(u64::MAX), Ok(offered));
+        assert_eq!(*device.acknowledged.borrow(), offered);
+        assert_ne!(device.reg(STATUS) & STATUS_FEATURES_OK, 0);
+    }
+
+    #[test]
+    fn modern_devices_can_reject_features() {
+        let device = FakeDevice::modern(DEVICE_NET, NET_MAC);
+        // Something else acknowledged a feature the device doesn't offer
+        *device.acknowledged.borrow_mut() = NET_CSUM;
+        let mut transport = VirtioMmio::new(device).unwrap();
+
+        assert_eq!(
+            transport.negotiate_features(NET_MAC),
+            Err(VirtioError::FeaturesRejected)
+        );
+        assert!(!transport.has_feature(NET_MAC));
+    }
+
+    #[test]
+    fn interrupts_are_acknowledged_once_read() {
+        let device = FakeDevice::modern(DEVICE_BLOCK, 0);
+        device.regs.borrow_mut()[INTERRUPT_STATUS / 4] = INTERRUPT_USED_BUFFER;
+        let transport = VirtioMmio::new(device.clone()).unwrap();
+
+        assert_eq!(transport.acknowledge_interrupt(), Ok(INTERRUPT_USED_BUFFER));
+        assert_eq!(transport.acknowledge_interrupt(), Ok(0));
+        assert_eq!(
+            device.writes.borrow().as_slice(),
+            [(INTERRUPT_ACK, INTERRUPT_USED_BUFFER)]
+        );
+    }
+}
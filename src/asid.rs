@@ -0,0 +1,39 @@
+//! # Address space identifiers
+//!
+//! Finding out how many ASID bits the hart implements and setting up the
+//! allocator that hands them out. The allocator, with its generations and
+//! rollover, lives in [`oslib::asid`], where it is tested on the host.
+
+use spin::Mutex;
+
+pub use oslib::asid::{AddressSpaceId, AsidAllocator, AsidStats};
+
+use crate::csr::{self, Satp, SatpMode};
+use crate::globals;
+
+/// Count the ASID bits the hart implements: the field is WARL, so the
+/// bits that stick when all ones are written are the implemented ones.
+///
+/// The kernel runs in M-mode, which `satp` doesn't affect, so trying a
+/// value out is harmless. A hart without Sv39 may ignore the write
+/// altogether, which reads as no ASIDs.
+pub fn detect_bits() -> u32 {
+    if !csr::has_extension(b'S') {
+        return 0;
+    }
+
+    let old = csr::satp::read();
+    csr::satp::write(Satp::new(SatpMode::Sv39, u16::MAX, 0).bits());
+    let asid = Satp::from_bits(csr::satp::read()).asid;
+    csr::satp::write(old);
+    unsafe { core::arch::asm!("sfence.vma zero, zero") };
+
+    asid.count_ones()
+}
+
+/// Detect the hart's ASID bits and set up the allocator
+pub fn init() -> &'static Mutex<AsidAllocator> {
+    let bits = detect_bits();
+    log!(Info, "asid: {} bits implemented", bits);
+    globals::ASIDS.init(Mutex::new(AsidAllocator::new(bits)))
+}
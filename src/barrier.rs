@@ -0,0 +1,102 @@
+//! # Memory barriers
+//!
+//! Ordering between the CPU, RAM a device reads or writes by DMA, and
+//! device registers. Drivers use these rather than writing their own
+//! `fence` instructions, so every fence says which accesses it orders.
+//!
+//! The usual pairs:
+//!
+//! | CPU does                                | then                          | fence       |
+//! |-----------------------------------------|-------------------------------|-------------|
+//! | fills descriptors in RAM                | publishes a ring index in RAM | [`dma_wmb`] |
+//! | writes rings in RAM                     | writes a doorbell register    | [`io_wmb`]  |
+//! | reads a used/completion index from RAM  | reads the entries it covers   | [`dma_rmb`] |
+//! | reads a status register                 | reads RAM the device filled   | [`io_rmb`]  |
+//!
+//! [`mb`] orders everything against everything and is for when none of
+//! the above fit.
+//!
+//! For the write side, [`DmaWriteGuard`] puts the fences in the right
+//! places by construction:
+//!
+//! ```ignore
+//! DmaWriteGuard::new()
+//!     .publish(|| ring.avail.idx = next)
+//!     .notify(|| regs.write32(QUEUE_NOTIFY, queue));
+//! ```
+
+use core::arch::asm;
+
+/// Order earlier RAM writes before later RAM writes, so a device never
+/// sees a published index before the descriptors behind it
+#[inline(always)]
+pub fn dma_wmb() {
+    unsafe { asm!("fence w, w", options(nostack)) };
+}
+
+/// Order earlier RAM reads before later RAM reads, so entries aren't read
+/// before the index saying the device has finished with them
+#[inline(always)]
+pub fn dma_rmb() {
+    unsafe { asm!("fence r, r", options(nostack)) };
+}
+
+/// Order earlier RAM writes before later device register writes, so a
+/// doorbell never rings before the rings it announces are visible
+#[inline(always)]
+pub fn io_wmb() {
+    unsafe { asm!("fence w, o", options(nostack)) };
+}
+
+/// Order earlier device register reads before later RAM reads, so data
+/// the device wrote isn't read before the status saying it is there
+#[inline(always)]
+pub fn io_rmb() {
+    unsafe { asm!("fence i, r", options(nostack)) };
+}
+
+/// Full barrier over RAM and device accesses
+#[inline(always)]
+pub fn mb() {
+    unsafe { asm!("fence iorw, iorw", options(nostack)) };
+}
+
+/// Descriptors are being filled in. The only way on is [`publish`],
+/// which fences them before the index that hands them to the device.
+///
+/// [`publish`]: DmaWriteGuard::publish
+#[must_use = "descriptors are never handed to the device unless published"]
+pub struct DmaWriteGuard(());
+
+impl DmaWriteGuard {
+    pub fn new() -> Self {
+        Self(())
+    }
+
+    /// Fence the descriptor writes, then run `publish` to update the index
+    /// the device reads
+    pub fn publish(self, publish: impl FnOnce()) -> Published {
+        dma_wmb();
+        publish();
+        Published(())
+    }
+}
+
+impl Default for DmaWriteGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The index has been published. Notifying the device is optional; some
+/// devices poll, or have said they don't want to be notified.
+pub struct Published(());
+
+impl Published {
+    /// Fence the published index, then run `notify` to ring the doorbell,
+    /// handing back what it returns
+    pub fn notify<T>(self, notify: impl FnOnce() -> T) -> T {
+        io_wmb();
+        notify()
+    }
+}
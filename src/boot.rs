@@ -0,0 +1,138 @@
+//! # Boot report
+//!
+//! `kmain` brings subsystems up one after another. Most of them the kernel
+//! can do without, so instead of stopping at the first one that fails it
+//! records each outcome here and carries on, and once logging is up the
+//! report says what came up and what didn't. Subsystems the kernel can't
+//! run without go through [`BootReport::fatal`], which panics, leaving
+//! the heartbeat blinking the failed subsystem's position in the report.
+
+use crate::gpio::GpioError;
+use crate::heartbeat::{self, Pattern};
+use crate::i2c::I2cError;
+use crate::mmio::MmioError;
+use crate::page::PageError;
+use crate::panic_code::PanicCode;
+
+/// Subsystems a report has room for
+const MAX_SUBSYSTEMS: usize = 16;
+
+/// Why a subsystem failed to come up
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum InitError {
+    Mmio(MmioError),
+    Page(PageError),
+    Gpio(GpioError),
+    I2c(I2cError),
+}
+
+impl From<MmioError> for InitError {
+    fn from(error: MmioError) -> Self {
+        Self::Mmio(error)
+    }
+}
+
+impl From<PageError> for InitError {
+    fn from(error: PageError) -> Self {
+        Self::Page(error)
+    }
+}
+
+impl From<GpioError> for InitError {
+    fn from(error: GpioError) -> Self {
+        Self::Gpio(error)
+    }
+}
+
+impl From<I2cError> for InitError {
+    fn from(error: I2cError) -> Self {
+        Self::I2c(error)
+    }
+}
+
+/// How each subsystem's bring-up went, in the order they were brought up
+pub struct BootReport {
+    outcomes: [Option<(&'static str, Result<(), InitError>)>; MAX_SUBSYSTEMS],
+    len: usize,
+}
+
+impl BootReport {
+    pub const fn new() -> Self {
+        Self {
+            outcomes: [None; MAX_SUBSYSTEMS],
+            len: 0,
+        }
+    }
+
+    /// Record how bringing up `name` went, handing back its value if it
+    /// came up
+    pub fn record<T, E: Into<InitError>>(
+        &mut self,
+        name: &'static str,
+        result: Result<T, E>,
+    ) -> Option<T> {
+        let (outcome, value) = match result {
+            Ok(value) => (Ok(()), Some(value)),
+            Err(error) => (Err(error.into()), None),
+        };
+        if let Some(slot) = self.outcomes.get_mut(self.len) {
+            *slot = Some((name, outcome));
+            self.len += 1;
+        }
+        value
+    }
+
+    /// Like [`record`](Self::record), for subsystems the kernel can't boot
+    /// without: a failure panics
+    pub fn fatal<T, E: Into<InitError>>(&mut self, name: &'static str, result: Result<T, E>) -> T {
+        match result.map_err(Into::into) {
+            Ok(value) => {
+                self.record::<_, InitError>(name, Ok(()));
+                value
+            }
+            Err(error) => {
+                // Counted from one, in the order subsystems came up
+                let position = u8::try_from(self.len + 1).unwrap_or(u8::MAX);
+                heartbeat::set(Pattern::BootFailure(position));
+                let code = match error {
+                    InitError::Page(PageError::OutOfMemory) => PanicCode::OutOfMemory,
+                    _ => PanicCode::Explicit,
+                };
+                panic_with!(code, "{} failed to come up: {:?}", name, error)
+            }
+        }
+    }
+
+    /// Subsystems that failed
+    pub fn failures(&self) -> usize {
+        self.outcomes()
+            .filter(|(_, outcome)| outcome.is_err())
+            .count()
+    }
+
+    fn outcomes(&self) -> impl Iterator<Item = &(&'static str, Result<(), InitError>)> {
+        self.outcomes[..self.len].iter().flatten()
+    }
+
+    /// Log every subsystem's outcome and a summary
+    pub fn log(&self) {
+        for (name, outcome) in self.outcomes() {
+            match outcome {
+                Ok(()) => log!(Debug, "boot: {} up", name),
+                Err(error) => log!(Warn, "boot: {} failed: {:?}", name, error),
+            }
+        }
+        log!(
+            Info,
+            "boot: {} of {} subsystems up",
+            self.len - self.failures(),
+            self.len
+        );
+    }
+}
+
+impl Default for BootReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -3,6 +3,9 @@
 # Disable generation of compressed instructions.
 .option norvc
 
+# Harts with a slot in HART_STACKS, ipi::QUEUED_HARTS
+.equ	HART_SLOTS, 8
+
 # Define a .data section.
 .section .data
 
@@ -23,9 +26,19 @@ _start:
 	la		gp, _global_pointer
 .option pop
 
+	# QEMU passes the address of the device tree in a1. The BSS clearing
+	# below uses a0 and a1, so keep it in s1 until we hand it to kmain.
+	mv		s1, a1
+
 	# SATP should be zero, but let's make sure. Each HART has its own
-	# SATP register.
+	# SATP register. Harts without supervisor mode (like the E51 monitor
+	# core sifive_u starts us on) don't have one, so check misa for 'S'.
+	csrr	t0, misa
+	li		t1, 1 << ('S' - 'A')
+	and		t0, t0, t1
+	beqz	t0, 5f
 	csrw	satp, zero
+5:
 	# Any hardware threads (hart) that are not bootstrapping
 	# need to wait for an IPI
 	csrr	t0, mhartid
@@ -66,6 +79,9 @@ _start:
 	li		t3, (1 << 3) | (1 << 7) | (1 << 11)
 	csrw	mie, t3
 
+	# kmain takes the device tree address as its only argument.
+	mv		a0, s1
+
 	# Set the return address to infinitely wait for interrupts.
 	la		ra, 4f
 	# We use mret here so that the mstatus register is properly updated.
@@ -73,20 +89,74 @@ _start:
 	mret
 
 3:
-	# Parked harts go here. We need to set these
-	# to only awaken if it receives a software interrupt,
-	# which we're going to call the SIPI (Software Intra-Processor Interrupt).
-	# We only use these to run user-space programs, although this may
-	# change.
+	# Parked harts go here, woken only by a software interrupt from the
+	# boot hart (see smp.rs). MIE stays off, so the interrupt ends the wfi
+	# without trapping. Harts past the slots of HART_STACKS stay parked.
+	li		t1, HART_SLOTS
+	bgeu	t0, t1, 4f
+	li		t1, 1 << 3
+	csrw	mie, t1
+	la		t1, HART_STACKS
+	slli	t2, t0, 3
+	add		t1, t1, t2
+6:
+	ld		sp, (t1)
+	bnez	sp, 7f
+	wfi
+	j		6b
+7:
+	# Our stack is set; see the stacks it was published with
+	fence	r, rw
+	la		t2, asm_trap_vector
+	csrw	mtvec, t2
+	mv		a0, t0
+	call	kmain_hart
 
 4:
 	wfi
 	j		4b
 
-# In the future our trap vector will go here.
+# Every trap comes here. mscratch holds the top of this hart's trap
+# stack, or zero while it has none, in which case the trap runs on the
+# interrupted stack. The interrupted registers are saved in a TrapFrame
+# (see trap.rs) below the stack pointer and handed to handle_trap, then
+# put back for mret.
+
+.equ	TRAP_FRAME_SIZE, 32 * 8
 
 .global asm_trap_vector
-# This will be our trap vector when we start
-# handling interrupts.
+.align 4
 asm_trap_vector:
+	csrrw	sp, mscratch, sp
+	bnez	sp, 1f
+
+	# No trap stack yet: back onto the interrupted one, leaving mscratch zero
+	csrrw	sp, mscratch, sp
+	addi	sp, sp, -TRAP_FRAME_SIZE
+	sd		t0, 5 * 8(sp)
+	addi	t0, sp, TRAP_FRAME_SIZE
+	j		2f
+
+1:
+	# On the trap stack, with the interrupted sp in mscratch. The stack's
+	# top goes back in mscratch for the next trap.
+	addi	sp, sp, -TRAP_FRAME_SIZE
+	sd		t0, 5 * 8(sp)
+	addi	t0, sp, TRAP_FRAME_SIZE
+	csrrw	t0, mscratch, t0
+
+2:
+	# t0 holds the interrupted sp
+	sd		t0, 2 * 8(sp)
+	.irp	n, 1, 3, 4, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+	sd		x\n, \n * 8(sp)
+	.endr
+
+	mv		a0, sp
+	call	handle_trap
+
+	.irp	n, 1, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+	ld		x\n, \n * 8(sp)
+	.endr
+	ld		sp, 2 * 8(sp)
 	mret
@@ -0,0 +1,50 @@
+//! # Build info
+//!
+//! What this kernel was built from, filled in by `build.rs`, and what its
+//! image costs, read off the layout at run time. Both go in the boot log
+//! so logs from different builds can be told apart and compared.
+
+use crate::{features, layout};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short hash of the commit built, `-dirty` if the tree had changes
+pub const COMMIT: &str = env!("BUILD_COMMIT");
+
+/// When the kernel was built, as an ISO 8601 UTC timestamp
+pub const BUILD_TIME: &str = env!("BUILD_TIME");
+
+/// Sizes of the sections of the kernel image, in bytes
+pub fn section_sizes() -> [(&'static str, usize); 4] {
+    [
+        ("text", layout::text_range().len()),
+        ("rodata", layout::rodata_range().len()),
+        ("data", layout::data_range().len()),
+        ("bss", layout::bss_range().len()),
+    ]
+}
+
+/// Log the build info and section sizes
+pub fn log() {
+    log!(
+        Info,
+        "build: os {} ({}) built {} with {}",
+        VERSION,
+        COMMIT,
+        BUILD_TIME,
+        features::Enabled
+    );
+    let [text, rodata, data, bss] = section_sizes();
+    log!(
+        Info,
+        "build: {} {}, {} {}, {} {}, {} {} bytes",
+        text.0,
+        text.1,
+        rodata.0,
+        rodata.1,
+        data.0,
+        data.1,
+        bss.0,
+        bss.1
+    );
+}
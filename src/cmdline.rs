@@ -0,0 +1,40 @@
+//! # Kernel command line
+//!
+//! The `bootargs` of the device tree's `/chosen` node (QEMU's `-append`),
+//! read as space separated `key=value` options or bare flags.
+
+use crate::fdt::Fdt;
+use crate::globals;
+
+/// Read the command line out of the device tree. Without one the command
+/// line is empty.
+pub fn init(fdt: Option<&Fdt>) {
+    let bootargs = fdt
+        .and_then(|fdt| fdt.find_node("/chosen"))
+        .and_then(|chosen| chosen.property_str("bootargs"))
+        .unwrap_or("");
+
+    globals::CMDLINE.init(bootargs);
+}
+
+/// The whole command line
+pub fn raw() -> &'static str {
+    globals::CMDLINE.get()
+}
+
+/// The value of the last `key=value` option. A bare `key` gives an empty value.
+pub fn get(key: &str) -> Option<&'static str> {
+    raw()
+        .split_ascii_whitespace()
+        .rev()
+        .find_map(|option| match option.split_once('=') {
+            Some((k, value)) if k == key => Some(value),
+            None if option == key => Some(""),
+            _ => None,
+        })
+}
+
+/// Whether `flag` appears on the command line, either bare or with a value
+pub fn has(flag: &str) -> bool {
+    get(flag).is_some()
+}
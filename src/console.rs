@@ -0,0 +1,353 @@
+//! # Console
+//!
+//! `print!` writes to whichever [`ConsoleSink`] was picked at boot with
+//! `console=` on the command line:
+//!
+//! - `console=serial` (the default) goes to the platform's UART
+//! - `console=null` throws everything away, so benchmarks don't pay for
+//!   serial I/O
+//! - `console=mem` captures output into a bounded buffer, for headless
+//!   runs that inspect what the kernel printed
+//!
+//! Until a sink is picked output goes to the serial console, which drops
+//! it until `init` has claimed the UART.
+//!
+//! Input always comes from the UART, whichever sink output goes to. It
+//! is read in one of two [`TerminalMode`]s. Cooked mode hands out
+//! whole lines, echoed and editable with backspace as they are typed; raw
+//! mode hands out each byte as it arrives, with no echo, for programs that
+//! handle their own input. Unread bytes stay in the UART's FIFO, so
+//! switching modes mid-stream loses nothing.
+
+use core::fmt::{Error, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+pub use oslib::console::{ConsoleSink, MemConsole, NullConsole, OutputBuffer};
+
+use crate::mmio::DeviceMemory;
+use crate::platform::{self, UartKind};
+use crate::sifive_uart::{self, SifiveUart};
+use crate::uart::{self, Uart};
+use crate::utils::utf8::{self, Utf8Decoder};
+use crate::{cmdline, globals, heartbeat};
+
+/// The UART driving the serial console
+pub enum SerialPort {
+    Ns16550a(Uart),
+    Sifive(SifiveUart),
+}
+
+impl SerialPort {
+    /// Claim and initialise the UART named by the platform profile
+    fn init() -> Self {
+        let platform = platform::current();
+        let base = platform.console_base;
+        let claim = |size| {
+            DeviceMemory::claim("console", base, size).expect("couldn't claim the console UART")
+        };
+
+        match platform.console {
+            UartKind::Ns16550a => {
+                let mut uart = Uart::new(claim(uart::MMIO_SIZE));
+                uart.init();
+                Self::Ns16550a(uart)
+            }
+            UartKind::Sifive => {
+                let mut uart = SifiveUart::new(claim(sifive_uart::MMIO_SIZE));
+                uart.init();
+                Self::Sifive(uart)
+            }
+        }
+    }
+
+    fn get(&self) -> Option<u8> {
+        match self {
+            Self::Ns16550a(uart) => uart.get(),
+            Self::Sifive(uart) => uart.get(),
+        }
+    }
+
+    fn write_bytes(&self, bytes: &[u8]) {
+        match self {
+            Self::Ns16550a(uart) => uart.write_bytes(bytes),
+            Self::Sifive(uart) => bytes.iter().for_each(|&c| uart.put(c)),
+        }
+    }
+
+    fn flush(&self) {
+        match self {
+            Self::Ns16550a(uart) => uart.flush(),
+            // Its registers only say whether the TX FIFO is full, never
+            // whether it has drained
+            Self::Sifive(_) => {}
+        }
+    }
+}
+
+/// Bytes of serial output held back before they are written out
+pub const SERIAL_BUFFER_SIZE: usize = 2 * 1024;
+
+static SERIAL_BUFFER: Mutex<OutputBuffer<SERIAL_BUFFER_SIZE>> = Mutex::new(OutputBuffer::new());
+
+/// Write straight to the UART. Output before `console::init` has claimed
+/// it is dropped.
+fn write_serial(bytes: &[u8]) {
+    if let Some(port) = globals::SERIAL_PORT.try_get() {
+        port.write_bytes(bytes);
+    }
+}
+
+/// The UART named by the platform profile, buffered: output collects
+/// until a newline, the buffer's watermark or a drain, then goes out in
+/// one go so the UART can be fed a FIFO at a time. Partial lines, like a
+/// prompt, are drained before the console waits for input.
+pub struct SerialConsole;
+
+impl SerialConsole {
+    /// Write out whatever is buffered ahead of a panic report, which goes
+    /// to [`EARLYCON`]. If the panic came from inside the buffer's lock
+    /// what is buffered is lost rather than printed out of order.
+    pub fn drain_for_panic(&self) {
+        if let Some(mut buffer) = SERIAL_BUFFER.try_lock() {
+            buffer.drain(write_serial);
+        }
+    }
+}
+
+impl ConsoleSink for SerialConsole {
+    fn write_bytes(&self, bytes: &[u8]) {
+        SERIAL_BUFFER.lock().write(bytes, write_serial);
+    }
+
+    fn drain(&self) {
+        SERIAL_BUFFER.lock().drain(write_serial);
+    }
+
+    fn flush(&self) {
+        self.drain();
+        if let Some(port) = globals::SERIAL_PORT.try_get() {
+            port.flush();
+        }
+    }
+}
+
+/// The UART without any buffering, for panics and anything else that
+/// can't rely on a later drain
+pub struct EarlyConsole;
+
+impl ConsoleSink for EarlyConsole {
+    fn write_bytes(&self, bytes: &[u8]) {
+        write_serial(bytes);
+    }
+}
+
+pub static SERIAL: SerialConsole = SerialConsole;
+pub static EARLYCON: EarlyConsole = EarlyConsole;
+pub static NULL: NullConsole = NullConsole;
+pub static MEM: MemConsole<MEM_CONSOLE_SIZE> = MemConsole::new();
+
+/// Bytes `console=mem` captures before dropping output
+pub const MEM_CONSOLE_SIZE: usize = 16 * 1024;
+
+/// Claim and initialise the serial console and pick the sink `print!` writes to
+pub fn init() {
+    globals::SERIAL_PORT.init(SerialPort::init());
+
+    let sink: &'static dyn ConsoleSink = match cmdline::get("console") {
+        Some("null") => &NULL,
+        Some("mem") => &MEM,
+        _ => &SERIAL,
+    };
+    globals::CONSOLE.init(sink);
+}
+
+/// The sink console output currently goes to
+pub fn sink() -> &'static dyn ConsoleSink {
+    globals::CONSOLE.try_get().copied().unwrap_or(&SERIAL)
+}
+
+/// Write `args` to the console, as `print!` does. Buffering sinks hold
+/// it back until the line ends.
+pub fn print(args: core::fmt::Arguments) {
+    let _ = Console.write_fmt(args);
+}
+
+/// Wait until console output written so far has gone out
+pub fn flush() {
+    sink().flush();
+}
+
+/// Read a byte of input, if one is waiting. Input always comes from the
+/// serial port, wherever output goes. Output held back is drained first,
+/// so a prompt shows before anyone waits for an answer to it.
+pub fn get_byte() -> Option<u8> {
+    sink().drain();
+    globals::SERIAL_PORT.try_get()?.get()
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TerminalMode {
+    /// Line at a time, echoed and editable
+    Cooked,
+    /// Byte at a time, no echo
+    Raw,
+}
+
+static RAW: AtomicBool = AtomicBool::new(false);
+static ECHO: AtomicBool = AtomicBool::new(true);
+
+pub fn mode() -> TerminalMode {
+    if RAW.load(Ordering::Relaxed) {
+        TerminalMode::Raw
+    } else {
+        TerminalMode::Cooked
+    }
+}
+
+pub fn set_mode(mode: TerminalMode) {
+    RAW.store(mode == TerminalMode::Raw, Ordering::Relaxed);
+}
+
+/// Turn echo of cooked input on or off, say while a password is typed.
+/// Raw input is never echoed.
+pub fn set_echo(on: bool) {
+    ECHO.store(on, Ordering::Relaxed);
+}
+
+/// What [`read_line_edited`] read
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LineRead {
+    /// Bytes stored in the buffer
+    pub len: usize,
+    /// Input arrived that didn't fit in the buffer and was thrown away
+    pub truncated: bool,
+    /// The line was ended with Ctrl-D rather than a newline: there is
+    /// no more input to come
+    pub end: bool,
+}
+
+/// Wait for input and read it into `buf`.
+///
+/// In cooked mode this is a whole line, without its terminator, edited
+/// with backspace as it is typed. Input is decoded as UTF-8 and stored
+/// a character at a time, with invalid sequences stored as
+/// [`utf8::REPLACEMENT`], so `buf[..len]` is always valid UTF-8 and
+/// backspace takes back a whole character. A character that doesn't fit
+/// in what is left of `buf` is dropped and reported as truncation.
+/// Ctrl-D ends the line and the input. Escape sequences, like the ones
+/// arrow keys send, and other control characters are ignored. In raw
+/// mode it is the next byte, undecoded.
+pub fn read_line_edited(buf: &mut [u8]) -> LineRead {
+    let mut decoder = Utf8Decoder::new();
+    let mut escape = Escape::None;
+    let mut len = 0;
+    let mut truncated = false;
+    loop {
+        let Some(byte) = get_byte() else {
+            heartbeat::poll();
+            core::hint::spin_loop();
+            continue;
+        };
+
+        if mode() == TerminalMode::Raw {
+            return match buf.first_mut() {
+                Some(first) => {
+                    *first = byte;
+                    LineRead {
+                        len: 1,
+                        truncated: false,
+                        end: false,
+                    }
+                }
+                None => LineRead {
+                    len: 0,
+                    truncated: true,
+                    end: false,
+                },
+            };
+        }
+
+        let echo = ECHO.load(Ordering::Relaxed);
+        for c in decoder.push(byte).into_iter().flatten() {
+            let in_escape = escape != Escape::None;
+            escape = match (escape, c) {
+                (Escape::None, '\u{1b}') => Escape::Started,
+                (Escape::Started, '[') => Escape::Csi,
+                // A CSI sequence ends with its final byte
+                (Escape::Csi, '\u{40}'..='\u{7e}') => Escape::None,
+                (Escape::Csi, _) => Escape::Csi,
+                (Escape::Started, _) => Escape::None,
+                (Escape::None, _) => Escape::None,
+            };
+            if in_escape || escape != Escape::None {
+                continue;
+            }
+
+            match c {
+                '\r' | '\n' | '\u{4}' => {
+                    if echo {
+                        print!("\r\n");
+                    }
+                    return LineRead {
+                        len,
+                        truncated,
+                        end: c == '\u{4}',
+                    };
+                }
+                // backspace and delete
+                '\u{8}' | '\u{7f}' => {
+                    if len > 0 {
+                        len = utf8::last_char_start(&buf[..len]);
+                        if echo {
+                            print!("{}{}{}", 8 as char, ' ', 8 as char);
+                        }
+                    }
+                }
+                _ if c.is_control() => {}
+                _ => {
+                    let end = len + c.len_utf8();
+                    if end <= buf.len() {
+                        c.encode_utf8(&mut buf[len..end]);
+                        len = end;
+                        if echo {
+                            print!("{}", c);
+                        }
+                    } else {
+                        truncated = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How far into an escape sequence cooked input is
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// An escape, and nothing after it yet
+    Started,
+    /// A control sequence introducer, escape then `[`
+    Csi,
+}
+
+/// `core::fmt::Write` adaptor for a particular sink
+pub struct Writer<'a>(pub &'a dyn ConsoleSink);
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        self.0.write_bytes(out.as_bytes());
+        Ok(())
+    }
+}
+
+/// Writer handed to `write!` by the print macros
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        Writer(sink()).write_str(out)
+    }
+}
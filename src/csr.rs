@@ -0,0 +1,162 @@
+//! # Control and status registers
+//!
+//! Every CSR the kernel touches gets a module here with `read`, and for
+//! writable ones `write`, `set_bits` and `clear_bits`, so no other code
+//! writes `csrr`/`csrw` by hand. Registers with fields get a typed view
+//! from [`oslib::csr`], whose encoding and decoding are plain functions
+//! kept apart from the instructions that move the bits.
+//!
+//! The kernel runs in M-mode, so it is the machine registers that matter;
+//! `satp` is here for the page tables the kernel builds.
+
+pub use oslib::csr::{
+    Cause, Exception, Interrupt, Mcause, Mstatus, PmpLockout, Privilege, Satp, SatpMode,
+};
+
+macro_rules! csr {
+    ($(#[$doc:meta])* $name:ident, read_only) => {
+        $(#[$doc])*
+        pub mod $name {
+            #[inline(always)]
+            pub fn read() -> usize {
+                let value: usize;
+                unsafe {
+                    core::arch::asm!(concat!("csrr {}, ", stringify!($name)), out(reg) value)
+                };
+                value
+            }
+        }
+    };
+    ($(#[$doc:meta])* $name:ident) => {
+        $(#[$doc])*
+        pub mod $name {
+            #[inline(always)]
+            pub fn read() -> usize {
+                let value: usize;
+                unsafe {
+                    core::arch::asm!(concat!("csrr {}, ", stringify!($name)), out(reg) value)
+                };
+                value
+            }
+
+            #[inline(always)]
+            pub fn write(value: usize) {
+                unsafe { core::arch::asm!(concat!("csrw ", stringify!($name), ", {}"), in(reg) value) };
+            }
+
+            /// Set the bits of `mask`, leaving the rest alone
+            #[inline(always)]
+            pub fn set_bits(mask: usize) {
+                unsafe { core::arch::asm!(concat!("csrs ", stringify!($name), ", {}"), in(reg) mask) };
+            }
+
+            /// Clear the bits of `mask`, leaving the rest alone
+            #[inline(always)]
+            pub fn clear_bits(mask: usize) {
+                unsafe { core::arch::asm!(concat!("csrc ", stringify!($name), ", {}"), in(reg) mask) };
+            }
+        }
+    };
+}
+
+csr!(
+    /// Extensions the hart implements, one bit per letter
+    misa,
+    read_only
+);
+csr!(
+    /// The hart's ID
+    mhartid,
+    read_only
+);
+csr!(
+    /// Machine status, see [`Mstatus`](super::Mstatus)
+    mstatus
+);
+csr!(
+    /// Which interrupts may be taken in M-mode
+    mie
+);
+csr!(
+    /// Which interrupts are pending
+    mip
+);
+csr!(
+    /// M-mode trap vector
+    mtvec
+);
+csr!(
+    /// Scratch register for the M-mode trap handler
+    mscratch
+);
+csr!(
+    /// Where the last M-mode trap was taken
+    mepc
+);
+csr!(
+    /// Why the last M-mode trap was taken, see [`Mcause`](super::Mcause)
+    mcause
+);
+csr!(
+    /// Address or instruction the last M-mode trap was about
+    mtval
+);
+csr!(
+    /// Exceptions handed to S-mode
+    medeleg
+);
+csr!(
+    /// Interrupts handed to S-mode
+    mideleg
+);
+csr!(
+    /// Supervisor address translation, see [`Satp`](super::Satp)
+    satp
+);
+csr!(
+    /// Configuration of PMP entries 0 to 7, a byte each
+    pmpcfg0
+);
+csr!(
+    /// Address of PMP entry 0, see [`PmpLockout`](super::PmpLockout)
+    pmpaddr0
+);
+csr!(
+    /// Address of PMP entry 1, see [`PmpLockout`](super::PmpLockout)
+    pmpaddr1
+);
+
+/// Whether `misa` lists the extension `letter`
+pub fn has_extension(letter: u8) -> bool {
+    debug_assert!(letter.is_ascii_uppercase());
+    misa::read() & (1 << (letter - b'A')) != 0
+}
+
+/// Mask M-mode interrupts, returning whether they were enabled
+pub fn disable_interrupts() -> bool {
+    let was = mstatus::read() & Mstatus::MIE != 0;
+    mstatus::clear_bits(Mstatus::MIE);
+    was
+}
+
+/// Install `lockout` as PMP entry `entry`, 0 or 1. Returns whether
+/// the hart took it; one without the entry, or with it already locked,
+/// ignores the writes.
+pub fn write_lockout(lockout: PmpLockout, entry: usize) -> bool {
+    debug_assert!(lockout.is_napot(), "{:#x?} isn't a NAPOT region", lockout);
+    // The address goes in before the entry is locked
+    let addr = match entry {
+        0 => {
+            pmpaddr0::write(lockout.addr_bits());
+            pmpaddr0::read()
+        }
+        1 => {
+            pmpaddr1::write(lockout.addr_bits());
+            pmpaddr1::read()
+        }
+        _ => return false,
+    };
+    let shift = entry * 8;
+    pmpcfg0::set_bits(PmpLockout::CFG << shift);
+    addr == lockout.addr_bits() && (pmpcfg0::read() >> shift) & 0xff == PmpLockout::CFG
+}
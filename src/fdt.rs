@@ -0,0 +1,7 @@
+//! # Flattened device tree
+//!
+//! The reader lives in [`oslib::fdt`] so it can be tested against
+//! synthetic blobs on the host; the kernel only ever reads the blob QEMU
+//! hands it in `a1`, through [`Fdt::from_addr`].
+
+pub use oslib::fdt::{Fdt, FdtError, Node, Nodes, Properties, Property};
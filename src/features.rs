@@ -0,0 +1,44 @@
+//! # Build features
+//!
+//! The optional parts of the kernel are Cargo features. This is the one
+//! place that lists them, so the boot log can say what a kernel was built
+//! with.
+//!
+//! None of them depends on another, so every combination builds and there
+//! is nothing to reject. A feature that comes to need another should
+//! enable it in Cargo.toml, as `debug_checks` does its oslib counterpart,
+//! rather than fail the build with a `compile_error!`.
+//!
+//! - `virtio` (default): the virtio-mmio transport
+//! - `debug_checks`: self-checks in the paging code, debug builds only
+//! - `page_poison`: poison and quarantine freed frames
+
+use core::fmt;
+
+/// Names of the features this kernel was built with
+pub const ENABLED: &[&str] = &[
+    #[cfg(feature = "virtio")]
+    "virtio",
+    #[cfg(feature = "debug_checks")]
+    "debug_checks",
+    #[cfg(feature = "page_poison")]
+    "page_poison",
+];
+
+/// Formats [`ENABLED`] as a space separated list
+pub struct Enabled;
+
+impl fmt::Display for Enabled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if ENABLED.is_empty() {
+            return write!(f, "no optional features");
+        }
+        for (i, feature) in ENABLED.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", feature)?;
+        }
+        Ok(())
+    }
+}
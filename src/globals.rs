@@ -0,0 +1,92 @@
+//! # Kernel globals
+//!
+//! State that is set up once during boot and then shared between
+//! subsystems: the device tree, the platform, the devices claimed and the
+//! memory managers. Each is behind a [`Global`] that is written exactly
+//! once. Reading one before its owner has initialized it panics with its
+//! name instead of handing back whatever happened to be in memory.
+//!
+//! State a module keeps to itself, and that is usable from the start
+//! without initializing, stays in that module as a plain static: locks
+//! like the frame allocator's, atomics like the IPI work queues and the
+//! interrupt counters, and buffers like the console's.
+//!
+//! They are listed in the order `kmain` initializes them.
+
+use core::ops::Range;
+
+use spin::Mutex;
+
+pub use oslib::global::Global;
+
+use crate::asid::AsidAllocator;
+use crate::console::{ConsoleSink, SerialPort};
+use crate::fdt::Fdt;
+use crate::layout::Layout;
+use crate::memmap::MemoryMap;
+use crate::mmio::DeviceMemory;
+use crate::page::{PageRefCount, PageSystem};
+use crate::platform::{Environment, Platform};
+use crate::plic::Plic;
+use crate::reserved_memory::Carveouts;
+use crate::sifive_gpio::SifiveGpio;
+use crate::sifive_i2c::SifiveI2c;
+
+/// The device tree passed in at boot, if there was a valid one. Set by `kmain`.
+pub static FDT: Global<Fdt> = Global::new("device tree");
+
+/// The `bootargs` of the device tree. Set by `cmdline::init`.
+pub static CMDLINE: Global<&'static str> = Global::new("kernel command line");
+
+/// Whether we are running under QEMU. Set by `platform::init`.
+pub static ENVIRONMENT: Global<Environment> = Global::new("environment");
+
+/// The machine profile. Set by `platform::init`.
+pub static PLATFORM: Global<&'static Platform> = Global::new("platform profile");
+
+/// The console UART. Set by `console::init`.
+pub static SERIAL_PORT: Global<SerialPort> = Global::new("serial port");
+
+/// Where `print!` output goes. Set by `console::init`.
+pub static CONSOLE: Global<&'static dyn ConsoleSink> = Global::new("console sink");
+
+/// Where the linker put the kernel image. Set by `layout::init`.
+pub static LAYOUT: Global<Layout> = Global::new("kernel layout");
+
+/// The CLINT's registers. Set by `time::init`.
+pub static CLINT: Global<DeviceMemory> = Global::new("CLINT");
+
+/// The interrupt controller. Set by `plic::init`.
+pub static PLIC: Global<Plic> = Global::new("PLIC");
+
+/// Frequency of `mtime` in Hz. Set by `time::init`.
+pub static TIMEBASE_FREQUENCY: Global<u64> = Global::new("timebase frequency");
+
+/// The GPIO controller, if the machine has one. Set by `gpio::init`.
+pub static GPIO: Global<SifiveGpio> = Global::new("GPIO controller");
+
+/// The GPIO pin the heartbeat LED is on, if `heartbeat=` gave one. Set by
+/// `heartbeat::init`.
+pub static HEARTBEAT_PIN: Global<u32> = Global::new("heartbeat pin");
+
+/// The I2C controller, if the machine has one. Set by `i2c::init`.
+pub static I2C: Global<SifiveI2c> = Global::new("I2C controller");
+
+/// Carveouts from the device tree's `/reserved-memory`. Set by
+/// `reserved_memory::init`, from `page::init`.
+pub static CARVEOUTS: Global<Carveouts> = Global::new("reserved memory carveouts");
+
+/// Physical frames the frame allocator hands out. Set by `page::init`.
+pub static FRAME_REGION: Global<Range<usize>> = Global::new("frame allocator region");
+
+/// Reference counts of every frame of RAM. Set by `page::init`.
+pub static PAGE_REF_COUNT: Global<PageRefCount> = Global::new("page reference count table");
+
+/// Physical memory user mappings may not point at. Set by `page::init`.
+pub static KERNEL_OWNED_MEMORY: Global<MemoryMap> = Global::new("kernel owned memory");
+
+/// The kernel's own address space. Set by `kmain` after `init_paging_system`.
+pub static KERNEL_PAGES: Global<Mutex<PageSystem>> = Global::new("kernel page table");
+
+/// Hands out ASIDs to address spaces. Set by `asid::init`.
+pub static ASIDS: Global<Mutex<AsidAllocator>> = Global::new("ASID allocator");
@@ -0,0 +1,45 @@
+//! # GPIO
+//!
+//! What the kernel needs from a GPIO controller, so code driving pins
+//! doesn't care whose controller they are on. The only controller so far
+//! is the SiFive one on sifive_u, found through the device tree by
+//! [`init`].
+
+pub use oslib::gpio::{Direction, Edge, GpioController, GpioError};
+
+use crate::fdt::Fdt;
+use crate::globals;
+use crate::plic::{self, RouteError};
+use crate::sifive_gpio::{self, SifiveGpio};
+
+/// Bring up the first GPIO controller the device tree describes. A
+/// machine without one isn't an error.
+pub fn init(fdt: Option<&Fdt>) -> Result<Option<&'static SifiveGpio>, GpioError> {
+    let Some((soc, node)) = fdt.and_then(|fdt| {
+        let soc = fdt.find_node("/soc")?;
+        let node = soc
+            .children()
+            .find(|node| node.is_compatible(sifive_gpio::COMPATIBLE))?;
+        Some((soc, node))
+    }) else {
+        return Ok(None);
+    };
+
+    let gpio = sifive_gpio::from_node(&node, &soc)?;
+    Ok(Some(globals::GPIO.init(gpio)))
+}
+
+/// Route the interrupt for `edge` of `pin` through the PLIC to this hart.
+/// Until something handles external interrupts the pin is left with its
+/// interrupt disabled and [`GpioError::Unhandled`] returned.
+pub fn enable_interrupt(gpio: &dyn GpioController, pin: u32, edge: Edge) -> Result<(), GpioError> {
+    let irq = gpio.enable_interrupt(pin, edge)?;
+    if let Err(error) = plic::route(irq) {
+        gpio.disable_interrupt(pin)?;
+        return Err(match error {
+            RouteError::NoPlic => GpioError::NoPlic,
+            RouteError::Unhandled => GpioError::Unhandled(irq),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! # Heartbeat
+//!
+//! An LED on a GPIO pin that blinks a [`Pattern`] saying what state the
+//! kernel is in, which can be read off a board with no console at all.
+//! Booting with `heartbeat=<pin>` picks the pin; without it there is no
+//! heartbeat.
+//!
+//! Nothing drives it from a timer interrupt yet, so the LED only changes
+//! while something calls [`poll`]: the console while it waits for input,
+//! and the halt loop after a panic.
+
+use spin::Mutex;
+
+pub use oslib::heartbeat::Pattern;
+
+use crate::gpio::{Direction, GpioController, GpioError};
+use crate::sifive_gpio::SifiveGpio;
+use crate::{abort, cmdline, globals, time};
+
+static PATTERN: Mutex<Pattern> = Mutex::new(Pattern::Healthy);
+
+/// Drive the pin `heartbeat=` names, if any, as an output on `gpio`
+pub fn init(gpio: Option<&SifiveGpio>) -> Result<(), GpioError> {
+    let Some(pin) = cmdline::get("heartbeat").and_then(|pin| pin.parse().ok()) else {
+        return Ok(());
+    };
+    let gpio = gpio.ok_or(GpioError::NoSuchPin(pin))?;
+    gpio.set_direction(pin, Direction::Output)?;
+    globals::HEARTBEAT_PIN.init(pin);
+    poll();
+    Ok(())
+}
+
+/// Show `pattern` from now on
+pub fn set(pattern: Pattern) {
+    *PATTERN.lock() = pattern;
+    poll();
+}
+
+/// Light or darken the LED for where the pattern has got to
+pub fn poll() {
+    let (Some(&pin), Some(gpio)) = (globals::HEARTBEAT_PIN.try_get(), globals::GPIO.try_get())
+    else {
+        return;
+    };
+    // Whoever holds the lock is about to poll with a new pattern
+    let Some(pattern) = PATTERN.try_lock().map(|pattern| *pattern) else {
+        return;
+    };
+    let _ = gpio.set(pin, pattern.is_lit(time::uptime_us() / 1_000));
+}
+
+/// Park the hart for good after a panic, blinking [`Pattern::Panicked`]
+/// unless a boot failure is already showing. Without a heartbeat this is
+/// just [`abort`].
+pub fn halt() -> ! {
+    if globals::HEARTBEAT_PIN.try_get().is_none() {
+        abort();
+    }
+    if let Some(mut pattern) = PATTERN.try_lock() {
+        if *pattern == Pattern::Healthy {
+            *pattern = Pattern::Panicked;
+        }
+    }
+    loop {
+        poll();
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,64 @@
+//! # I2C
+//!
+//! Buses whose devices are addressed by the bus rather than through MMIO.
+//! A controller implements [`I2cBus`]; drivers for the devices hanging off
+//! it, like [`Eeprom`], only ever see the trait, so they work on any
+//! controller or on a mock standing in for one.
+//!
+//! In the device tree a bus's devices are the children of its controller
+//! node, with `reg` holding their bus address. The bus logic and the
+//! EEPROM driver live in oslib, where they are tested against a mock bus.
+
+pub use oslib::i2c::{
+    bus_address, poll_ack, Eeprom, I2cBus, I2cError, EEPROM_WRITE_TIMEOUT_US, TRANSFER_TIMEOUT_US,
+};
+
+use crate::fdt::{Fdt, Node};
+use crate::globals;
+use crate::sifive_i2c::{self, SifiveI2c};
+
+/// A device on a bus, as its device tree node describes it
+#[derive(Copy, Clone)]
+pub struct BusDevice {
+    pub node: Node,
+    /// Bus address from `reg`
+    pub address: u8,
+}
+
+/// The children of the bus controller `bus` that have a 7-bit bus address.
+/// Anything else in `reg` can't be addressed, so the child is skipped.
+pub fn children(bus: &Node) -> impl Iterator<Item = BusDevice> {
+    bus.children().filter_map(|node| {
+        let address = bus_address(node.property_u32("reg")?)?;
+        Some(BusDevice { node, address })
+    })
+}
+
+/// Bring up the first I2C controller the device tree describes and log
+/// the devices on it. A machine without one isn't an error.
+pub fn init(fdt: Option<&Fdt>) -> Result<Option<&'static SifiveI2c>, I2cError> {
+    let Some((soc, node)) = fdt.and_then(|fdt| {
+        let soc = fdt.find_node("/soc")?;
+        let node = soc
+            .children()
+            .find(|node| node.is_compatible(sifive_i2c::COMPATIBLE))?;
+        Some((soc, node))
+    }) else {
+        return Ok(None);
+    };
+
+    let bus = globals::I2C.init(sifive_i2c::from_node(&node, &soc)?);
+    for device in children(&node) {
+        log!(
+            Info,
+            "i2c: {} at {:#04x}",
+            device
+                .node
+                .compatible()
+                .next()
+                .unwrap_or(device.node.name()),
+            device.address
+        );
+    }
+    Ok(Some(bus))
+}
@@ -0,0 +1,91 @@
+//! # Inter-processor interrupts
+//!
+//! Harts signal each other through the CLINT: each has an `msip` register,
+//! and writing 1 to it raises a machine software interrupt on that hart.
+//! What the interrupt is for travels separately, as bits of work queued
+//! for the target, so several requests sent before it gets round to
+//! handling them are all seen.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::mmio::MmioError;
+use crate::{csr, globals, tlb};
+
+/// Offset of hart 0's `msip` register from the CLINT base. Each hart's
+/// register is a 32-bit word after the previous one's.
+pub const MSIP_OFFSET: usize = 0x0000;
+
+/// Harts we keep a work queue for
+pub const QUEUED_HARTS: usize = 8;
+
+/// Work a hart is asked to do by another
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct IpiWork(usize);
+
+impl IpiWork {
+    pub const NONE: Self = Self(0);
+    /// Pick something else to run
+    pub const RESCHEDULE: Self = Self(1 << 0);
+    /// Flush the batch waiting in the hart's `tlb` mailbox
+    pub const TLB_SHOOTDOWN: Self = Self(1 << 1);
+
+    pub const fn bits(self) -> usize {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for IpiWork {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+static PENDING: [AtomicUsize; QUEUED_HARTS] = [const { AtomicUsize::new(0) }; QUEUED_HARTS];
+
+/// Offset of `hart`'s `msip` register from the CLINT base
+pub const fn msip_offset(hart: usize) -> usize {
+    MSIP_OFFSET + hart * 4
+}
+
+/// Queue `work` for `hart` and interrupt it
+pub fn send_ipi(hart: usize, work: IpiWork) -> Result<(), MmioError> {
+    assert!(hart < QUEUED_HARTS, "no work queue for hart {}", hart);
+    PENDING[hart].fetch_or(work.bits(), Ordering::Release);
+    globals::CLINT.get().write32(msip_offset(hart), 1)
+}
+
+/// Acknowledge the software interrupt on `hart` and take the work queued
+/// for it
+pub fn take_ipi(hart: usize) -> Result<IpiWork, MmioError> {
+    // Clear first, so work queued from here on raises a new interrupt
+    globals::CLINT.get().write32(msip_offset(hart), 0)?;
+    let work = match PENDING.get(hart) {
+        Some(pending) => pending.swap(0, Ordering::Acquire),
+        None => 0,
+    };
+    Ok(IpiWork(work))
+}
+
+/// Handle the software interrupt on `hart`, the one we are running on:
+/// acknowledge it and do the work queued for it. The trap handler calls
+/// this when `mcause` says `MachineSoftware`.
+pub fn handle(hart: usize) {
+    let work = match take_ipi(hart) {
+        Ok(work) => work,
+        Err(_) => {
+            // msip stays set, so returning would only trap again
+            csr::mie::clear_bits(1 << csr::Interrupt::MachineSoftware.code());
+            return;
+        }
+    };
+    if work.contains(IpiWork::TLB_SHOOTDOWN) {
+        tlb::handle_shootdown(hart);
+    }
+    // Nothing is scheduled yet, so RESCHEDULE only needs the hart woken
+}
@@ -0,0 +1,177 @@
+//! # Kernel image layout
+//!
+//! The only place linker symbols are read. The symbols in `virt.lds` are
+//! addresses, not variables: only their address may be taken, and reading
+//! through one reads whatever happens to be there. `_heap_size` is an
+//! absolute symbol whose address *is* the size.
+//!
+//! [`init`] turns them into ranges once, checks they are in order and
+//! inside RAM, and keeps them for the accessors below.
+
+use core::ops::Range;
+use core::ptr::addr_of;
+
+use crate::globals;
+use crate::page::PAGE_SIZE;
+
+extern "C" {
+    static _text_start: u8;
+    static _text_end: u8;
+    static _rodata_start: u8;
+    static _rodata_end: u8;
+    static _data_start: u8;
+    static _data_end: u8;
+    static _bss_start: u8;
+    static _bss_end: u8;
+    static _stack_start: u8;
+    static _stack_end: u8;
+    static _memory_start: u8;
+    static _memory_end: u8;
+    static _heap_start: u8;
+    static _heap_size: u8;
+}
+
+/// Where each part of the kernel image ended up
+pub struct Layout {
+    pub text: Range<usize>,
+    pub rodata: Range<usize>,
+    pub data: Range<usize>,
+    pub bss: Range<usize>,
+    pub stack: Range<usize>,
+    /// What is left of RAM after the stack, not yet page aligned
+    pub heap: Range<usize>,
+    /// All of RAM
+    pub memory: Range<usize>,
+}
+
+impl Layout {
+    fn from_symbols() -> Self {
+        let symbol = |start: *const u8, end: *const u8| start as usize..end as usize;
+        let heap_start = addr_of!(_heap_start) as usize;
+        Self {
+            text: symbol(addr_of!(_text_start), addr_of!(_text_end)),
+            rodata: symbol(addr_of!(_rodata_start), addr_of!(_rodata_end)),
+            data: symbol(addr_of!(_data_start), addr_of!(_data_end)),
+            bss: symbol(addr_of!(_bss_start), addr_of!(_bss_end)),
+            stack: symbol(addr_of!(_stack_start), addr_of!(_stack_end)),
+            heap: heap_start..heap_start + addr_of!(_heap_size) as usize,
+            memory: symbol(addr_of!(_memory_start), addr_of!(_memory_end)),
+        }
+    }
+
+    /// The parts of the image by name, lowest first
+    pub fn sections(&self) -> [(&'static str, &Range<usize>); 6] {
+        [
+            ("text", &self.text),
+            ("rodata", &self.rodata),
+            ("data", &self.data),
+            ("bss", &self.bss),
+            ("stack", &self.stack),
+            ("heap", &self.heap),
+        ]
+    }
+
+    /// Panic unless every section is well formed, inside RAM and below
+    /// the next one
+    fn check(&self) {
+        assert!(
+            self.memory.start <= self.memory.end,
+            "memory ends before it starts"
+        );
+
+        // First, so a bad heap gets the message that names both ranges
+        self.check_heap();
+
+        let mut previous_end = self.memory.start;
+        for (name, range) in self.sections() {
+            assert!(range.start <= range.end, "{} ends before it starts", name);
+            assert!(
+                range.start >= previous_end,
+                "{} overlaps the section before it",
+                name
+            );
+            assert!(
+                range.end <= self.memory.end,
+                "{} runs past the end of RAM",
+                name
+            );
+            previous_end = range.end;
+        }
+
+        // Mapped with their own permissions, so they must start a page
+        for (name, range) in [("text", &self.text), ("data", &self.data)] {
+            assert!(range.start % PAGE_SIZE == 0, "{} isn't page aligned", name);
+        }
+    }
+
+    /// Panic unless the heap lies inside RAM and clear of everything the
+    /// kernel already uses. Frames handed out of an overlap would be
+    /// zeroed over live code or data.
+    fn check_heap(&self) {
+        let heap = &self.heap;
+        assert!(
+            self.memory.start <= heap.start && heap.end <= self.memory.end,
+            "heap {:#x}..{:#x} isn't inside memory {:#x}..{:#x}",
+            heap.start,
+            heap.end,
+            self.memory.start,
+            self.memory.end
+        );
+
+        let image = [
+            ("text", &self.text),
+            ("rodata", &self.rodata),
+            ("data", &self.data),
+            ("bss", &self.bss),
+            ("stack", &self.stack),
+        ];
+        for (name, range) in image {
+            assert!(
+                heap.end <= range.start || range.end <= heap.start,
+                "heap {:#x}..{:#x} overlaps {} {:#x}..{:#x}",
+                heap.start,
+                heap.end,
+                name,
+                range.start,
+                range.end
+            );
+        }
+    }
+}
+
+/// Read the linker symbols and check them. Must run before anything asks
+/// for a range.
+pub fn init() -> &'static Layout {
+    let layout = Layout::from_symbols();
+    layout.check();
+    globals::LAYOUT.init(layout)
+}
+
+pub fn text_range() -> Range<usize> {
+    globals::LAYOUT.get().text.clone()
+}
+
+pub fn rodata_range() -> Range<usize> {
+    globals::LAYOUT.get().rodata.clone()
+}
+
+pub fn data_range() -> Range<usize> {
+    globals::LAYOUT.get().data.clone()
+}
+
+pub fn bss_range() -> Range<usize> {
+    globals::LAYOUT.get().bss.clone()
+}
+
+/// The boot hart's stack. It grows down from `end`.
+pub fn stack_range() -> Range<usize> {
+    globals::LAYOUT.get().stack.clone()
+}
+
+pub fn heap_range() -> Range<usize> {
+    globals::LAYOUT.get().heap.clone()
+}
+
+pub fn memory_range() -> Range<usize> {
+    globals::LAYOUT.get().memory.clone()
+}
@@ -0,0 +1,177 @@
+//! # Kernel log
+//!
+//! `log!` records go to every registered [`LogSink`] whose level lets them
+//! through, so each destination can be as chatty as suits it. The console
+//! is registered at boot with the level from `loglevel=` on the command
+//! line, defaulting to `info`.
+//!
+//! A sink that can't take a record right now refuses it instead of
+//! blocking, and the refusal is counted against that sink alone. Panics
+//! don't go through here at all; they write straight to the UART.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::{cmdline, console, time};
+
+/// How many sinks can be registered at once
+pub const MAX_SINKS: usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .into_iter()
+        .find(|level| level.name() == name)
+    }
+}
+
+/// Somewhere log records can go
+pub trait LogSink: Sync {
+    /// Write one record. Return false instead of waiting if the sink can't
+    /// take it now; the record is then dropped and counted.
+    fn write_record(&self, level: Level, timestamp_us: u64, text: fmt::Arguments) -> bool;
+}
+
+#[derive(Copy, Clone)]
+struct Registration {
+    name: &'static str,
+    sink: &'static dyn LogSink,
+    level: Level,
+}
+
+static SINKS: Mutex<[Option<Registration>; MAX_SINKS]> = Mutex::new([None; MAX_SINKS]);
+/// Records each sink refused, by slot
+static DROPPED: [AtomicUsize; MAX_SINKS] = [const { AtomicUsize::new(0) }; MAX_SINKS];
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LogError {
+    /// A sink with that name is already registered
+    Exists,
+    /// Every slot is in use
+    Full,
+    /// No sink has that name
+    NotFound,
+}
+
+/// Start sending records at `level` or more severe to `sink`
+pub fn register(
+    name: &'static str,
+    sink: &'static dyn LogSink,
+    level: Level,
+) -> Result<(), LogError> {
+    let mut sinks = SINKS.lock();
+    if sinks.iter().flatten().any(|r| r.name == name) {
+        return Err(LogError::Exists);
+    }
+    let slot = sinks
+        .iter()
+        .position(Option::is_none)
+        .ok_or(LogError::Full)?;
+    DROPPED[slot].store(0, Ordering::Relaxed);
+    sinks[slot] = Some(Registration { name, sink, level });
+    Ok(())
+}
+
+/// Stop sending records to the sink called `name`
+pub fn unregister(name: &str) -> Result<(), LogError> {
+    let mut sinks = SINKS.lock();
+    let slot = find(&*sinks, name)?;
+    sinks[slot] = None;
+    Ok(())
+}
+
+/// Change the level of the sink called `name`
+pub fn set_level(name: &str, level: Level) -> Result<(), LogError> {
+    let mut sinks = SINKS.lock();
+    let slot = find(&*sinks, name)?;
+    if let Some(registration) = &mut sinks[slot] {
+        registration.level = level;
+    }
+    Ok(())
+}
+
+/// How many records the sink called `name` has refused
+pub fn dropped(name: &str) -> Result<usize, LogError> {
+    let slot = find(&*SINKS.lock(), name)?;
+    Ok(DROPPED[slot].load(Ordering::Relaxed))
+}
+
+fn find(sinks: &[Option<Registration>], name: &str) -> Result<usize, LogError> {
+    sinks
+        .iter()
+        .position(|r| r.is_some_and(|r| r.name == name))
+        .ok_or(LogError::NotFound)
+}
+
+/// Send a record to every sink that wants it. Used by `log!`.
+pub fn log(level: Level, text: fmt::Arguments) {
+    // Copy the registrations out so no sink is written under the lock; a
+    // slow sink then can't hold up registration or logging elsewhere
+    let sinks = *SINKS.lock();
+    let timestamp_us = time::uptime_us();
+
+    for (slot, registration) in sinks.iter().enumerate() {
+        let Some(registration) = registration else {
+            continue;
+        };
+        if level > registration.level {
+            continue;
+        }
+        if !registration.sink.write_record(level, timestamp_us, text) {
+            DROPPED[slot].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writes records to whichever console sink was picked at boot
+pub struct ConsoleLog;
+
+impl LogSink for ConsoleLog {
+    fn write_record(&self, level: Level, timestamp_us: u64, text: fmt::Arguments) -> bool {
+        console::print(format_args!(
+            "[{:5}.{:06}] {:5} {}\r\n",
+            timestamp_us / 1_000_000,
+            timestamp_us % 1_000_000,
+            level.name(),
+            text
+        ));
+        true
+    }
+}
+
+pub static CONSOLE_LOG: ConsoleLog = ConsoleLog;
+
+/// Register the console, at the level given by `loglevel=`
+pub fn init() {
+    let level = cmdline::get("loglevel")
+        .and_then(Level::from_name)
+        .unwrap_or(Level::Info);
+    let _ = register("console", &CONSOLE_LOG, level);
+}
@@ -1,10 +1,5 @@
 #![no_std]
 #![no_main]
-#![feature(
-    panic_info_message,
-    // llvm_asm,
-    lang_items
-)]
 
 use core::arch::{global_asm, asm};
 
@@ -17,8 +12,7 @@ global_asm!(include_str!("boot.s"));
 macro_rules! print
 {
 	($($args:tt)+) => ({
-        use core::fmt::Write;
-		let _ = write!(crate::uart::Uart::new(0x1000_0000), $($args)+);
+		$crate::console::print(format_args!($($args)+))
 	});
 }
 #[macro_export]
@@ -34,28 +28,67 @@ macro_rules! println
 		print!(concat!($fmt, "\r\n"), $($args)+)
 	});
 }
+#[macro_export]
+macro_rules! log
+{
+	($level:ident, $($args:tt)+) => ({
+		$crate::log::log($crate::log::Level::$level, format_args!($($args)+))
+	});
+}
+/// Panic with a [`PanicCode`](panic_code::PanicCode) saying what kind of
+/// failure it is, for the footer of the panic report
+#[macro_export]
+macro_rules! panic_with
+{
+	($code:expr, $($args:tt)+) => ({
+		$crate::panic_code::set($code);
+		panic!($($args)+)
+	});
+}
 
 // ///////////////////////////////////
 // / LANGUAGE STRUCTURES / FUNCTIONS
 // ///////////////////////////////////
-#[no_mangle]
-#[lang = "eh_personality"]
-extern fn eh_personality() {}
-
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    print!("Aborting: ");
+    use core::fmt::Write;
+    // Mask interrupts before anything else, so no handler can run in the
+    // middle of the dump. We run in M-mode, so that is MIE.
+    csr::disable_interrupts();
+    let hart = csr::mhartid::read();
+
+    // Panics always go to the UART, whatever console= picked, unbuffered
+    // and after anything still buffered. There are no tasks yet, so the
+    // hart is all the context there is to report.
+    console::SERIAL.drain_for_panic();
+    let mut out = console::Writer(&console::EARLYCON);
+    let _ = write!(out, "Aborting on hart {}: ", hart);
     if let Some(p) = info.location() {
-        println!(
-            "line {}, file {}: {}",
+        let _ = write!(
+            out,
+            "line {}, file {}: {}\r\n",
             p.line(),
             p.file(),
-            info.message().unwrap()
+            info.message()
         );
     } else {
-        println!("no information available.");
+        let _ = write!(out, "no information available.\r\n");
+    }
+
+    let mut frames = [0; 16];
+    let depth = utils::backtrace::capture(frames.len(), &mut frames);
+    for (i, ra) in frames[..depth].iter().enumerate() {
+        let _ = write!(out, "  #{:<2} {:#x}\r\n", i, ra);
     }
-    abort();
+
+    let report = panic_code::KernelPanic {
+        code: panic_code::current(),
+        hart,
+        location: info.location(),
+        exit_codes: cmdline::get("panic") == Some("exit"),
+    };
+    let _ = write!(out, "{}\r\n", report);
+    power::force_shutdown(power::ShutdownReason::Panic(report.exit_status()));
 }
 #[no_mangle]
 extern "C" fn abort() -> ! {
@@ -70,86 +103,137 @@ extern "C" fn abort() -> ! {
 // / CONSTANTS
 // ///////////////////////////////////
 
+/// Longest line of input the echo loop reads
+const LINE_SIZE: usize = 256;
+
 // ///////////////////////////////////
 // / ENTRY POINT
 // ///////////////////////////////////
 #[no_mangle]
-extern "C" fn kmain() -> ! {
-    {
-        const UART: *mut () = 0x1000_0000 as *mut ();
-
-        unsafe {
-            UART.write_bytes('h' as u8, 1)
-        }
-    }
-
+extern "C" fn kmain(dtb: usize) -> ! {
     // Main should initialize all sub-systems and get
     // ready to start scheduling. The last thing this
     // should do is start the timer.
-    let mut uart = uart::Uart::new(0x1000_0000);
-    uart.init();
+
+    // Nothing may be printed until the platform is known, since the
+    // console's address depends on it.
+    let fdt = unsafe { fdt::Fdt::from_addr(dtb) }
+        .ok()
+        .map(|fdt| *globals::FDT.init(fdt));
+    cmdline::init(fdt.as_ref());
+    let platform = platform::init(fdt.as_ref());
+    console::init();
+    // Checked once something can report a broken linker script
+    let layout = layout::init();
+    time::init(fdt.as_ref());
+    let mut report = boot::BootReport::new();
+    report.record("plic", plic::init());
+    log::init();
+    let gpio = report.record("gpio", gpio::init(fdt.as_ref())).flatten();
+    report.record("heartbeat", heartbeat::init(gpio));
+    report.record("i2c", i2c::init(fdt.as_ref()));
+    buildinfo::log();
+
+    log!(
+        Info,
+        "platform: {} ({}) on {:?}, device tree {}",
+        platform.name,
+        platform.compatible,
+        platform::environment(),
+        if fdt.is_some() { "found" } else { "missing" }
+    );
+
+    if let Some(gpio) = gpio {
+        log!(Info, "gpio: {} pins", gpio::GpioController::pins(gpio));
+    }
+
+    #[cfg(feature = "virtio")]
+    virtio::discover(fdt.as_ref());
+
+    for (name, range) in layout.sections() {
+        log!(Debug, "layout: {:6} {:#x}..{:#x}", name, range.start, range.end);
+    }
+
+    page::init();
+    let kernel_pages = report.fatal("paging", page::init_paging_system());
+    let kernel_pages = globals::KERNEL_PAGES.init(spin::Mutex::new(kernel_pages));
+    memmap::print_physical_memory_map();
+    log!(Info, "paging: kernel page table at {:#x}", kernel_pages.lock().root());
+    log!(
+        Info,
+        "paging: layout fingerprint {:#018x}",
+        kernel_pages.lock().layout_fingerprint()
+    );
+    asid::init();
+    match kernel_pages.lock().activate() {
+        Ok(mode) => log!(Info, "paging: {:?} active", mode),
+        Err(_) => log!(Warn, "paging: Sv39 not supported, staying in bare mode"),
+    }
+    report.record("trap stack", smp::init());
+    log!(Info, "smp: harts {:#b} online", smp::start_harts(fdt.as_ref()));
+    report.log();
+    if cmdline::has("sysinfo") {
+        sysinfo::print();
+    }
 
     println!("hello world");
     println!("hello world again");
 
+    // The console echoes lines as they are typed, honouring the terminal
+    // mode and echo setting. Ctrl-D ends input, cooked or raw; `irqstats`
+    // prints the interrupt counters.
+    let mut line = [0; LINE_SIZE];
     loop {
-        if let Some(c) = uart.get() {
-            match c {
-                8 => {
-                    // This is a backspace, so we essentially have
-                    // to write a space and backup again:
-                    print!("{}{}{}", 8 as char, ' ', 8 as char);
-                }
-                b'\n' | b'\r' => {
-                    // Newline or carriage-return
-                    println!();
-                }
-                0x1b => {
-                    // Those familiar with ANSI escape sequences
-                    // knows that this is one of them. The next
-                    // thing we should get is the left bracket [
-                    // These are multi-byte sequences, so we can take
-                    // a chance and get from UART ourselves.
-                    // Later, we'll button this up.
-                    if let Some(next_byte) = uart.get() {
-                        if next_byte == 91 {
-                            // This is a right bracket! We're on our way!
-                            if let Some(b) = uart.get() {
-                                match b as char {
-                                    'A' => {
-                                        println!("That's the up arrow!");
-                                    }
-                                    'B' => {
-                                        println!("That's the down arrow!");
-                                    }
-                                    'C' => {
-                                        println!("That's the right arrow!");
-                                    }
-                                    'D' => {
-                                        println!("That's the left arrow!");
-                                    }
-                                    _ => {
-                                        println!("That's something else.....");
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    print!("{}", c as char);
-                }
-            }
+        let read = console::read_line_edited(&mut line);
+        if read.end || line[..read.len] == [4] {
+            break;
+        }
+        if &line[..read.len] == b"irqstats" {
+            sysinfo::print_irqs();
         }
     }
-}
 
-fn hcf() -> ! {
-    loop {
-        unsafe { asm!("wfi") };
-    }
+    power::shutdown(power::ShutdownReason::Finished)
 }
 
 // RUST MODULES
 
+pub mod asid;
+pub mod barrier;
+pub mod boot;
+pub mod buildinfo;
+pub mod cmdline;
+pub mod console;
+pub mod csr;
+pub mod fdt;
+pub mod features;
+pub mod globals;
+pub mod gpio;
+pub mod heartbeat;
+pub mod i2c;
+pub mod ipi;
+pub mod layout;
+pub mod log;
+pub mod memmap;
+pub mod mmio;
+pub mod page;
+pub mod panic_code;
+pub mod platform;
+pub mod plic;
+pub mod power;
+pub mod reserved_memory;
+pub mod sifive_gpio;
+pub mod sifive_i2c;
+pub mod sifive_uart;
+pub mod smp;
+pub mod stack;
+pub mod sysinfo;
+pub mod time;
+pub mod tlb;
+pub mod trap;
 pub mod uart;
+pub mod utils;
+#[cfg(feature = "virtio")]
+pub mod virtio;
+#[cfg(feature = "virtio")]
+pub mod virtqueue;
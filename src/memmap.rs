@@ -0,0 +1,81 @@
+//! # Physical memory map
+//!
+//! One sorted table of what lives where in the physical address space:
+//! RAM from the device tree, the firmware's reservations, the device tree
+//! blob itself, the kernel image, the frame allocator's heap and every
+//! claimed MMIO window. Regions nest, so the kernel shows up inside RAM,
+//! and stretches nothing describes are listed as gaps. Most early memory
+//! bugs are two of these lines disagreeing.
+//!
+//! The table itself is [`oslib::memmap`]; this gathers what goes in it.
+
+pub use oslib::memmap::{MemoryMap, Region, RegionKind, Row};
+
+use crate::{globals, layout, mmio};
+
+/// Gather every region the kernel knows about
+pub fn collect() -> MemoryMap {
+    let mut map = MemoryMap::new();
+
+    if let Some(fdt) = globals::FDT.try_get() {
+        for (base, size) in fdt.memory() {
+            map.add(RegionKind::Ram, "memory", base..base + size);
+        }
+        for (base, size) in fdt.reservations() {
+            map.add(RegionKind::Reserved, "rsvmap", base..base + size);
+        }
+        map.add(
+            RegionKind::DeviceTree,
+            "blob",
+            fdt.address()..fdt.address() + fdt.size(),
+        );
+    } else {
+        map.add(RegionKind::Ram, "linker script", layout::memory_range());
+    }
+
+    if let Some(carveouts) = globals::CARVEOUTS.try_get() {
+        for carveout in carveouts.iter() {
+            map.add(RegionKind::Reserved, carveout.name, carveout.range.clone());
+        }
+    }
+
+    let image = globals::LAYOUT.get();
+    map.add(
+        RegionKind::Kernel,
+        "image",
+        image.text.start..image.stack.end,
+    );
+    if let Some(frames) = globals::FRAME_REGION.try_get() {
+        map.add(RegionKind::Heap, "frames", frames.clone());
+    }
+
+    mmio::for_each_claim(|name, range| map.add(RegionKind::Mmio, name, range));
+    map
+}
+
+/// The regions nothing but the kernel may touch: the firmware's
+/// reservations, the device tree blob and the kernel image
+pub fn kernel_owned() -> MemoryMap {
+    let mut owned = MemoryMap::new();
+    for region in collect().regions() {
+        if matches!(
+            region.kind,
+            RegionKind::Reserved | RegionKind::DeviceTree | RegionKind::Kernel
+        ) {
+            owned.add(region.kind, region.label, region.range.clone());
+        }
+    }
+    owned
+}
+
+/// Log the physical memory map, one region per line
+pub fn print_physical_memory_map() {
+    let map = collect();
+    log!(Info, "physical memory map:");
+    for row in map.rows() {
+        log!(Info, "  {}", row);
+    }
+    if map.dropped() > 0 {
+        log!(Warn, "memory map: {} regions didn't fit", map.dropped());
+    }
+}
@@ -0,0 +1,213 @@
+//! # Device memory
+//!
+//! Drivers get at their registers through a [`DeviceMemory`] rather than
+//! a bare base address. It remembers how long the region is, so a bad
+//! register offset is caught instead of reading or writing whatever
+//! device happens to sit next door, and it holds an exclusive claim on
+//! the region so two drivers can't be bound to the same registers.
+//!
+//! Regions are identity mapped READ_WRITE into the kernel page table,
+//! either by `init_paging_system` for regions claimed before it ran or
+//! when claimed afterwards. Dropping a `DeviceMemory` releases the claim
+//! and unmaps it again.
+
+use core::ops::Range;
+
+use spin::Mutex;
+
+pub use oslib::mmio::{ByteRegisters, MmioError, Registers};
+
+use crate::globals;
+use crate::page::{PageError, PageFlags, PageSystem, PAGE_SIZE};
+use crate::utils::align::{align_down, align_up};
+
+/// How many regions can be claimed at once
+const MAX_CLAIMS: usize = 16;
+
+#[derive(Clone)]
+struct Claim {
+    name: &'static str,
+    range: Range<usize>,
+}
+
+static CLAIMS: Mutex<[Option<Claim>; MAX_CLAIMS]> = Mutex::new([const { None }; MAX_CLAIMS]);
+
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// The registers of one device
+pub struct DeviceMemory {
+    base: usize,
+    len: usize,
+}
+
+impl DeviceMemory {
+    /// Claim the `len` bytes of registers at `base` for the driver called
+    /// `name`. Fails if another driver already holds any of them.
+    pub fn claim(name: &'static str, base: usize, len: usize) -> Result<Self, MmioError> {
+        let range = base..base + len;
+        {
+            let mut claims = CLAIMS.lock();
+            if let Some(other) = claims.iter().flatten().find(|c| overlaps(&c.range, &range)) {
+                return Err(MmioError::Overlap(other.name));
+            }
+            let slot = claims
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or(MmioError::TooManyClaims)?;
+            *slot = Some(Claim {
+                name,
+                range: range.clone(),
+            });
+        }
+
+        let memory = Self { base, len };
+        if let Some(pages) = globals::KERNEL_PAGES.try_get() {
+            // dropping `memory` releases the claim again
+            map_region(&mut pages.lock(), &range).map_err(MmioError::Map)?;
+        }
+        Ok(memory)
+    }
+
+    /// Physical address of the first register
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Size of the region in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Address of the `width` byte register at `offset`, if it lies inside
+    /// the region
+    fn check(&self, offset: usize, width: usize) -> Result<usize, MmioError> {
+        oslib::mmio::check_access(self.len, offset, width)?;
+        Ok(self.base + offset)
+    }
+
+    pub fn read8(&self, offset: usize) -> Result<u8, MmioError> {
+        let addr = self.check(offset, 1)?;
+        Ok(unsafe { (addr as *const u8).read_volatile() })
+    }
+
+    pub fn write8(&self, offset: usize, value: u8) -> Result<(), MmioError> {
+        let addr = self.check(offset, 1)?;
+        unsafe { (addr as *mut u8).write_volatile(value) };
+        Ok(())
+    }
+
+    pub fn read32(&self, offset: usize) -> Result<u32, MmioError> {
+        let addr = self.check(offset, 4)?;
+        Ok(unsafe { (addr as *const u32).read_volatile() })
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) -> Result<(), MmioError> {
+        let addr = self.check(offset, 4)?;
+        unsafe { (addr as *mut u32).write_volatile(value) };
+        Ok(())
+    }
+
+    pub fn read64(&self, offset: usize) -> Result<u64, MmioError> {
+        let addr = self.check(offset, 8)?;
+        Ok(unsafe { (addr as *const u64).read_volatile() })
+    }
+
+    pub fn write64(&self, offset: usize, value: u64) -> Result<(), MmioError> {
+        let addr = self.check(offset, 8)?;
+        unsafe { (addr as *mut u64).write_volatile(value) };
+        Ok(())
+    }
+}
+
+impl Registers for DeviceMemory {
+    fn read32(&self, offset: usize) -> Result<u32, MmioError> {
+        DeviceMemory::read32(self, offset)
+    }
+
+    fn write32(&self, offset: usize, value: u32) -> Result<(), MmioError> {
+        DeviceMemory::write32(self, offset, value)
+    }
+}
+
+impl ByteRegisters for DeviceMemory {
+    fn read8(&self, offset: usize) -> Result<u8, MmioError> {
+        DeviceMemory::read8(self, offset)
+    }
+
+    fn write8(&self, offset: usize, value: u8) -> Result<(), MmioError> {
+        DeviceMemory::write8(self, offset, value)
+    }
+}
+
+impl Drop for DeviceMemory {
+    fn drop(&mut self) {
+        let range = self.base..self.base + self.len;
+        let mut claims = CLAIMS.lock();
+        if let Some(slot) = claims
+            .iter_mut()
+            .find(|slot| slot.as_ref().is_some_and(|c| c.range == range))
+        {
+            *slot = None;
+        }
+
+        let Some(pages) = globals::KERNEL_PAGES.try_get() else {
+            return;
+        };
+        let mut pages = pages.lock();
+        for page in pages_of(&range) {
+            // leave pages another device still has registers in
+            let page_range = page..page + PAGE_SIZE;
+            if claims
+                .iter()
+                .flatten()
+                .any(|c| overlaps(&c.range, &page_range))
+            {
+                continue;
+            }
+            let _ = pages.unmap(page);
+        }
+    }
+}
+
+/// Start addresses of the pages covering `range`
+fn pages_of(range: &Range<usize>) -> impl Iterator<Item = usize> {
+    let start = align_down(range.start, PAGE_SIZE);
+    // a window ending in the last page of the address space still covers it
+    let end = align_up(range.end, PAGE_SIZE).unwrap_or(usize::MAX);
+    (start..end).step_by(PAGE_SIZE)
+}
+
+/// Identity map `range`. Small devices can share a page, so pages that are
+/// already mapped are left alone.
+fn map_region(pages: &mut PageSystem, range: &Range<usize>) -> Result<(), PageError> {
+    for page in pages_of(range) {
+        if pages.translate(page) == Some(page) {
+            continue;
+        }
+        pages.map_identity(page, PageFlags::READ_WRITE)?;
+    }
+    Ok(())
+}
+
+/// Hand the name and range of every region claimed so far to `visit`
+pub fn for_each_claim(mut visit: impl FnMut(&'static str, Range<usize>)) {
+    let claims = CLAIMS.lock().clone();
+    for claim in claims.into_iter().flatten() {
+        visit(claim.name, claim.range);
+    }
+}
+
+/// Map every region claimed so far into `pages`
+pub fn map_claimed(pages: &mut PageSystem) -> Result<(), PageError> {
+    let claims = CLAIMS.lock().clone();
+    for claim in claims.iter().flatten() {
+        map_region(pages, &claim.range)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,642 @@
+//! # Paging
+//!
+//! Physical frame allocation and the kernel's side of Sv39 page tables.
+//! Frames are handed out of the heap region the linker script leaves after
+//! the kernel stack, first by bumping `next_highest_page` and then by
+//! recycling freed frames through the `next_free_page` list. The tables
+//! themselves are [`oslib::page`]'s, fed frames from here.
+
+use core::ops::Range;
+#[cfg(feature = "page_poison")]
+use core::panic::Location;
+use core::ptr::{addr_of_mut, NonNull};
+use core::sync::atomic::AtomicU16;
+
+use spin::Mutex;
+
+use oslib::page::RefCountError;
+pub use oslib::page::{
+    get_table_indices, AuditViolation, Entry, Frames, Leaf, PageError, PageFlags, PageRefCount,
+    PageSize, PageTable, Table, Translation, KERNEL_HALF_START, PAGE_ORDER, PAGE_SIZE,
+};
+use oslib::stack::StackInfo;
+
+use crate::asid::AddressSpaceId;
+pub use crate::csr::SatpMode;
+use crate::csr::{self, Satp};
+use crate::memmap::{self, RegionKind};
+use crate::panic_code::PanicCode;
+use crate::tlb::TlbBatch;
+use crate::utils::align::{align_down, align_up};
+use crate::utils::list::{IntrusiveList, Linked, ListNode};
+use crate::{globals, layout, mmio, reserved_memory, tlb};
+
+// ///////////////////////////////////
+// / FRAME ALLOCATOR
+// ///////////////////////////////////
+
+/// A freed frame. Its links are kept in the frame itself.
+struct FreeFrame {
+    node: ListNode,
+    /// Where the frame was freed, reported if it is written to afterwards
+    #[cfg(feature = "page_poison")]
+    freed_at: &'static Location<'static>,
+}
+
+unsafe impl Linked for FreeFrame {
+    fn node(item: NonNull<Self>) -> NonNull<ListNode> {
+        unsafe { NonNull::new_unchecked(addr_of_mut!((*item.as_ptr()).node)) }
+    }
+
+    unsafe fn from_node(node: NonNull<ListNode>) -> NonNull<Self> {
+        // the node is the first field
+        node.cast()
+    }
+}
+
+// A free frame is just memory nobody else is using
+unsafe impl Send for FreeFrame {}
+
+/// Where the next frame comes from. Any hart may allocate, so both
+/// fields change together under one lock; it is only held for a few
+/// loads and stores.
+struct FrameAllocator {
+    /// Lowest frame that has never been handed out
+    next_highest_page: usize,
+    /// Frames that have been freed, reused before bumping further
+    next_free_page: IntrusiveList<FreeFrame>,
+    #[cfg(feature = "page_poison")]
+    quarantine: Quarantine,
+}
+
+static FRAMES: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::new());
+
+impl FrameAllocator {
+    const fn new() -> Self {
+        Self {
+            next_highest_page: 0,
+            next_free_page: IntrusiveList::new(),
+            #[cfg(feature = "page_poison")]
+            quarantine: Quarantine::new(),
+        }
+    }
+}
+
+// ///////////////////////////////////
+// / PAGE POISONING
+// ///////////////////////////////////
+
+// With the page_poison feature, freed frames are filled with a pattern and
+// held back for a while before being reused. Allocating one checks the
+// pattern is intact, so a write through a stale pointer is reported along
+// with where the frame was freed, rather than corrupting its next owner.
+
+/// Byte freed frames are filled with
+#[cfg(feature = "page_poison")]
+const POISON: u8 = 0x6b;
+
+/// How many freed frames are kept out of circulation
+#[cfg(feature = "page_poison")]
+const QUARANTINE_DEPTH: usize = 16;
+
+/// The most recently freed frames, oldest first out
+#[cfg(feature = "page_poison")]
+struct Quarantine {
+    frames: [usize; QUARANTINE_DEPTH],
+    next: usize,
+}
+
+#[cfg(feature = "page_poison")]
+impl Quarantine {
+    const fn new() -> Self {
+        Self {
+            frames: [0; QUARANTINE_DEPTH],
+            next: 0,
+        }
+    }
+
+    /// Hold `frame` back, releasing the frame that has been held longest
+    fn push(&mut self, frame: usize) -> Option<usize> {
+        let oldest = core::mem::replace(&mut self.frames[self.next], frame);
+        self.next = (self.next + 1) % QUARANTINE_DEPTH;
+        (oldest != 0).then_some(oldest)
+    }
+}
+
+/// Fill a freed frame with poison, after its header
+#[cfg(feature = "page_poison")]
+fn poison_frame(addr: usize) {
+    let header = core::mem::size_of::<FreeFrame>();
+    unsafe { ((addr + header) as *mut u8).write_bytes(POISON, PAGE_SIZE - header) };
+}
+
+/// Check a frame coming off the free list hasn't been written since it
+/// was freed
+#[cfg(feature = "page_poison")]
+fn check_poison(addr: usize) {
+    let header = core::mem::size_of::<FreeFrame>();
+    let bytes =
+        unsafe { core::slice::from_raw_parts((addr + header) as *const u8, PAGE_SIZE - header) };
+
+    let mut modified = bytes
+        .iter()
+        .enumerate()
+        .filter(|(_, &b)| b != POISON)
+        .map(|(i, _)| i + header);
+    let Some(first) = modified.next() else {
+        return;
+    };
+    let last = modified.next_back().unwrap_or(first);
+
+    let freed_at = unsafe { (*(addr as *const FreeFrame)).freed_at };
+    panic_with!(
+        PanicCode::Corruption { subsystem: "page" },
+        "frame {:#x} was written after being freed at {}: offsets {:#x}..={:#x} modified",
+        addr,
+        freed_at,
+        first,
+        last
+    );
+}
+
+/// Stop on a reference count gone wrong: the frame allocator and the
+/// mappings no longer agree about who holds the frame at `addr`
+fn ref_count_corrupted(addr: usize, error: RefCountError) -> ! {
+    let code = PanicCode::Corruption { subsystem: "page" };
+    match error {
+        RefCountError::NotAllocated => {
+            panic_with!(code, "mapping frame {:#x} which is not allocated", addr)
+        }
+        RefCountError::Underflow => {
+            panic_with!(code, "frame {:#x} freed more often than referenced", addr)
+        }
+    }
+}
+
+/// The bank of RAM the device tree's `/memory` nodes say `addr` is in.
+/// Without a device tree, the memory the linker script was told about.
+fn memory_bank(addr: usize) -> Range<usize> {
+    globals::FDT
+        .try_get()
+        .and_then(|fdt| {
+            fdt.memory()
+                .map(|(base, size)| base..base + size)
+                .find(|bank| bank.contains(&addr))
+        })
+        .unwrap_or_else(layout::memory_range)
+}
+
+/// Whether `addr` lies in the range of frames the allocator hands out
+fn is_managed(addr: usize) -> bool {
+    globals::FRAME_REGION.get().contains(&addr)
+}
+
+/// Whether any of `range` is memory only the kernel may touch: the
+/// kernel's own regions, or a frame holding a page table
+fn is_kernel_owned(range: Range<usize>) -> bool {
+    let owned = globals::KERNEL_OWNED_MEMORY
+        .try_get()
+        .is_some_and(|owned| owned.overlapping(&range).is_some());
+    owned
+        || globals::PAGE_REF_COUNT
+            .try_get()
+            .is_some_and(|ref_count| ref_count.any_table(range))
+}
+
+/// Set up the frame allocator over the heap region, as far as the RAM
+/// the device tree describes reaches. The reference count table takes
+/// the first frames left once reserved memory is carved out, with one
+/// counter for each frame after it.
+pub fn init() {
+    let heap = layout::heap_range();
+    let heap_start = align_up(heap.start, PAGE_SIZE).expect("heap starts at the top of memory");
+    let mut last = align_down(heap.end.min(memory_bank(heap_start).end), PAGE_SIZE);
+
+    // QEMU places the device tree near the top of RAM; stop short of it
+    if let Some(fdt) = globals::FDT.try_get() {
+        if (heap_start..last).contains(&fdt.address()) {
+            last = align_down(fdt.address(), PAGE_SIZE);
+        }
+    }
+
+    let usable = reserved_memory::init(globals::FDT.try_get(), heap_start..last.max(heap_start));
+
+    // Sized for every usable frame, which is a few more than are left
+    // once the table has taken its own
+    let table_size = (usable.len() >> PAGE_ORDER) * core::mem::size_of::<AtomicU16>();
+    let first = align_up(usable.start + table_size, PAGE_SIZE)
+        .expect("reference count table runs past the top of memory")
+        .min(usable.end);
+    let region = first..usable.end;
+
+    *FRAMES.lock() = FrameAllocator {
+        next_highest_page: region.start,
+        ..FrameAllocator::new()
+    };
+    let counts = usable.start as *mut AtomicU16;
+    let len = region.len() >> PAGE_ORDER;
+    for i in 0..len {
+        unsafe { counts.add(i).write(AtomicU16::new(0)) };
+    }
+    let counts = unsafe { core::slice::from_raw_parts(counts, len) };
+    globals::PAGE_REF_COUNT.init(PageRefCount::new(region.start, counts));
+    globals::FRAME_REGION.init(region);
+
+    let mut owned = memmap::kernel_owned();
+    owned.add(RegionKind::Kernel, "ref counts", usable.start..first);
+    globals::KERNEL_OWNED_MEMORY.init(owned);
+}
+
+/// Allocate a single frame, returning its physical address. The frame's
+/// reference count starts at one, owned by the caller.
+pub fn alloc_page() -> Option<usize> {
+    let mut frames = FRAMES.lock();
+    let page = match frames.next_free_page.pop_front() {
+        Some(frame) => {
+            #[cfg(feature = "page_poison")]
+            check_poison(frame.as_ptr() as usize);
+            frame.as_ptr() as usize
+        }
+        None => {
+            let page = frames.next_highest_page;
+            if page >= globals::FRAME_REGION.get().end {
+                return None;
+            }
+            frames.next_highest_page = page + PAGE_SIZE;
+            page
+        }
+    };
+    drop(frames);
+
+    globals::PAGE_REF_COUNT.get().set(page, 1);
+    Some(page)
+}
+
+/// Allocate `count` physically contiguous frames, returning the address of
+/// the first. Freed frames are scattered, so these only come from memory
+/// that has never been handed out. Each frame starts with a reference
+/// count of one, owned by the caller, and is freed on its own.
+pub fn alloc_contiguous_pages(count: usize) -> Option<usize> {
+    let mut frames = FRAMES.lock();
+    let start = frames.next_highest_page;
+    let end = start.checked_add(count.checked_mul(PAGE_SIZE)?)?;
+    if end > globals::FRAME_REGION.get().end {
+        return None;
+    }
+    frames.next_highest_page = end;
+    drop(frames);
+
+    let ref_count = globals::PAGE_REF_COUNT.get();
+    for page in (start..end).step_by(PAGE_SIZE) {
+        ref_count.set(page, 1);
+    }
+    Some(start)
+}
+
+/// How much of the frame allocator's region is in use
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FrameStats {
+    /// Frames in the region
+    pub total: usize,
+    /// Frames that can be handed out, whether never used or freed since
+    pub free: usize,
+}
+
+/// Count the allocator's frames, or `None` before [`init`]
+pub fn frame_stats() -> Option<FrameStats> {
+    let region = globals::FRAME_REGION.try_get()?;
+    let frames = FRAMES.lock();
+    Some(FrameStats {
+        total: region.len() / PAGE_SIZE,
+        free: region.end.saturating_sub(frames.next_highest_page) / PAGE_SIZE
+            + frames.next_free_page.len(),
+    })
+}
+
+/// Allocate a frame and fill it with zeroes
+pub fn alloc_zeroed_page() -> Option<usize> {
+    let page = alloc_page()?;
+    unsafe { (page as *mut u8).write_bytes(0, PAGE_SIZE) };
+    Some(page)
+}
+
+/// Drop the caller's reference to the frame at `addr`. It is returned to
+/// the allocator once nothing else references it.
+#[cfg_attr(feature = "page_poison", track_caller)]
+pub fn free_page(addr: usize) {
+    assert!(
+        addr.is_multiple_of(PAGE_SIZE),
+        "freeing unaligned frame {:#x}",
+        addr
+    );
+    assert!(
+        is_managed(addr),
+        "freeing frame {:#x} the allocator doesn't own",
+        addr
+    );
+
+    match globals::PAGE_REF_COUNT.get().decrement(addr) {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(error) => ref_count_corrupted(addr, error),
+    }
+
+    unsafe {
+        (addr as *mut FreeFrame).write(FreeFrame {
+            node: ListNode::new(),
+            #[cfg(feature = "page_poison")]
+            freed_at: Location::caller(),
+        });
+    }
+    #[cfg(feature = "page_poison")]
+    poison_frame(addr);
+
+    let mut frames = FRAMES.lock();
+    #[cfg(feature = "page_poison")]
+    let Some(addr) = frames.quarantine.push(addr) else {
+        return;
+    };
+    unsafe {
+        frames
+            .next_free_page
+            .push_front(NonNull::new_unchecked(addr as *mut FreeFrame));
+    }
+}
+
+// ///////////////////////////////////
+// / PAGE TABLES
+// ///////////////////////////////////
+
+/// The frame allocator, as the page tables see it
+pub struct KernelFrames;
+
+impl Frames for KernelFrames {
+    fn alloc_table(&self) -> Option<usize> {
+        let table = alloc_zeroed_page()?;
+        globals::PAGE_REF_COUNT.get().mark_table(table);
+        Some(table)
+    }
+
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    fn free_table(&self, table: usize) {
+        globals::PAGE_REF_COUNT.get().clear_table(table);
+        free_page(table);
+    }
+
+    fn map_frame(&self, frame: usize) {
+        if let Err(error) = globals::PAGE_REF_COUNT.get().increment(frame) {
+            ref_count_corrupted(frame, error);
+        }
+    }
+
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    fn unmap_frame(&self, frame: usize) {
+        if is_managed(frame) {
+            free_page(frame);
+        }
+    }
+
+    fn is_kernel_owned(&self, range: Range<usize>) -> bool {
+        is_kernel_owned(range)
+    }
+
+    fn flush(&self, virt: usize) {
+        flush_tlb(virt);
+    }
+}
+
+/// An Sv39 address space: its tables, and what the TLBs of the harts that
+/// ran it need to hear about
+pub struct PageSystem {
+    table: PageTable<KernelFrames>,
+    asid: AddressSpaceId,
+    /// Translations changed since the last shootdown
+    pending: TlbBatch,
+    /// Harts that have run the address space
+    ran_on: usize,
+}
+
+/// Looking through the tables needs no flushing, so it is done on them
+/// directly
+impl core::ops::Deref for PageSystem {
+    type Target = PageTable<KernelFrames>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.table
+    }
+}
+
+impl PageSystem {
+    /// Create an empty address space
+    pub fn new() -> Result<Self, PageError> {
+        Ok(Self {
+            table: PageTable::new(KernelFrames)?,
+            asid: AddressSpaceId::new(),
+            pending: TlbBatch::new(),
+            ran_on: 0,
+        })
+    }
+
+    /// Tear the address space down: drop the reference each mapping holds
+    /// on its frames, then free every table including the root. Frames
+    /// nothing else references go back to the allocator.
+    ///
+    /// GLOBAL mappings belong to the kernel, which keeps its references to
+    /// them, so their frames are left alone. The address space must not be
+    /// active on any hart.
+    ///
+    /// Every hart it ran on flushes its ASID first, which covers any
+    /// pending batch too, so none of them still holds translations or
+    /// cached walks through the tables being freed. The ASID then goes
+    /// back to the allocator.
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    pub fn destroy(self) {
+        tlb::flush_asid(self.asid.asid(), self.ran_on);
+        self.table.destroy();
+        if let Some(asids) = globals::ASIDS.try_get() {
+            asids.lock().release(self.asid);
+        }
+    }
+
+    /// Point this hart's `satp` at the table.
+    ///
+    /// Implementations may ignore a write of a mode they don't support and
+    /// stay in bare mode, so `satp` is read back. If Sv39 didn't engage,
+    /// `satp` is put back to bare and `ModeUnsupported` returned. Harts
+    /// without supervisor mode have no `satp` at all and get the same error
+    /// without it being touched.
+    pub fn activate(&mut self) -> Result<SatpMode, PageError> {
+        if !csr::has_extension(b'S') {
+            return Err(PageError::ModeUnsupported);
+        }
+
+        let (asid, _) = globals::ASIDS.get().lock().assign(&mut self.asid);
+        write_satp(Satp::new(SatpMode::Sv39, asid, self.root()));
+        self.ran_on |= tlb::hart_bit(tlb::current_hart());
+
+        match Satp::from_bits(csr::satp::read()).mode {
+            Some(SatpMode::Sv39) => Ok(SatpMode::Sv39),
+            _ => {
+                write_satp(Satp::from_bits(0));
+                Err(PageError::ModeUnsupported)
+            }
+        }
+    }
+
+    /// Switch this hart to the address space once it has been activated.
+    /// The TLB is only flushed when its ASID had to be handed out again,
+    /// otherwise the translations cached under it are still good.
+    pub fn switch_to(&mut self) {
+        let (asid, flush) = globals::ASIDS.get().lock().assign(&mut self.asid);
+        let satp = Satp::new(SatpMode::Sv39, asid, self.root());
+        if flush {
+            write_satp(satp);
+        } else {
+            csr::satp::write(satp.bits());
+        }
+        self.ran_on |= tlb::hart_bit(tlb::current_hart());
+    }
+
+    /// Map the 4KiB page at `virt` to the frame at `phys`, taking a
+    /// reference on the frame.
+    pub fn map_page(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        flags: PageFlags,
+    ) -> Result<(), PageError> {
+        self.table.map_page(virt, phys, flags)
+    }
+
+    /// Map a page of `size` at `virt` to the frames starting at `phys`,
+    /// taking a reference on each of them.
+    pub fn map(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        flags: PageFlags,
+        size: PageSize,
+    ) -> Result<(), PageError> {
+        self.table.map(virt, phys, flags, size)
+    }
+
+    /// Map the `size` bytes starting at `virt` to consecutive frames
+    /// starting at `phys`
+    pub fn map_range(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        size: usize,
+        flags: PageFlags,
+    ) -> Result<(), PageError> {
+        self.table.map_range(virt, phys, size, flags)
+    }
+
+    /// Map the 4KiB page at `addr` to the frame at the same address
+    pub fn map_identity(&mut self, addr: usize, flags: PageFlags) -> Result<(), PageError> {
+        self.table.map_identity(addr, flags)
+    }
+
+    /// Map `start..end` to the frames at the same addresses
+    pub fn map_identity_range(
+        &mut self,
+        start: usize,
+        end: usize,
+        flags: PageFlags,
+    ) -> Result<(), PageError> {
+        self.table.map_identity_range(start, end, flags)
+    }
+
+    /// Map `stack` where it is, leaving its guard page unmapped
+    pub fn map_stack(&mut self, stack: &StackInfo) -> Result<(), PageError> {
+        stack.map(&mut self.table)
+    }
+
+    /// Remove the 4KiB mapping at `virt`, dropping its reference on the
+    /// frame. A superpage covering it is split first.
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    pub fn unmap(&mut self, virt: usize) -> Result<(), PageError> {
+        self.unmap_range(virt & !(PAGE_SIZE - 1), PAGE_SIZE)
+    }
+
+    /// Remove every mapping in the `size` bytes at `virt`, dropping their
+    /// references on the frames. Superpages that stick out of the range
+    /// are split so only the part inside it goes.
+    ///
+    /// Stops at the first page that isn't mapped, leaving the pages before
+    /// it unmapped.
+    #[cfg_attr(feature = "page_poison", track_caller)]
+    pub fn unmap_range(&mut self, virt: usize, size: usize) -> Result<(), PageError> {
+        let result = self.table.unmap_pages(virt, size, &mut self.pending);
+        self.shootdown();
+        result
+    }
+
+    /// Give every page in the `size` bytes at `virt` the permissions in
+    /// `flags`. Superpages that stick out of the range are split so only
+    /// the part inside it changes.
+    ///
+    /// Stops at the first page that isn't mapped, leaving the pages before
+    /// it changed.
+    pub fn protect(&mut self, virt: usize, size: usize, flags: PageFlags) -> Result<(), PageError> {
+        let result = self
+            .table
+            .protect_pages(virt, size, flags, &mut self.pending);
+        self.shootdown();
+        result
+    }
+
+    /// Have the other harts that ran the address space flush what changed
+    /// since the last shootdown. This hart flushed as it made the changes.
+    fn shootdown(&mut self) {
+        let batch = core::mem::take(&mut self.pending);
+        tlb::shootdown(self.asid.asid(), &batch, self.ran_on);
+    }
+}
+
+/// Write `satp` and drop every cached translation made under the old value
+fn write_satp(satp: Satp) {
+    csr::satp::write(satp.bits());
+    unsafe { core::arch::asm!("sfence.vma zero, zero") };
+}
+
+/// Flush any cached translation for `virt`
+pub fn flush_tlb(virt: usize) {
+    unsafe { core::arch::asm!("sfence.vma {}, zero", in(reg) virt) };
+}
+
+/// Build the kernel's address space: the kernel image identity mapped
+/// with the permissions of each section, plus the devices it talks to.
+pub fn init_paging_system() -> Result<PageSystem, PageError> {
+    let mut pages = PageSystem::new()?;
+
+    map_kernel_memory(&mut pages)?;
+    mmio::map_claimed(&mut pages)?;
+
+    #[cfg(feature = "debug_checks")]
+    if let Err((leaf, violation)) = pages.audit() {
+        panic_with!(
+            PanicCode::Corruption {
+                subsystem: "page tables"
+            },
+            "kernel page table fails its audit: {:?} at {:#x?}",
+            violation,
+            leaf
+        );
+    }
+
+    Ok(pages)
+}
+
+fn map_kernel_memory(pages: &mut PageSystem) -> Result<(), PageError> {
+    // rodata follows text without page alignment, so they share permissions
+    let text_start = layout::text_range().start;
+    let rodata_end = layout::rodata_range().end;
+    pages.map_identity_range(text_start, rodata_end, PageFlags::READ_EXECUTE)?;
+
+    // data, bss and the kernel stack are contiguous
+    let data_start = layout::data_range().start;
+    let stack_end = layout::stack_range().end;
+    pages.map_identity_range(data_start, stack_end, PageFlags::READ_WRITE)?;
+    Ok(())
+}
@@ -0,0 +1,36 @@
+//! # Panic codes
+//!
+//! What kind of failure a panic was, for scripts watching the kernel. A
+//! call site that knows panics through [`panic_with!`], which records a
+//! [`PanicCode`] before panicking as usual; anything else is
+//! [`PanicCode::Explicit`]. The panic handler ends its report with a
+//! single line in a fixed format,
+//!
+//! ```text
+//! ##PANIC code=Corruption subsystem=page hart=0 at=src/page.rs:150##
+//! ```
+//!
+//! which doesn't change when the human readable message above it does.
+//! Booting with `panic=exit` also makes QEMU exit with a status that says
+//! which code it was, instead of 1 for every panic.
+
+use spin::Mutex;
+
+pub use oslib::panic_code::{Access, KernelPanic, PanicCode};
+
+/// The code of the panic about to happen, set by `panic_with!`
+static CURRENT: Mutex<Option<PanicCode>> = Mutex::new(None);
+
+/// Record `code` for the panic that follows. Use [`panic_with!`] rather
+/// than calling this directly.
+pub fn set(code: PanicCode) {
+    *CURRENT.lock() = Some(code);
+}
+
+/// The code recorded for this panic, `Explicit` if none was
+pub fn current() -> PanicCode {
+    CURRENT
+        .try_lock()
+        .and_then(|code| *code)
+        .unwrap_or(PanicCode::Explicit)
+}
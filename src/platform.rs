@@ -0,0 +1,142 @@
+//! # Platform profiles
+//!
+//! Everything the kernel assumes about the machine it runs on before it
+//! can discover devices for itself: where the console is and what kind of
+//! UART drives it, where RAM starts, where the CLINT and PLIC live. One
+//! profile is picked at boot by matching the device tree root's
+//! `compatible`, or forced with `platform=<name>` on the command line.
+
+pub use oslib::platform::{detect, Environment};
+
+use crate::fdt::Fdt;
+use crate::{cmdline, globals};
+
+/// The UART flavours the console can drive
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UartKind {
+    /// NS16550A compatible, polled through the line status register
+    Ns16550a,
+    /// `sifive,uart0`, with separate TX/RX data registers carrying full and
+    /// empty flags
+    Sifive,
+}
+
+/// The boot hart has no supervisor mode, so `satp` doesn't exist on it and
+/// page tables can be built but never activated there. On sifive_u hart 0
+/// is the E51 monitor core.
+pub const QUIRK_BOOT_HART_NO_SUPERVISOR: u32 = 1 << 0;
+
+/// A row of equally spaced device windows, some of which may be empty
+#[derive(Copy, Clone, Debug)]
+pub struct MmioSlots {
+    pub base: usize,
+    pub count: usize,
+    pub stride: usize,
+}
+
+/// What we need to know about a machine before looking at its device tree
+pub struct Platform {
+    /// Name used by `platform=` on the command line
+    pub name: &'static str,
+    /// Root `compatible` entry identifying the machine
+    pub compatible: &'static str,
+    /// Kind and base address of the UART used as the early console
+    pub console: UartKind,
+    pub console_base: usize,
+    /// Where RAM starts. The linker script must agree.
+    pub memory_base: usize,
+    pub clint_base: usize,
+    pub plic_base: usize,
+    /// `sifive,test0` device used to power off or reboot, if any
+    pub test_device: Option<usize>,
+    /// Where virtio-mmio transports sit, if the machine has any
+    pub virtio_mmio: Option<MmioSlots>,
+    /// Frequency `mtime` ticks at, used when the device tree doesn't say
+    pub timebase_frequency: u64,
+    /// Whether a device tree is passed in `a1` at boot
+    pub has_dtb: bool,
+    /// `QUIRK_*` flags
+    pub quirks: u32,
+}
+
+impl Platform {
+    pub const fn has_quirk(&self, quirk: u32) -> bool {
+        self.quirks & quirk != 0
+    }
+}
+
+/// `qemu-system-riscv64 -machine virt`
+pub static VIRT: Platform = Platform {
+    name: "virt",
+    compatible: "riscv-virtio",
+    console: UartKind::Ns16550a,
+    console_base: 0x1000_0000,
+    memory_base: 0x8000_0000,
+    clint_base: 0x0200_0000,
+    plic_base: 0x0c00_0000,
+    test_device: Some(0x10_0000),
+    virtio_mmio: Some(MmioSlots {
+        base: 0x1000_1000,
+        count: 8,
+        stride: 0x1000,
+    }),
+    timebase_frequency: 10_000_000,
+    has_dtb: true,
+    quirks: 0,
+};
+
+/// `qemu-system-riscv64 -machine sifive_u`
+pub static SIFIVE_U: Platform = Platform {
+    name: "sifive_u",
+    compatible: "sifive,hifive-unleashed-a00",
+    console: UartKind::Sifive,
+    console_base: 0x1001_0000,
+    memory_base: 0x8000_0000,
+    clint_base: 0x0200_0000,
+    plic_base: 0x0c00_0000,
+    test_device: Some(0x10_0000),
+    virtio_mmio: None,
+    timebase_frequency: 10_000_000,
+    has_dtb: true,
+    quirks: QUIRK_BOOT_HART_NO_SUPERVISOR,
+};
+
+static PROFILES: [&Platform; 2] = [&VIRT, &SIFIVE_U];
+
+/// Pick the profile for the machine we're running on. The command line
+/// wins over the device tree; without either we assume virt.
+pub fn init(fdt: Option<&Fdt>) -> &'static Platform {
+    globals::ENVIRONMENT.init(detect(fdt));
+    globals::PLATFORM.init(select(fdt))
+}
+
+/// What we detected we are running on
+pub fn environment() -> Environment {
+    globals::ENVIRONMENT
+        .try_get()
+        .copied()
+        .unwrap_or(Environment::Unknown)
+}
+
+fn select(fdt: Option<&Fdt>) -> &'static Platform {
+    if let Some(name) = cmdline::get("platform") {
+        if let Some(profile) = PROFILES.iter().find(|p| p.name == name) {
+            return profile;
+        }
+    }
+
+    let root = fdt.and_then(|fdt| fdt.root());
+    if let Some(root) = root {
+        if let Some(profile) = PROFILES.iter().find(|p| root.is_compatible(p.compatible)) {
+            return profile;
+        }
+    }
+
+    &VIRT
+}
+
+/// The selected profile. Until one is selected the virt profile is used,
+/// so the early console has somewhere to go.
+pub fn current() -> &'static Platform {
+    globals::PLATFORM.try_get().copied().unwrap_or(&VIRT)
+}
@@ -0,0 +1,74 @@
+//! # Platform-Level Interrupt Controller
+//!
+//! Routes external interrupts from devices to harts. We only use the boot
+//! hart's M-mode context, which is context 0 on both virt and sifive_u.
+//!
+//! Every interrupt claimed through [`dispatch`] is counted, and [`stats`]
+//! reports those counts next to the PLIC's pending bits. An IRQ that is
+//! pending but never counted is being raised but not delivered (not
+//! enabled, or its priority is at or below the threshold); one that is
+//! neither was never raised by its device.
+
+pub use oslib::plic::{IrqCounters, IrqStats, MAX_IRQS};
+
+use crate::mmio::{DeviceMemory, MmioError};
+use crate::{globals, platform, uart};
+
+/// Times each IRQ has been claimed by [`dispatch`]
+static FIRE_COUNTS: IrqCounters = IrqCounters::new();
+
+/// The interrupt controller, driving its registers through
+/// [`DeviceMemory`]. Its register logic and counters live in `oslib`,
+/// where they are tested against a fake PLIC.
+pub type Plic = oslib::plic::Plic<DeviceMemory>;
+
+/// Whether the trap handler dispatches external interrupts. It doesn't
+/// yet: it masks MEIE the first time one arrives, so an enabled source
+/// would never be claimed and nothing else would interrupt the hart.
+pub const INTERRUPTS_HANDLED: bool = false;
+
+/// Why an interrupt couldn't be routed to the hart
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RouteError {
+    /// The PLIC wasn't claimed at boot
+    NoPlic,
+    /// Nothing would claim and complete the interrupt
+    Unhandled,
+}
+
+/// Let `irq` interrupt the boot hart. Refused while
+/// [`INTERRUPTS_HANDLED`] is false, leaving the source disabled.
+pub fn route(irq: u32) -> Result<(), RouteError> {
+    let plic = globals::PLIC.try_get().ok_or(RouteError::NoPlic)?;
+    if !INTERRUPTS_HANDLED {
+        return Err(RouteError::Unhandled);
+    }
+    plic.set_priority(irq, 1);
+    plic.enable(irq);
+    Ok(())
+}
+
+/// Claim the PLIC and let every priority through to the boot hart
+pub fn init() -> Result<(), MmioError> {
+    let regs = DeviceMemory::claim(
+        "plic",
+        platform::current().plic_base,
+        oslib::plic::MMIO_SIZE,
+    )?;
+    let plic = globals::PLIC.init(Plic::new(regs, 0));
+    plic.set_threshold(0);
+    Ok(())
+}
+
+/// Claim every pending IRQ, count it, hand it to `handler` and complete it
+pub fn dispatch(handler: impl FnMut(u32)) {
+    oslib::plic::dispatch(globals::PLIC.get(), &FIRE_COUNTS, handler);
+}
+
+pub fn stats() -> IrqStats {
+    IrqStats {
+        counts: FIRE_COUNTS.counts(),
+        pending: globals::PLIC.try_get().map_or(0, Plic::pending),
+        rx_overruns: uart::overruns(),
+    }
+}
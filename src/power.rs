@@ -0,0 +1,111 @@
+//! # Power
+//!
+//! Turning the machine off. QEMU's `sifive,test0` device powers off or
+//! resets the machine when a magic value is written to it; we run in
+//! M-mode without an SBI underneath, so it is the only way out. It is only
+//! used when we have detected we are running under QEMU.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::mmio::DeviceMemory;
+use crate::{abort, console, heartbeat, platform, plic, time};
+
+/// Size of the test device's register block
+const TEST_DEVICE_SIZE: usize = 0x1000;
+
+/// Power off with a failure. QEMU exits with the status in the upper 16
+/// bits.
+const TEST_FAIL: u32 = 0x3333;
+const TEST_PASS: u32 = 0x5555;
+const TEST_RESET: u32 = 0x7777;
+
+/// Set once a shutdown has begun
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Why the machine is going down
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ShutdownReason {
+    /// The kernel ran out of things to do
+    Finished,
+    /// Somebody asked for it
+    Requested,
+    /// The kernel panicked, with the status QEMU should exit with
+    Panic(u16),
+}
+
+/// Whether a shutdown has begun. Long running work should check this and
+/// wind down.
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+/// Orderly shutdown: tell the rest of the kernel we are stopping, report
+/// how long we were up and what the interrupts did, make sure the console
+/// has written all of it, then power off.
+///
+/// A second shutdown while one is in progress, say from a panic in the
+/// middle of it, skips straight to [`force_shutdown`].
+pub fn shutdown(reason: ShutdownReason) -> ! {
+    if STOPPING.swap(true, Ordering::Relaxed) {
+        force_shutdown(reason);
+    }
+
+    let uptime_ms = time::uptime_us() / 1_000;
+    println!(
+        "shutting down ({:?}) after {}.{:03}s",
+        reason,
+        uptime_ms / 1_000,
+        uptime_ms % 1_000
+    );
+    let stats = plic::stats();
+    println!(
+        "  {} interrupts, {} receive overruns",
+        stats.counts.iter().sum::<usize>(),
+        stats.rx_overruns
+    );
+    console::flush();
+
+    power_off_with(reason)
+}
+
+/// Power off without any of the orderly steps, for when the kernel can no
+/// longer be trusted to take them
+pub fn force_shutdown(reason: ShutdownReason) -> ! {
+    STOPPING.store(true, Ordering::Relaxed);
+    power_off_with(reason)
+}
+
+fn power_off_with(reason: ShutdownReason) -> ! {
+    match reason {
+        ShutdownReason::Panic(status) => {
+            write_test_device(TEST_FAIL | (u32::from(status) << 16));
+            heartbeat::halt()
+        }
+        _ => poweroff(),
+    }
+}
+
+/// Power the machine off. Without a test device the hart is parked instead.
+pub fn poweroff() -> ! {
+    write_test_device(TEST_PASS);
+    abort()
+}
+
+/// Reset the machine. Without a test device the hart is parked instead.
+pub fn reboot() -> ! {
+    write_test_device(TEST_RESET);
+    abort()
+}
+
+fn write_test_device(value: u32) {
+    // On real hardware whatever sits at that address isn't a test device
+    if platform::environment() != platform::Environment::Qemu {
+        return;
+    }
+    let Some(base) = platform::current().test_device else {
+        return;
+    };
+    if let Ok(test) = DeviceMemory::claim("test", base, TEST_DEVICE_SIZE) {
+        let _ = test.write32(0, value);
+    }
+}
@@ -0,0 +1,66 @@
+//! # Reserved memory
+//!
+//! Memory the device tree keeps away from the frame allocator: the
+//! header's reservation block and the `/reserved-memory` carveouts. The
+//! carving is done by [`oslib::reserved_memory`]; this keeps the result
+//! in [`globals::CARVEOUTS`] for drivers to find theirs.
+//!
+//! Carveouts marked `no-map` are never mapped by the kernel unless a
+//! driver asks for them. The kernel only maps its own image and claimed
+//! devices, so that holds for every carveout.
+
+use core::ops::Range;
+
+pub use oslib::reserved_memory::{Carveout, Carveouts, Skipped};
+
+use crate::fdt::Fdt;
+use crate::globals;
+
+/// Find the memory the device tree reserves and take it out of `frames`,
+/// the range the frame allocator is about to hand out, returning what is
+/// left of it
+pub fn init(fdt: Option<&Fdt>, frames: Range<usize>) -> Range<usize> {
+    let Some(fdt) = fdt else {
+        globals::CARVEOUTS.init(Carveouts::new());
+        return frames;
+    };
+
+    for (base, size) in fdt.reservations() {
+        log!(
+            Info,
+            "reserved-memory: memreserve at {:#x}..{:#x}",
+            base,
+            base.saturating_add(size)
+        );
+    }
+
+    let (carveouts, frames) =
+        oslib::reserved_memory::carve(fdt, frames, |node, reason| match reason {
+            Skipped::Full => log!(Warn, "reserved-memory: no room for {}", node.name()),
+            Skipped::NoSize => log!(
+                Warn,
+                "reserved-memory: {} has neither reg nor size",
+                node.name()
+            ),
+            Skipped::NoRoom { size, alignment } => log!(
+                Warn,
+                "reserved-memory: no room for {} ({:#x} bytes, aligned to {:#x})",
+                node.name(),
+                size,
+                alignment
+            ),
+        });
+
+    for carveout in carveouts.iter() {
+        log!(
+            Info,
+            "reserved-memory: {} at {:#x}..{:#x}{}",
+            carveout.name,
+            carveout.range.start,
+            carveout.range.end,
+            if carveout.no_map { ", no-map" } else { "" }
+        );
+    }
+    globals::CARVEOUTS.init(carveouts);
+    frames
+}
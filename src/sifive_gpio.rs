@@ -0,0 +1,26 @@
+use crate::fdt::Node;
+use crate::gpio::GpioError;
+use crate::mmio::DeviceMemory;
+
+pub const COMPATIBLE: &str = "sifive,gpio0";
+
+/// Size of the register block
+pub const MMIO_SIZE: usize = 0x1000;
+
+/// Pins the controller has when the device tree doesn't say
+const DEFAULT_PINS: u32 = 16;
+
+/// The SiFive GPIO controller, driving its registers through
+/// [`DeviceMemory`]. Its register logic lives in `oslib`, where it is
+/// tested against fake registers.
+pub type SifiveGpio = oslib::sifive_gpio::SifiveGpio<DeviceMemory>;
+
+/// Claim the controller `node` describes, laid out as `parent` says
+pub fn from_node(node: &Node, parent: &Node) -> Result<SifiveGpio, GpioError> {
+    let (base, size) = node.reg(parent).next().ok_or(GpioError::NoRegion)?;
+    Ok(SifiveGpio::new(
+        DeviceMemory::claim("gpio", base, size.min(MMIO_SIZE))?,
+        node.property_u32("ngpios").unwrap_or(DEFAULT_PINS),
+        node.property("interrupts").unwrap_or(&[]),
+    ))
+}
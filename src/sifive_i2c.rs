@@ -0,0 +1,35 @@
+use crate::fdt::Node;
+use crate::i2c::I2cError;
+use crate::mmio::DeviceMemory;
+use crate::time;
+
+pub const COMPATIBLE: &str = "sifive,i2c0";
+
+/// Size of the register block
+pub const MMIO_SIZE: usize = 0x1000;
+
+/// Bus clock used unless the device tree says otherwise
+const DEFAULT_BUS_HZ: u32 = 100_000;
+/// Peripheral clock assumed when the node doesn't give one
+const DEFAULT_INPUT_HZ: u32 = 500_000_000;
+
+/// The SiFive I2C controller, driving its registers through
+/// [`DeviceMemory`]. Its register logic lives in `oslib`, along with the
+/// prescaler calculation and its tests.
+pub type SifiveI2c = oslib::sifive_i2c::SifiveI2c<DeviceMemory>;
+
+/// Claim the controller `node` describes, laid out as `parent` says, and
+/// enable it at the node's `clock-frequency`
+pub fn from_node(node: &Node, parent: &Node) -> Result<SifiveI2c, I2cError> {
+    let (base, size) = node.reg(parent).next().ok_or(I2cError::NoRegion)?;
+    let bus = SifiveI2c::new(
+        DeviceMemory::claim("i2c", base, size.min(MMIO_SIZE))?,
+        time::clock(),
+    );
+
+    let bus_hz = node
+        .property_u32("clock-frequency")
+        .unwrap_or(DEFAULT_BUS_HZ);
+    bus.enable(DEFAULT_INPUT_HZ, bus_hz)?;
+    Ok(bus)
+}
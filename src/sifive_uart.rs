@@ -0,0 +1,67 @@
+use core::fmt::{Error, Write};
+
+use crate::mmio::DeviceMemory;
+
+/// Size of the register block
+pub const MMIO_SIZE: usize = 0x1000;
+
+/// Transmit data register. Bit 31 reads as 1 while the TX FIFO is full.
+const TXDATA: usize = 0x00;
+/// Receive data register. Bit 31 reads as 1 when the RX FIFO was empty.
+const RXDATA: usize = 0x04;
+const TXCTRL: usize = 0x08;
+const RXCTRL: usize = 0x0c;
+
+const FIFO_FLAG: u32 = 1 << 31;
+
+/// # SiFive UART
+///
+/// The `sifive,uart0` found on the HiFive Unleashed and QEMU's sifive_u.
+/// Unlike the NS16550A it has no line status register; the data registers
+/// themselves say whether the FIFOs are full or empty.
+pub struct SifiveUart {
+    regs: DeviceMemory,
+}
+
+impl SifiveUart {
+    /// Wrap the structure
+    pub fn new(regs: DeviceMemory) -> Self {
+        Self { regs }
+    }
+
+    /// Enable the transmitter and receiver
+    pub fn init(&mut self) {
+        let _ = self.regs.write32(TXCTRL, 1 << 0);
+        let _ = self.regs.write32(RXCTRL, 1 << 0);
+    }
+
+    pub fn put(&self, c: u8) {
+        // wait for room in the TX FIFO
+        while self
+            .regs
+            .read32(TXDATA)
+            .is_ok_and(|data| data & FIFO_FLAG != 0)
+        {}
+        let _ = self.regs.write32(TXDATA, c as u32);
+    }
+
+    pub fn get(&self) -> Option<u8> {
+        let data = self.regs.read32(RXDATA).ok()?;
+        if data & FIFO_FLAG != 0 {
+            // The FIFO was empty
+            None
+        } else {
+            Some(data as u8)
+        }
+    }
+}
+
+impl Write for SifiveUart {
+    fn write_str(&mut self, out: &str) -> Result<(), Error> {
+        for c in out.bytes() {
+            self.put(c);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! # Secondary harts
+//!
+//! Every hart but the boot hart parks in boot.s as soon as it starts. It
+//! waits there with only the machine software interrupt enabled, and
+//! global interrupts off, so the interrupt wakes `wfi` without trapping.
+//! Each time it wakes it looks at its slot of [`HART_STACKS`].
+//!
+//! [`start_harts`] gives each hart the device tree lists a stack and a
+//! trap stack, fills in its slot and interrupts it. The hart switches to
+//! the stack and enters [`kmain_hart`], which sets up its trap stack and
+//! waits for work with interrupts on.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Once;
+
+use crate::fdt::Fdt;
+use crate::ipi::{self, IpiWork, QUEUED_HARTS};
+use crate::mmio::MmioError;
+use crate::page::PageError;
+use crate::stack::{self, StackInfo, DEFAULT_STACK_PAGES};
+use crate::tlb::{current_hart, hart_bit};
+use crate::{csr, time};
+
+/// How long to wait for a hart to come up
+pub const START_TIMEOUT_US: u64 = 100_000;
+
+/// Top of the stack each parked hart is to switch to, or zero while it has
+/// none. boot.s reads it before the boot hart has cleared the BSS, so it
+/// lives in .data, which is loaded rather than cleared. Harts without a
+/// slot stay parked.
+#[no_mangle]
+#[link_section = ".data.hart_stacks"]
+static HART_STACKS: [AtomicUsize; QUEUED_HARTS] = [const { AtomicUsize::new(0) }; QUEUED_HARTS];
+
+/// Each hart's stacks, set before its slot of [`HART_STACKS`]
+static STACKS: [Once<HartStacks>; QUEUED_HARTS] = [const { Once::new() }; QUEUED_HARTS];
+
+/// Harts that have their trap stack and take interrupts
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// The stacks a hart was given
+#[derive(Copy, Clone, Debug)]
+pub struct HartStacks {
+    /// What the hart runs on. The boot hart's comes from the linker
+    /// script, so it has none.
+    pub kernel: Option<StackInfo>,
+    /// What traps run on, through `mscratch`
+    pub trap: StackInfo,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StartError {
+    Page(PageError),
+    Mmio(MmioError),
+    /// The hart didn't come up within [`START_TIMEOUT_US`]
+    Timeout,
+}
+
+impl From<PageError> for StartError {
+    fn from(error: PageError) -> Self {
+        Self::Page(error)
+    }
+}
+
+impl From<MmioError> for StartError {
+    fn from(error: MmioError) -> Self {
+        Self::Mmio(error)
+    }
+}
+
+/// Harts that have come up, the boot hart included once [`init`] has run
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Give the boot hart, the one we are running on, a trap stack
+pub fn init() -> Result<(), PageError> {
+    let hart = current_hart();
+    let trap = stack::alloc_kernel_stack(DEFAULT_STACK_PAGES)?;
+    let stacks = STACKS[hart].call_once(|| HartStacks { kernel: None, trap });
+    enter(hart, stacks);
+    Ok(())
+}
+
+/// Bring up every other hart the device tree lists, one at a time,
+/// returning the harts online afterwards
+pub fn start_harts(fdt: Option<&Fdt>) -> usize {
+    let boot = current_hart();
+    for hart in harts(fdt).filter(|&hart| hart != boot) {
+        if hart >= QUEUED_HARTS {
+            log!(
+                Warn,
+                "smp: hart {} left parked, only {} are supported",
+                hart,
+                QUEUED_HARTS
+            );
+            continue;
+        }
+        if let Err(error) = start(hart) {
+            log!(Warn, "smp: hart {} didn't start: {:?}", hart, error);
+        }
+    }
+    online()
+}
+
+/// The IDs of the harts the device tree lists
+fn harts(fdt: Option<&Fdt>) -> impl Iterator<Item = usize> {
+    let cpus = fdt.and_then(|fdt| fdt.find_node("/cpus"));
+    cpus.into_iter().flat_map(|cpus| {
+        cpus.children()
+            .filter(|cpu| cpu.property_str("device_type") == Some("cpu"))
+            .filter(|cpu| cpu.property_str("status") != Some("disabled"))
+            .filter_map(move |cpu| cpu.reg(&cpus).next())
+            .map(|(hart, _)| hart)
+    })
+}
+
+/// Hand `hart` its stacks and wait for it to come up
+fn start(hart: usize) -> Result<(), StartError> {
+    if STACKS[hart].is_completed() {
+        // Already started
+        return Ok(());
+    }
+    let kernel = stack::alloc_kernel_stack(DEFAULT_STACK_PAGES)?;
+    let trap = stack::alloc_kernel_stack(DEFAULT_STACK_PAGES)?;
+    STACKS[hart].call_once(|| HartStacks {
+        kernel: Some(kernel),
+        trap,
+    });
+
+    HART_STACKS[hart].store(kernel.top, Ordering::Release);
+    ipi::send_ipi(hart, IpiWork::NONE)?;
+
+    let deadline = time::deadline_us(START_TIMEOUT_US);
+    while online() & hart_bit(hart) == 0 {
+        if time::has_passed(&deadline) {
+            return Err(StartError::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Guard this hart's stacks, switch traps onto its trap stack and start
+/// taking interrupts
+fn enter(hart: usize, stacks: &HartStacks) {
+    for (entry, stack) in [Some(stacks.trap), stacks.kernel]
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        if !stack::guard(&stack, entry) {
+            log!(
+                Warn,
+                "smp: hart {} has no PMP entry {} to guard its stack",
+                hart,
+                entry
+            );
+        }
+    }
+    csr::mscratch::write(stacks.trap.top);
+    csr::mie::set_bits(1 << csr::Interrupt::MachineSoftware.code());
+    csr::mstatus::set_bits(csr::Mstatus::MIE);
+    ONLINE.fetch_or(hart_bit(hart), Ordering::Release);
+}
+
+/// Where a secondary hart goes from boot.s, on the stack [`start_harts`]
+/// gave it
+#[no_mangle]
+extern "C" fn kmain_hart(hart: usize) -> ! {
+    // The interrupt that woke us is still pending; nothing was queued with it
+    let _ = ipi::take_ipi(hart);
+    let stacks = STACKS[hart].get().expect("hart started without stacks");
+    enter(hart, stacks);
+    loop {
+        unsafe { core::arch::asm!("wfi") };
+    }
+}
@@ -0,0 +1,60 @@
+//! # Kernel stacks
+//!
+//! Stacks beyond the boot hart's, which comes from the linker script: one
+//! for each secondary hart to run on, and one per hart for traps. Each is
+//! a run of contiguous frames laid out by [`oslib::stack`], identity mapped
+//! into the kernel's page table with the lowest page left out as a guard.
+//!
+//! The kernel runs in M-mode, which the page tables don't apply to, so
+//! leaving the guard unmapped isn't enough on its own. The hart using a
+//! stack also covers its guard with a locked PMP entry that allows
+//! nothing, and an overflow faults there instead of writing over whatever
+//! lies below. Locked entries stay until reset, which is fine as stacks
+//! are never freed.
+
+pub use oslib::stack::StackInfo;
+
+use crate::csr::{self, PmpLockout};
+use crate::globals;
+use crate::page::{alloc_contiguous_pages, free_page, PageError, PAGE_SIZE};
+
+/// Stack size used for secondary harts and trap handling
+pub const DEFAULT_STACK_PAGES: usize = 4;
+
+/// PMP entries a hart guards its stacks with, one each
+pub const GUARD_ENTRIES: usize = 2;
+
+/// Allocate a stack of `pages` pages and map it into the kernel's address
+/// space above an unmapped guard page
+pub fn alloc_kernel_stack(pages: usize) -> Result<StackInfo, PageError> {
+    assert!(pages > 0, "a stack needs at least one page");
+    let base = alloc_contiguous_pages(pages + 1).ok_or(PageError::OutOfMemory)?;
+    let stack = StackInfo::new(base, pages);
+
+    let mut kernel_pages = globals::KERNEL_PAGES.get().lock();
+    if let Err(error) = kernel_pages.map_stack(&stack) {
+        // Whatever was mapped comes first; the unmapping stops where it ends
+        let _ = kernel_pages.unmap_range(stack.bottom, stack.top - stack.bottom);
+        for frame in (stack.guard..stack.top).step_by(PAGE_SIZE) {
+            free_page(frame);
+        }
+        return Err(error);
+    }
+    Ok(stack)
+}
+
+/// Make the guard page of `stack` fault on this hart, through PMP entry
+/// `entry` (below [`GUARD_ENTRIES`]). Returns false if the hart has no
+/// such entry, leaving the guard unprotected.
+pub fn guard(stack: &StackInfo, entry: usize) -> bool {
+    assert!(
+        entry < GUARD_ENTRIES,
+        "PMP entry {} isn't for guards",
+        entry
+    );
+    let lockout = PmpLockout {
+        base: stack.guard,
+        size: PAGE_SIZE,
+    };
+    csr::write_lockout(lockout, entry)
+}
@@ -0,0 +1,146 @@
+//! # System info
+//!
+//! One snapshot of what the kernel can say about itself, to paste into a
+//! bug report. Each section reads a subsystem's own stats and prints "not
+//! initialized" in their place if the subsystem isn't up yet, so the
+//! snapshot can be printed at any point of boot. Booting with `sysinfo`
+//! on the command line prints it once boot is done, and typing `irqstats`
+//! at the console prints just the interrupt section.
+
+use crate::{buildinfo, features, globals, mmio, page, platform, plic, time, tlb};
+
+/// Print every section
+pub fn print() {
+    print_build();
+    print_platform();
+    print_memory();
+    print_devices();
+    print_irqs();
+    print_paging();
+}
+
+fn section(name: &str) {
+    println!("[{}]", name);
+}
+
+fn not_initialized() {
+    println!("  not initialized");
+}
+
+fn print_build() {
+    section("build");
+    println!(
+        "  os {} ({}) built {}",
+        buildinfo::VERSION,
+        buildinfo::COMMIT,
+        buildinfo::BUILD_TIME
+    );
+    println!("  features {}", features::Enabled);
+}
+
+fn print_platform() {
+    section("platform");
+    let platform = platform::current();
+    println!(
+        "  {} ({}) on {:?}",
+        platform.name,
+        platform.compatible,
+        platform::environment()
+    );
+    let uptime_ms = time::uptime_us() / 1_000;
+    println!(
+        "  up {}.{:03}s, timebase {} Hz",
+        uptime_ms / 1_000,
+        uptime_ms % 1_000,
+        time::timebase_frequency()
+    );
+}
+
+fn print_memory() {
+    section("memory");
+    let Some(frames) = page::frame_stats() else {
+        return not_initialized();
+    };
+    println!(
+        "  {} of {} frames free ({} KiB)",
+        frames.free,
+        frames.total,
+        frames.free * page::PAGE_SIZE / 1024
+    );
+    if let Some(carveouts) = globals::CARVEOUTS.try_get() {
+        for carveout in carveouts.iter() {
+            println!(
+                "  reserved {} {:#x}..{:#x}",
+                carveout.name, carveout.range.start, carveout.range.end
+            );
+        }
+    }
+}
+
+fn print_devices() {
+    section("devices");
+    let mut claimed = 0;
+    mmio::for_each_claim(|name, range| {
+        println!("  {:8} {:#x}..{:#x}", name, range.start, range.end);
+        claimed += 1;
+    });
+    if claimed == 0 {
+        println!("  none claimed");
+    }
+}
+
+/// Print the interrupt counters on their own, for the `irqstats` command
+pub fn print_irqs() {
+    section("irqs");
+    if globals::PLIC.try_get().is_none() {
+        return not_initialized();
+    }
+    let stats = plic::stats();
+    for (irq, &count) in stats.counts.iter().enumerate() {
+        if count > 0 || stats.is_pending(irq as u32) {
+            println!(
+                "  irq {:3}: {}{}",
+                irq,
+                count,
+                if stats.is_pending(irq as u32) {
+                    ", pending"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+    println!("  {} receive overruns", stats.rx_overruns);
+}
+
+fn print_paging() {
+    section("paging");
+    let Some(kernel_pages) = globals::KERNEL_PAGES.try_get() else {
+        return not_initialized();
+    };
+    // Whoever asked may be in the middle of changing the table
+    match kernel_pages.try_lock() {
+        Some(pages) => println!(
+            "  kernel table at {:#x}, fingerprint {:#018x}",
+            pages.root(),
+            pages.layout_fingerprint()
+        ),
+        None => println!("  kernel table busy"),
+    }
+    if let Some(asids) = globals::ASIDS.try_get() {
+        let allocator = asids.lock();
+        let stats = allocator.stats();
+        println!(
+            "  {} ASID bits, {} flushes avoided, {} forced, {} rollovers",
+            allocator.bits(),
+            stats.flushes_avoided,
+            stats.flushes_forced,
+            stats.rollovers
+        );
+    }
+    let shootdowns = tlb::stats();
+    println!(
+        "  {} shootdown IPIs, {} harts skipped, {} timeouts",
+        shootdowns.ipis_sent, shootdowns.harts_skipped, shootdowns.timeouts
+    );
+}
@@ -0,0 +1,113 @@
+//! # Machine timer
+//!
+//! Time keeping backed by the CLINT's free-running `mtime` counter. This is
+//! usable before interrupts or any scheduler are up, since it only reads
+//! a memory mapped register.
+//!
+//! Everything here reads the time through a [`Clock`], the source and its
+//! frequency, whose logic lives in oslib and is tested there against a
+//! mock source. Waits measure against a [`Deadline`], which gives up
+//! rather than spinning forever if the CLINT isn't there to count.
+
+pub use oslib::time::{Clock, Deadline, TimeSource};
+
+use crate::fdt::Fdt;
+use crate::mmio::DeviceMemory;
+use crate::{globals, platform};
+
+/// Size of the CLINT's register block
+pub const CLINT_SIZE: usize = 0x1_0000;
+
+/// Offset of the 64-bit `mtime` register from the CLINT base
+pub const MTIME_OFFSET: usize = 0xbff8;
+
+/// The CLINT's `mtime` counter
+pub struct ClintTime;
+
+impl TimeSource for ClintTime {
+    fn now_ticks(&self) -> u64 {
+        globals::CLINT
+            .try_get()
+            .and_then(|clint| clint.read64(MTIME_OFFSET).ok())
+            .unwrap_or(0)
+    }
+
+    fn is_running(&self) -> bool {
+        globals::CLINT
+            .try_get()
+            .is_some_and(|clint| clint.read64(MTIME_OFFSET).is_ok())
+    }
+}
+
+pub static CLINT_TIME: ClintTime = ClintTime;
+
+/// Claim the CLINT, take the timer frequency from the
+/// `timebase-frequency` property of the device tree's `/cpus` node,
+/// falling back to the platform's default.
+pub fn init(fdt: Option<&Fdt>) {
+    let clint = DeviceMemory::claim("clint", platform::current().clint_base, CLINT_SIZE)
+        .expect("couldn't claim the CLINT");
+    globals::CLINT.init(clint);
+
+    let frequency = fdt
+        .and_then(|fdt| fdt.find_node("/cpus"))
+        .and_then(|cpus| cpus.property_u32("timebase-frequency"))
+        .map_or(platform::current().timebase_frequency, u64::from);
+
+    globals::TIMEBASE_FREQUENCY.init(frequency);
+}
+
+/// The source the kernel reads the time from
+pub fn source() -> &'static dyn TimeSource {
+    &CLINT_TIME
+}
+
+/// Frequency `mtime` ticks at, in Hz
+pub fn timebase_frequency() -> u64 {
+    globals::TIMEBASE_FREQUENCY
+        .try_get()
+        .copied()
+        .unwrap_or(platform::current().timebase_frequency)
+}
+
+/// The kernel's time source at its frequency. The functions below all go
+/// through it.
+pub fn clock() -> Clock<'static> {
+    Clock::new(source(), timebase_frequency())
+}
+
+/// Ticks of the current time source
+pub fn now_ticks() -> u64 {
+    source().now_ticks()
+}
+
+/// Microseconds since the time source started counting
+pub fn uptime_us() -> u64 {
+    clock().uptime_us()
+}
+
+/// Convert microseconds into `mtime` ticks, saturating for spans too long
+/// to count
+pub fn us_to_ticks(us: u64) -> u64 {
+    oslib::time::us_to_ticks(us, timebase_frequency())
+}
+
+/// A deadline `us` microseconds from now
+pub fn deadline_us(us: u64) -> Deadline {
+    clock().deadline_us(us)
+}
+
+/// Whether `deadline` has passed. Without a running clock every deadline
+/// has.
+pub fn has_passed(deadline: &Deadline) -> bool {
+    clock().has_passed(deadline)
+}
+
+/// Busy wait until `us` microseconds have elapsed, or not at all if there
+/// is no clock to measure them by.
+///
+/// Unlike a nop loop this doesn't depend on how fast the host runs us, so
+/// it can be used for hardware settle times during boot.
+pub fn delay_us(us: u64) {
+    clock().delay_us(us)
+}
@@ -0,0 +1,154 @@
+//! # TLB shootdown
+//!
+//! A hart only drops the translations it has cached when it runs
+//! `sfence.vma` itself, so changing an address space another hart has run
+//! means asking that hart to flush too. Asking once per page would cost an
+//! interrupt per page, so changes are collected into a [`TlbBatch`] and
+//! each hart that needs it gets one interrupt for the whole batch. Past
+//! [`BATCH_PAGES`] pages the batch stops listing them and asks for the
+//! address space's whole ASID to be flushed instead.
+//!
+//! The batch travels in the target's mailbox and the interrupt only says
+//! one is waiting. The target acknowledges once it has flushed, and the
+//! initiator spins until every hart it interrupted has or
+//! [`SHOOTDOWN_TIMEOUT_US`] passes. A hart that takes too long is logged
+//! and left to flush when it gets round to it; its mailbox keeps the batch
+//! and merges in anything sent meanwhile.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use oslib::tlb::Mailboxes;
+pub use oslib::tlb::{hart_bit, TlbBatch, BATCH_PAGES};
+
+use crate::ipi::{self, IpiWork, QUEUED_HARTS};
+use crate::{csr, time};
+
+/// How long to wait for harts to acknowledge a shootdown
+pub const SHOOTDOWN_TIMEOUT_US: u64 = 10_000;
+
+/// Batches waiting for each hart, and which harts haven't flushed theirs
+static MAILBOXES: Mailboxes<QUEUED_HARTS> = Mailboxes::new();
+
+/// Only one initiator waits on the mailboxes at a time
+static INITIATOR: Mutex<()> = Mutex::new(());
+
+/// How shootdowns have gone
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct ShootdownStats {
+    pub ipis_sent: u64,
+    /// Harts that didn't need a flush as they hadn't run the address space
+    pub harts_skipped: u64,
+    /// Pages flushed one at a time, counted per hart
+    pub page_flushes: u64,
+    /// Flushes of a whole ASID or the whole TLB, counted per hart
+    pub full_flushes: u64,
+    /// Shootdowns that gave up waiting for a hart
+    pub timeouts: u64,
+}
+
+static IPIS_SENT: AtomicU64 = AtomicU64::new(0);
+static HARTS_SKIPPED: AtomicU64 = AtomicU64::new(0);
+static PAGE_FLUSHES: AtomicU64 = AtomicU64::new(0);
+static FULL_FLUSHES: AtomicU64 = AtomicU64::new(0);
+static TIMEOUTS: AtomicU64 = AtomicU64::new(0);
+
+pub fn stats() -> ShootdownStats {
+    ShootdownStats {
+        ipis_sent: IPIS_SENT.load(Ordering::Relaxed),
+        harts_skipped: HARTS_SKIPPED.load(Ordering::Relaxed),
+        page_flushes: PAGE_FLUSHES.load(Ordering::Relaxed),
+        full_flushes: FULL_FLUSHES.load(Ordering::Relaxed),
+        timeouts: TIMEOUTS.load(Ordering::Relaxed),
+    }
+}
+
+/// The hart we are running on
+pub fn current_hart() -> usize {
+    csr::mhartid::read()
+}
+
+/// Have every hart in `ran_on` but this one flush `batch`, made under
+/// `asid`, and wait for them to. This hart is expected to have flushed
+/// its own translations as it made the changes.
+pub fn shootdown(asid: u16, batch: &TlbBatch, ran_on: usize) {
+    if batch.is_empty() {
+        return;
+    }
+    let queued = (1 << QUEUED_HARTS) - 1;
+    let others = ran_on & !hart_bit(current_hart());
+    HARTS_SKIPPED.fetch_add(
+        u64::from((queued & !ran_on & !hart_bit(current_hart())).count_ones()),
+        Ordering::Relaxed,
+    );
+    if others == 0 {
+        return;
+    }
+
+    let _initiator = INITIATOR.lock();
+    let mut targets = 0;
+    for hart in (0..QUEUED_HARTS).filter(|&hart| others & hart_bit(hart) != 0) {
+        MAILBOXES.post(hart, asid, batch);
+        match ipi::send_ipi(hart, IpiWork::TLB_SHOOTDOWN) {
+            Ok(()) => {
+                IPIS_SENT.fetch_add(1, Ordering::Relaxed);
+                targets |= hart_bit(hart);
+            }
+            Err(error) => log!(Warn, "tlb: couldn't interrupt hart {}: {:?}", hart, error),
+        }
+    }
+
+    let deadline = time::deadline_us(SHOOTDOWN_TIMEOUT_US);
+    loop {
+        let waiting = MAILBOXES.waiting(targets);
+        if waiting == 0 {
+            return;
+        }
+        if time::has_passed(&deadline) {
+            TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+            log!(
+                Warn,
+                "tlb: harts {:#b} didn't flush ASID {} within {}us",
+                waiting,
+                asid,
+                SHOOTDOWN_TIMEOUT_US
+            );
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Flush every translation cached under `asid` on each hart in `ran_on`,
+/// this one included, for an address space that is going away
+pub fn flush_asid(asid: u16, ran_on: usize) {
+    if ran_on & hart_bit(current_hart()) != 0 {
+        unsafe { core::arch::asm!("sfence.vma zero, {}", in(reg) asid) };
+        FULL_FLUSHES.fetch_add(1, Ordering::Relaxed);
+    }
+    let mut batch = TlbBatch::new();
+    batch.add_all();
+    shootdown(asid, &batch, ran_on);
+}
+
+/// Flush whatever batch is waiting for `hart` and acknowledge it. Called
+/// by [`ipi::handle`] when the hart is sent [`IpiWork::TLB_SHOOTDOWN`].
+pub fn handle_shootdown(hart: usize) {
+    MAILBOXES.take(hart, |shootdown| match shootdown.asid {
+        None => {
+            unsafe { core::arch::asm!("sfence.vma zero, zero") };
+            FULL_FLUSHES.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(asid) if shootdown.batch.is_full() => {
+            unsafe { core::arch::asm!("sfence.vma zero, {}", in(reg) asid) };
+            FULL_FLUSHES.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(asid) => {
+            for &page in shootdown.batch.pages() {
+                unsafe { core::arch::asm!("sfence.vma {}, {}", in(reg) page, in(reg) asid) };
+            }
+            PAGE_FLUSHES.fetch_add(shootdown.batch.pages().len() as u64, Ordering::Relaxed);
+        }
+    });
+}
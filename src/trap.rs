@@ -0,0 +1,91 @@
+//! # Traps
+//!
+//! Every trap lands in `asm_trap_vector` in boot.s, which saves the
+//! interrupted registers in a [`TrapFrame`] and calls [`handle_trap`] with
+//! it. Returning puts the registers back and `mret`s to where the hart
+//! was.
+//!
+//! The only interrupt the kernel uses is the machine software interrupt
+//! other harts raise through the CLINT, see [`ipi`]. Any other interrupt
+//! is masked in `mie` the first time it arrives, since nothing would clear
+//! it and the hart would trap again the moment it returned. Exceptions are
+//! the kernel's own faults and panic.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::csr::{self, Cause, Exception, Interrupt, Mcause};
+use crate::ipi;
+use crate::panic_code::{Access, PanicCode};
+
+/// The registers of the interrupted code, `x1` to `x31` indexed by their
+/// number. Slot 0 is unused.
+#[repr(C)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],
+}
+
+/// Interrupts that arrived without anything to handle them, and were
+/// masked
+static UNEXPECTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Interrupts masked because nothing handles them
+pub fn unexpected_interrupts() -> usize {
+    UNEXPECTED.load(Ordering::Relaxed)
+}
+
+/// Called by `asm_trap_vector` for every trap
+#[no_mangle]
+extern "C" fn handle_trap(frame: &mut TrapFrame) {
+    let mcause = Mcause::from_bits(csr::mcause::read());
+    match mcause.cause() {
+        Cause::Interrupt(Interrupt::MachineSoftware) => ipi::handle(csr::mhartid::read()),
+        Cause::Interrupt(interrupt) => mask(interrupt.code()),
+        Cause::Unknown {
+            interrupt: true,
+            code,
+        } => mask(code),
+        Cause::Exception(exception) => fault(frame, mcause, Some(exception)),
+        Cause::Unknown {
+            interrupt: false, ..
+        } => fault(frame, mcause, None),
+    }
+}
+
+/// Stop the interrupt numbered `code` from being taken again. Nothing is
+/// logged, as the interrupted code may hold the console.
+fn mask(code: usize) {
+    csr::mie::clear_bits(1 << code);
+    UNEXPECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The kernel faulted; there is nothing to return to
+fn fault(frame: &TrapFrame, mcause: Mcause, exception: Option<Exception>) -> ! {
+    let mepc = csr::mepc::read();
+    let mtval = csr::mtval::read();
+    let access = match exception {
+        Some(Exception::LoadAccessFault | Exception::LoadPageFault) => Some(Access::Read),
+        Some(Exception::StoreAccessFault | Exception::StorePageFault) => Some(Access::Write),
+        Some(Exception::InstructionAccessFault | Exception::InstructionPageFault) => {
+            Some(Access::Execute)
+        }
+        _ => None,
+    };
+    let code = match access {
+        Some(access) => PanicCode::PageFault {
+            addr: mtval,
+            access,
+        },
+        None => PanicCode::HardwareFault {
+            cause: mcause.bits(),
+        },
+    };
+    panic_with!(
+        code,
+        "{:?} at {:#x}, mtval {:#x}, ra {:#x}, sp {:#x}",
+        mcause.cause(),
+        mepc,
+        mtval,
+        frame.regs[1],
+        frame.regs[2]
+    );
+}
@@ -0,0 +1,8 @@
+//! # Utilities
+//!
+//! Small building blocks shared between subsystems that don't belong to
+//! any one of them.
+
+pub mod backtrace;
+
+pub use oslib::{align, list, utf8};
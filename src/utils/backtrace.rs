@@ -0,0 +1,62 @@
+//! # Backtraces
+//!
+//! Return addresses found by following the frame pointer chain from the
+//! caller. With frame pointers kept, every frame saves its return address
+//! just below where `s0` points and the caller's `s0` below that. The
+//! kernel is built with `-Cforce-frame-pointers=yes` in `.cargo/config`
+//! for this; code that doesn't keep them, like the prebuilt `core`, ends
+//! the chain early.
+//!
+//! Every frame pointer must lie in the boot stack and every return address
+//! in the kernel's text, or the walk stops, so a corrupt stack cuts a
+//! backtrace short rather than faulting in the middle of a panic.
+
+use core::arch::asm;
+use core::ops::Range;
+
+use crate::globals;
+
+/// Fill `out` with up to `max_frames` return addresses, innermost first,
+/// and return how many were found
+#[inline(never)]
+pub fn capture(max_frames: usize, out: &mut [usize]) -> usize {
+    let mut fp: usize;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+
+    // Before the layout is known there is nothing to check against
+    let Some(layout) = globals::LAYOUT.try_get() else {
+        return 0;
+    };
+
+    let max_frames = max_frames.min(out.len());
+    let mut frames = 0;
+    while frames < max_frames {
+        let Some((ra, caller_fp)) = read_frame(fp, &layout.stack) else {
+            break;
+        };
+        if !layout.text.contains(&ra) {
+            break;
+        }
+        out[frames] = ra;
+        frames += 1;
+
+        // The stack grows down, so callers' frames are always higher
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+    }
+    frames
+}
+
+/// The return address and caller's frame pointer saved by the frame at
+/// `fp`, if the frame lies inside `stack`
+fn read_frame(fp: usize, stack: &Range<usize>) -> Option<(usize, usize)> {
+    let saved = fp.checked_sub(16)?;
+    if !fp.is_multiple_of(8) || saved < stack.start || fp > stack.end {
+        return None;
+    }
+    let ra = unsafe { ((fp - 8) as *const usize).read() };
+    let caller_fp = unsafe { (saved as *const usize).read() };
+    Some((ra, caller_fp))
+}
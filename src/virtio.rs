@@ -0,0 +1,66 @@
+//! # Virtio MMIO transport
+//!
+//! The register interface of `virtio,mmio` devices (virtio 1.1 section 4.2).
+//! A transport is created from the device tree node, claiming exactly the
+//! window its `reg` describes, so a register offset past the end of a
+//! misconfigured window is an error instead of a poke at whatever lies
+//! beyond it. The register logic lives in `oslib`, where it is tested
+//! against fake devices.
+
+pub use oslib::virtio::*;
+
+use crate::fdt::{Fdt, Node};
+use crate::mmio::DeviceMemory;
+use crate::platform;
+
+/// A virtio-mmio device driven through [`DeviceMemory`]
+pub type VirtioMmio = oslib::virtio::VirtioMmio<DeviceMemory>;
+
+/// Claim the window described by `node`'s `reg`, laid out as `parent`
+/// says, and check it holds a virtio device
+pub fn from_node(node: &Node, parent: &Node) -> Result<VirtioMmio, VirtioError> {
+    let (base, size) = node.reg(parent).next().ok_or(VirtioError::NoRegion)?;
+    if size < CONFIG {
+        return Err(VirtioError::RegionTooSmall(size));
+    }
+    VirtioMmio::new(DeviceMemory::claim("virtio", base, size)?)
+}
+
+/// Whether the device tree has a `virtio,mmio` node at `base`
+fn in_device_tree(fdt: &Fdt, base: usize) -> bool {
+    let Some(soc) = fdt.find_node("/soc") else {
+        return false;
+    };
+    soc.children()
+        .filter(|node| node.is_compatible(COMPATIBLE))
+        .any(|node| node.reg(&soc).any(|(address, _)| address == base))
+}
+
+/// Probe the platform's virtio-mmio slots and log what is in them,
+/// warning about devices the device tree doesn't describe
+pub fn discover(fdt: Option<&Fdt>) {
+    let Some(slots) = platform::current().virtio_mmio else {
+        return;
+    };
+
+    // Each window is only claimed while it is read, leaving it free for
+    // whichever driver binds it; windows another driver has claimed are
+    // skipped
+    let claim = |base, len| DeviceMemory::claim("virtio probe", base, len).ok();
+    for slot in probe_all(slots.base, slots.count, slots.stride, claim) {
+        log!(
+            Info,
+            "virtio: {} device (id {}) at {:#x}",
+            device_name(slot.device_id),
+            slot.device_id,
+            slot.base
+        );
+        if fdt.is_some_and(|fdt| !in_device_tree(fdt, slot.base)) {
+            log!(
+                Warn,
+                "virtio: device at {:#x} isn't in the device tree",
+                slot.base
+            );
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! # Split virtqueues
+//!
+//! The rings a driver and a virtio device exchange buffers through
+//! (virtio 1.1 section 2.6). The driver chains descriptors for a request,
+//! puts the head in the available ring and rings the device's doorbell;
+//! the device hands the chain back through the used ring when it is done.
+//!
+//! Every queue is [`QUEUE_SIZE`] entries and fits in one frame, laid out
+//! so legacy devices, which want the rings at fixed offsets from a page
+//! frame number, and modern ones, which take each ring's address, can both
+//! use it.
+//!
+//! Drivers poll the used ring for finished requests; the device is asked
+//! not to interrupt, as nothing handles external interrupts yet.
+
+use core::mem::size_of;
+use core::ptr::{addr_of, addr_of_mut};
+
+use crate::barrier::{self, DmaWriteGuard, Published};
+use crate::page::{alloc_zeroed_page, free_page, PAGE_ORDER, PAGE_SIZE};
+use crate::time;
+use crate::virtio::{self, VirtioError, VirtioMmio};
+
+/// Entries in every queue
+pub const QUEUE_SIZE: u16 = 16;
+
+/// How long [`VirtQueue::poll_used`] waits for the device
+pub const POLL_TIMEOUT_US: u64 = 1_000_000;
+
+/// Don't interrupt us when the device uses a buffer
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// The descriptor continues in `next`
+const DESC_F_NEXT: u16 = 1;
+/// The device writes the buffer rather than reading it
+const DESC_F_WRITE: u16 = 2;
+
+/// Where the used ring starts. Legacy devices find it by rounding the end
+/// of the available ring up to this, which we tell them in QueueAlign.
+const USED_ALIGN: usize = 512;
+const AVAIL_OFFSET: usize = QUEUE_SIZE as usize * size_of::<Descriptor>();
+const USED_OFFSET: usize = USED_ALIGN;
+
+const _: () = assert!(AVAIL_OFFSET + size_of::<AvailRing>() <= USED_OFFSET);
+const _: () = assert!(USED_OFFSET + size_of::<UsedRing>() <= PAGE_SIZE);
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct AvailRing {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE as usize],
+    used_event: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct UsedRing {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE as usize],
+    avail_event: u16,
+}
+
+/// One buffer of a request
+#[derive(Copy, Clone, Debug)]
+pub struct Buffer {
+    /// Physical address
+    pub addr: usize,
+    pub len: u32,
+    /// The device fills the buffer in, rather than reading it
+    pub device_writes: bool,
+}
+
+/// A queue of a virtio device
+pub struct VirtQueue {
+    index: u16,
+    /// The frame holding the descriptors and both rings
+    page: usize,
+    /// First descriptor of the free chain, linked through `next`
+    free_head: u16,
+    free: u16,
+    /// Our copy of the available ring's index
+    avail_idx: u16,
+    /// Set once `avail_idx` has been published and until the device has
+    /// been notified of it
+    published: Option<Published>,
+    /// Used ring entries we have already taken
+    last_used: u16,
+}
+
+impl VirtQueue {
+    /// Set up queue `index` of `transport`. The device must be between
+    /// feature negotiation and DRIVER_OK.
+    ///
+    /// The queue's frame is never freed once the device has it: nothing
+    /// tells us the device has stopped using it short of a reset.
+    pub fn new(transport: &VirtioMmio, index: u16) -> Result<Self, VirtioError> {
+        transport.write32(virtio::QUEUE_SEL, u32::from(index))?;
+        let max = transport.read32(virtio::QUEUE_NUM_MAX)?;
+        if max < u32::from(QUEUE_SIZE) {
+            return Err(VirtioError::QueueTooSmall(max));
+        }
+
+        let page = alloc_zeroed_page().ok_or(VirtioError::OutOfMemory)?;
+        let mut queue = Self {
+            index,
+            page,
+            free_head: 0,
+            free: QUEUE_SIZE,
+            avail_idx: 0,
+            published: None,
+            last_used: 0,
+        };
+        for i in 0..QUEUE_SIZE {
+            queue.descriptor(i).next = i + 1;
+        }
+        // Nothing handles the device's interrupts, so it is asked not to
+        // raise them
+        let avail = queue.avail();
+        unsafe { addr_of_mut!((*avail).flags).write_volatile(AVAIL_F_NO_INTERRUPT) };
+
+        if let Err(error) = queue.give_to(transport) {
+            free_page(page);
+            return Err(error);
+        }
+        Ok(queue)
+    }
+
+    /// Tell the device where the rings are and enable the queue
+    fn give_to(&self, transport: &VirtioMmio) -> Result<(), VirtioError> {
+        transport.write32(virtio::QUEUE_NUM, u32::from(QUEUE_SIZE))?;
+
+        if transport.version()? == 1 {
+            transport.write32(virtio::GUEST_PAGE_SIZE, PAGE_SIZE as u32)?;
+            transport.write32(virtio::QUEUE_ALIGN, USED_ALIGN as u32)?;
+            return transport.write32(virtio::QUEUE_PFN, (self.page >> PAGE_ORDER) as u32);
+        }
+
+        let rings = [
+            (virtio::QUEUE_DESC_LOW, self.page),
+            (virtio::QUEUE_DRIVER_LOW, self.page + AVAIL_OFFSET),
+            (virtio::QUEUE_DEVICE_LOW, self.page + USED_OFFSET),
+        ];
+        for (low, addr) in rings {
+            transport.write32(low, addr as u32)?;
+            transport.write32(low + 4, (addr as u64 >> 32) as u32)?;
+        }
+        transport.write32(virtio::QUEUE_READY, 1)
+    }
+
+    fn descriptor(&mut self, index: u16) -> &mut Descriptor {
+        let table = self.page as *mut Descriptor;
+        unsafe { &mut *table.add(usize::from(index % QUEUE_SIZE)) }
+    }
+
+    fn avail(&self) -> *mut AvailRing {
+        (self.page + AVAIL_OFFSET) as *mut AvailRing
+    }
+
+    fn used(&self) -> *const UsedRing {
+        (self.page + USED_OFFSET) as *const UsedRing
+    }
+
+    /// Which queue of the device this is
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Descriptors not in use by a request
+    pub fn free_descriptors(&self) -> u16 {
+        self.free
+    }
+
+    /// Chain `buffers` into a request and make it available to the device,
+    /// returning the head descriptor that identifies it when it comes back.
+    /// The device isn't told until [`notify`].
+    ///
+    /// [`notify`]: VirtQueue::notify
+    pub fn add_buffer(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free) {
+            return Err(VirtioError::QueueFull);
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let last = i + 1 == buffers.len();
+            let descriptor = self.descriptor(index);
+            let next = descriptor.next;
+            descriptor.addr = buffer.addr as u64;
+            descriptor.len = buffer.len;
+            descriptor.flags = 0;
+            if buffer.device_writes {
+                descriptor.flags |= DESC_F_WRITE;
+            }
+            if last {
+                self.free_head = next;
+            } else {
+                descriptor.flags |= DESC_F_NEXT;
+                index = next;
+            }
+        }
+        self.free -= buffers.len() as u16;
+
+        let avail = self.avail();
+        let slot = usize::from(self.avail_idx % QUEUE_SIZE);
+        unsafe { addr_of_mut!((*avail).ring[slot]).write_volatile(head) };
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+
+        let idx = self.avail_idx;
+        // The published index must not overtake the descriptors and ring
+        // slot behind it. Notifying is left to `notify`, which the token
+        // kept here orders after the index.
+        self.published = Some(
+            DmaWriteGuard::new()
+                .publish(|| unsafe { addr_of_mut!((*avail).idx).write_volatile(idx) }),
+        );
+        Ok(head)
+    }
+
+    /// Tell the device there are new buffers in this queue. Nothing is
+    /// written if none were added since the last notification.
+    pub fn notify(&mut self, transport: &VirtioMmio) -> Result<(), VirtioError> {
+        match self.published.take() {
+            Some(published) => {
+                published.notify(|| transport.write32(virtio::QUEUE_NOTIFY, u32::from(self.index)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Take the next request the device has finished with, returning its
+    /// head descriptor and how many bytes the device wrote. Its descriptors
+    /// are free again afterwards.
+    ///
+    /// The used ring and the chain's links are the device's to write, so
+    /// neither is trusted: a head outside the table, or a chain longer than
+    /// the queue, is reported as corruption and nothing is freed.
+    pub fn pop_used(&mut self) -> Result<Option<(u16, u32)>, VirtioError> {
+        let used = self.used();
+        let idx = unsafe { addr_of!((*used).idx).read_volatile() };
+        if idx == self.last_used {
+            return Ok(None);
+        }
+        // Don't read the entry before the index that covers it
+        barrier::dma_rmb();
+
+        let slot = usize::from(self.last_used % QUEUE_SIZE);
+        let elem = unsafe { addr_of!((*used).ring[slot]).read_volatile() };
+        let head = match u16::try_from(elem.id) {
+            Ok(head) if head < QUEUE_SIZE => head,
+            _ => return Err(VirtioError::CorruptChain(elem.id)),
+        };
+
+        let mut tail = head;
+        let mut count = 1;
+        while self.descriptor(tail).flags & DESC_F_NEXT != 0 {
+            if count == QUEUE_SIZE {
+                return Err(VirtioError::CorruptChain(elem.id));
+            }
+            tail = self.descriptor(tail).next;
+            count += 1;
+        }
+        self.last_used = self.last_used.wrapping_add(1);
+        let free_head = self.free_head;
+        self.descriptor(tail).next = free_head;
+        self.free_head = head;
+        self.free += count;
+
+        Ok(Some((head, elem.len)))
+    }
+
+    /// Spin until the device finishes a request, returning its head
+    /// descriptor and how many bytes the device wrote. Gives up after
+    /// [`POLL_TIMEOUT_US`], so a dead device can't hang the caller.
+    pub fn poll_used(&mut self) -> Result<(u16, u32), VirtioError> {
+        let deadline = time::deadline_us(POLL_TIMEOUT_US);
+        loop {
+            if let Some(used) = self.pop_used()? {
+                return Ok(used);
+            }
+            if time::has_passed(&deadline) {
+                return Err(VirtioError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+}